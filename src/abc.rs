@@ -0,0 +1,752 @@
+use error::ParseError;
+use helper::SequenceHelper;
+use midi::{Division, TempoMap};
+use sequence::Sequence;
+use std::collections::HashMap;
+use util::{TextCursor, Time, Volume};
+use Result;
+
+/// Number of ticks in a quarter note when placing notes, divisible by most tuplets.
+/// Tunes without a Q: field play at the MIDI default tempo of 120 quarter notes per minute.
+pub const TICKS_PER_QUARTER_NOTE: u16 = 3840;
+
+/// Semitones of the natural notes from C to B
+const NATURAL_SEMITONES: [i32; 7] = [0, 2, 4, 5, 7, 9, 11];
+/// Order in which sharps are added to key signatures, as indexes from C
+const SHARPS_ORDER: [usize; 7] = [3, 0, 4, 1, 5, 2, 6];
+/// Position of every tonic from C to B in the circle of fifths
+const TONIC_FIFTHS: [i32; 7] = [0, 2, 4, -1, 1, 3, 5];
+
+/// Something that happens in the body of a tune
+enum Token {
+    /// Notes starting together, as their MIDI key, duration in whole notes and if they are tied to the next note of the same key
+    Notes {
+        notes: Vec<(i32, f64, bool)>,
+        /// How long until the next token, in whole notes
+        advance: f64,
+    },
+    /// Silence in whole notes
+    Rest(f64),
+    /// New tempo in microseconds per quarter note
+    Tempo(u32),
+    RepeatStart,
+    RepeatEnd,
+    /// Start of the n-th ending of a repeated part
+    Ending(u32),
+}
+
+/// What is known about the tune while it is read
+struct TuneState {
+    /// Default note length in whole notes, from the L: field
+    unit: Option<f64>,
+    /// Numerator and denominator of the meter, from the M: field
+    meter: Option<(u32, u32)>,
+    /// Semitones added to every note from C to B by the key signature
+    key: [i32; 7],
+    /// Accidentals written in the current bar, by note index from C and octave
+    bar_accidentals: HashMap<(usize, i32), i32>,
+    /// Duration factor and number of notes left of the current tuplet
+    tuplet: Option<(f64, u32)>,
+    /// Duration factor for the next note, coming from a broken rhythm
+    broken: Option<f64>,
+    /// Value of the Q: field of the header with its line and column, only read once the whole header is known as the L: field can come after it
+    header_tempo: Option<(String, usize, usize)>,
+    tokens: Vec<Token>,
+}
+
+/// Builds a Sequence from the first tune of an ABC notation text.
+/// The Frequency IDs are MIDI note numbers, usable with MIDIFrequencyLookup, `C` being the middle C.
+/// Supports the X:, T:, M:, L:, Q: and K: fields, key signatures, accidentals, lengths, broken rhythms, bar lines, repeats with endings, chords, ties and tuplets.
+/// Decorations, annotations, slurs and grace notes are ignored.
+/// # Arguments
+/// * text - The ABC notation to parse
+/// * i_id - The Instrument ID of every note
+/// * nb_channels - The number of channels of the final output, every note gets full volume on all of them
+pub fn parse(text: &str, i_id: usize, nb_channels: usize) -> Result<Sequence> {
+    let mut state = TuneState {
+        unit: None,
+        meter: Some((4, 4)),
+        key: [0; 7],
+        bar_accidentals: HashMap::new(),
+        tuplet: None,
+        broken: None,
+        header_tempo: None,
+        tokens: Vec::new(),
+    };
+    // Text before the first X: field is not part of a tune
+    let lines = text.lines().collect::<Vec<&str>>();
+    let first_line = lines
+        .iter()
+        .position(|line| line.starts_with("X:"))
+        .unwrap_or(0);
+    let mut in_body = false;
+    for (index, line) in lines.iter().enumerate().skip(first_line) {
+        let mut cursor = TextCursor::new(line);
+        cursor.line = index + 1;
+        let is_field = line.chars().nth(1) == Some(':')
+            && line
+                .chars()
+                .next()
+                .map_or(false, |c| c.is_ascii_alphabetic());
+        if in_body {
+            // A new tune or an empty line ends the tune
+            if line.trim().is_empty() || line.starts_with("X:") {
+                break;
+            }
+            if is_field {
+                read_field(&mut cursor, &mut state, true)?;
+            } else {
+                read_body(&mut cursor, &mut state)?;
+            }
+        } else if is_field {
+            in_body = line.starts_with("K:");
+            read_field(&mut cursor, &mut state, false)?;
+        } else if !line.trim().is_empty() && !line.starts_with('%') {
+            return Err(cursor.error("Expected a header field").into());
+        }
+    }
+    // Places the notes in ticks, playing the repeats
+    let mut tempo_map = TempoMap::new(Division::TicksPerQuarterNote(TICKS_PER_QUARTER_NOTE));
+    let whole_note_ticks = f64::from(TICKS_PER_QUARTER_NOTE) * 4f64;
+    let to_tick = |position: f64| (position * whole_note_ticks).round() as u64;
+    let mut position = 0f64;
+    // Notes as start tick, end tick and MIDI key
+    let mut notes: Vec<(u64, u64, usize)> = Vec::new();
+    // Index of the notes tied to the next note of the same key
+    let mut tied: HashMap<usize, usize> = HashMap::new();
+    for token_index in expand_repeats(&state.tokens) {
+        match state.tokens[token_index] {
+            Token::Notes {
+                notes: ref chord,
+                advance,
+            } => {
+                let start = to_tick(position);
+                for &(key, duration, tie) in chord {
+                    let key = key as usize;
+                    let end = to_tick(position + duration);
+                    let index = match tied.get(&key) {
+                        Some(&index) if notes[index].1 == start => {
+                            notes[index].1 = end;
+                            index
+                        }
+                        _ => {
+                            notes.push((start, end, key));
+                            notes.len() - 1
+                        }
+                    };
+                    if tie {
+                        tied.insert(key, index);
+                    } else {
+                        tied.remove(&key);
+                    }
+                }
+                position += advance;
+            }
+            Token::Rest(duration) => position += duration,
+            Token::Tempo(tempo) => tempo_map.set_tempo(to_tick(position), tempo),
+            _ => {}
+        }
+    }
+    let mut helper = SequenceHelper::new();
+    helper.set_tempo_map(tempo_map.clone());
+    notes.sort_by_key(|&(start, _, _)| start);
+    let volume = Volume::new(1f64)?;
+    for (start, end, key) in notes {
+        let at_tick = helper.at_tick;
+        helper.tick_forward(start - at_tick)?;
+        let duration = Time::new(tempo_map.get_seconds(end) - tempo_map.get_seconds(start))?;
+        helper.new_note(key, i_id, duration, vec![volume; nb_channels])?;
+    }
+    Ok(helper.sequence)
+}
+
+/// Reads an information field, the letter and colon included
+/// # Arguments
+/// * cursor - Where the field is read
+/// * state - The tune the field applies to
+/// * in_body - If the field is in the middle of the notes, tempo changes then become tokens
+fn read_field(cursor: &mut TextCursor, state: &mut TuneState, in_body: bool) -> Result<()> {
+    let letter = cursor.next_char().unwrap_or(' ');
+    cursor.next_char(); // Colon
+    cursor.skip_whitespace();
+    let (line, column) = (cursor.line, cursor.column);
+    let mut value = String::new();
+    while let Some(c) = cursor.peek_char() {
+        if (c == '%') | (c == ']') {
+            break;
+        }
+        value.push(c);
+        cursor.next_char();
+    }
+    let value = value.trim();
+    let error = |reason| ParseError {
+        line,
+        column,
+        reason,
+    };
+    match letter {
+        'M' => {
+            state.meter = match value {
+                "C" => Some((4, 4)),
+                "C|" => Some((2, 2)),
+                "none" | "" => None,
+                _ => Some(parse_fraction(value).ok_or_else(|| error("Invalid meter"))?),
+            }
+        }
+        'L' => {
+            let (numerator, denominator) =
+                parse_fraction(value).ok_or_else(|| error("Invalid note length"))?;
+            state.unit = Some(f64::from(numerator) / f64::from(denominator));
+        }
+        'Q' if in_body => {
+            let tempo =
+                parse_tempo(value, get_unit(state)).ok_or_else(|| error("Invalid tempo"))?;
+            state.tokens.push(Token::Tempo(tempo));
+        }
+        'Q' => state.header_tempo = Some((value.to_string(), line, column)),
+        'K' => {
+            state.key = parse_key(value).ok_or_else(|| error("Invalid key"))?;
+            // The K: field ends the header
+            if let Some((value, line, column)) = state.header_tempo.take() {
+                let tempo = parse_tempo(&value, get_unit(state)).ok_or(ParseError {
+                    line,
+                    column,
+                    reason: "Invalid tempo",
+                })?;
+                state.tokens.insert(0, Token::Tempo(tempo));
+            }
+        }
+        _ => {} // Other fields do not change the music
+    }
+    Ok(())
+}
+
+/// Returns the default note length, that depends on the meter if there is no L: field
+fn get_unit(state: &TuneState) -> f64 {
+    state.unit.unwrap_or_else(|| match state.meter {
+        Some((numerator, denominator)) if f64::from(numerator) / f64::from(denominator) < 0.75 => {
+            1f64 / 16f64
+        }
+        _ => 1f64 / 8f64,
+    })
+}
+
+/// Parses a fraction like `6/8`
+fn parse_fraction(text: &str) -> Option<(u32, u32)> {
+    let mut parts = text.split('/');
+    let numerator = parts.next()?.trim().parse::<u32>().ok()?;
+    let denominator = parts.next()?.trim().parse::<u32>().ok()?;
+    if (numerator == 0) | (denominator == 0) | parts.next().is_some() {
+        return None;
+    }
+    Some((numerator, denominator))
+}
+
+/// Parses a tempo like `1/4=120` or `120`, returns it in microseconds per quarter note
+/// # Arguments
+/// * text - The value of the Q: field
+/// * unit - The default note length, counted per minute when no beat is given
+fn parse_tempo(text: &str, unit: f64) -> Option<u32> {
+    // Texts in quotes are only descriptions
+    let text = text
+        .split('"')
+        .enumerate()
+        .filter(|&(i, _)| i % 2 == 0)
+        .map(|(_, part)| part)
+        .collect::<String>();
+    let (beat, bpm) = match text.find('=') {
+        Some(i) => {
+            let mut beat = 0f64;
+            for fraction in text[..i].split_whitespace() {
+                let (numerator, denominator) = parse_fraction(fraction)?;
+                beat += f64::from(numerator) / f64::from(denominator);
+            }
+            (beat, text[i + 1..].trim().parse::<f64>().ok()?)
+        }
+        None => (unit, text.trim().parse::<f64>().ok()?),
+    };
+    if (beat <= 0f64) | (bpm <= 0f64) {
+        return None;
+    }
+    Some((60_000_000f64 * 0.25f64 / (bpm * beat)).round() as u32)
+}
+
+/// Parses a key like `F#m`, `Bb`, `Ddor` or `D =c`, returns the semitones added to every note from C to B
+fn parse_key(text: &str) -> Option<[i32; 7]> {
+    let mut key = [0; 7];
+    let mut words = text.split_whitespace();
+    let tonic_word = match words.next() {
+        None => return Some(key),
+        Some("none") | Some("HP") | Some("Hp") => return Some(key),
+        Some(word) => word,
+    };
+    let mut chars = tonic_word.chars().peekable();
+    let tonic = note_index(chars.next()?.to_ascii_lowercase())?;
+    let mut fifths = TONIC_FIFTHS[tonic];
+    match chars.peek() {
+        Some('#') => {
+            fifths += 7;
+            chars.next();
+        }
+        Some('b') => {
+            fifths -= 7;
+            chars.next();
+        }
+        _ => {}
+    }
+    // The mode can be glued to the tonic or be the next word, when that word is a mode and not a modifier like bass or clef=treble
+    let glued_mode = chars.collect::<String>();
+    let mut modifications = words.collect::<Vec<&str>>();
+    fifths += if !glued_mode.is_empty() {
+        mode_fifths(&glued_mode)?
+    } else {
+        match modifications.first().and_then(|word| mode_fifths(word)) {
+            Some(mode_fifths) => {
+                modifications.remove(0);
+                mode_fifths
+            }
+            None => 0,
+        }
+    };
+    if (fifths > 7) | (fifths < -7) {
+        return None;
+    }
+    for i in 0..fifths.abs() as usize {
+        if fifths > 0 {
+            key[SHARPS_ORDER[i]] = 1;
+        } else {
+            key[SHARPS_ORDER[6 - i]] = -1;
+        }
+    }
+    // Explicit accidentals like ^f or =c, other words like clef=treble are ignored
+    for word in modifications {
+        let accidental = word
+            .chars()
+            .take_while(|c| "^_=".contains(*c))
+            .collect::<String>();
+        let letter = word[accidental.len()..].chars().next();
+        if let (Some(offset), Some(index)) = (
+            accidental_offset(&accidental),
+            letter.and_then(|c| note_index(c.to_ascii_lowercase())),
+        ) {
+            key[index] = offset;
+        }
+    }
+    Some(key)
+}
+
+/// Returns the fifths a mode adds to the major key of its tonic, if the text is a mode.
+/// Only the first three letters of a mode matter, minor can also be written as just `m`.
+fn mode_fifths(mode: &str) -> Option<i32> {
+    let mode = mode.to_ascii_lowercase();
+    if !mode.chars().all(char::is_alphabetic) {
+        return None;
+    }
+    match mode.get(..3).unwrap_or_else(|| mode.as_str()) {
+        "maj" | "ion" => Some(0),
+        "m" | "min" | "aeo" => Some(-3),
+        "mix" => Some(-1),
+        "dor" => Some(-2),
+        "phr" => Some(-4),
+        "lyd" => Some(1),
+        "loc" => Some(-5),
+        _ => None,
+    }
+}
+
+/// Returns the index from C of a lowercase note letter
+fn note_index(letter: char) -> Option<usize> {
+    "cdefgab".find(letter)
+}
+
+/// Returns the semitones of an accidental like `^^` or `_`
+fn accidental_offset(accidental: &str) -> Option<i32> {
+    match accidental {
+        "^" => Some(1),
+        "^^" => Some(2),
+        "_" => Some(-1),
+        "__" => Some(-2),
+        "=" => Some(0),
+        _ => None,
+    }
+}
+
+/// Reads a line of notes
+fn read_body(cursor: &mut TextCursor, state: &mut TuneState) -> Result<()> {
+    loop {
+        let (line, column) = (cursor.line, cursor.column);
+        let error = |reason| ParseError {
+            line,
+            column,
+            reason,
+        };
+        let c = match cursor.peek_char() {
+            None | Some('%') => return Ok(()),
+            Some(c) => c,
+        };
+        match c {
+            '^' | '_' | '=' | 'a'..='g' | 'A'..='G' => {
+                let (key, duration, tie) = read_note(cursor, state)?;
+                push_notes(cursor, state, vec![(key, duration, tie)], duration);
+            }
+            '[' => match cursor.peek_nth(1) {
+                Some('|') => read_bar_line(cursor, state),
+                Some(d) if d.is_digit(10) => {
+                    cursor.next_char();
+                    let ending = cursor.read_number().unwrap_or(1);
+                    state.tokens.push(Token::Ending(ending));
+                }
+                Some(f) if f.is_ascii_alphabetic() && cursor.peek_nth(2) == Some(':') => {
+                    cursor.next_char();
+                    read_field(cursor, state, true)?;
+                    if cursor.next_char() != Some(']') {
+                        return Err(error("Inline field is never closed").into());
+                    }
+                }
+                _ => {
+                    cursor.next_char();
+                    let mut chord = Vec::new();
+                    loop {
+                        cursor.skip_whitespace();
+                        match cursor.peek_char() {
+                            Some(']') => break,
+                            Some(c) if "^_=abcdefgABCDEFG".contains(c) => {
+                                chord.push(read_note(cursor, state)?)
+                            }
+                            _ => return Err(error("Chord is never closed").into()),
+                        }
+                    }
+                    cursor.next_char();
+                    if chord.is_empty() {
+                        return Err(error("Chord is empty").into());
+                    }
+                    // Length and tie written after the chord apply to all of its notes
+                    let factor = read_length(cursor)?;
+                    let tie = cursor.peek_char() == Some('-');
+                    if tie {
+                        cursor.next_char();
+                    }
+                    for note in &mut chord {
+                        note.1 *= factor;
+                        note.2 |= tie;
+                    }
+                    let advance = chord[0].1;
+                    push_notes(cursor, state, chord, advance);
+                }
+            },
+            '|' | ':' => read_bar_line(cursor, state),
+            'z' | 'x' => {
+                cursor.next_char();
+                let duration = get_unit(state) * read_length(cursor)?;
+                push_notes(cursor, state, Vec::new(), duration);
+            }
+            'Z' => {
+                cursor.next_char();
+                let bars = cursor.read_number().unwrap_or(1);
+                let bar = state
+                    .meter
+                    .map_or(1f64, |(n, d)| f64::from(n) / f64::from(d));
+                state.tokens.push(Token::Rest(bar * f64::from(bars)));
+            }
+            '(' if cursor.peek_nth(1).map_or(false, |d| d.is_digit(10)) => {
+                cursor.next_char();
+                let p = cursor.read_number().unwrap_or(3);
+                let compound = state.meter.map_or(false, |(n, _)| (n % 3 == 0) & (n > 3));
+                let mut q = match p {
+                    2 | 4 | 8 => 3,
+                    3 | 6 => 2,
+                    _ if compound => 3,
+                    _ => 2,
+                };
+                let mut r = p;
+                if cursor.peek_char() == Some(':') {
+                    cursor.next_char();
+                    q = cursor.read_number().unwrap_or(q);
+                    if cursor.peek_char() == Some(':') {
+                        cursor.next_char();
+                        r = cursor.read_number().unwrap_or(p);
+                    }
+                }
+                if (p == 0) | (q == 0) {
+                    return Err(error("Invalid tuplet").into());
+                }
+                state.tuplet = Some((f64::from(q) / f64::from(p), r));
+            }
+            '"' | '!' | '+' | '{' => {
+                // Annotations, decorations and grace notes, skipped up to their closing character
+                cursor.next_char();
+                let closing = if c == '{' { '}' } else { c };
+                loop {
+                    match cursor.next_char() {
+                        Some(x) if x == closing => break,
+                        Some(_) => {}
+                        None => return Err(error("Never closed").into()),
+                    }
+                }
+            }
+            '(' | ')' | '-' | '.' | '~' | '`' | '\\' | 'y' | 'H' | 'L' | 'M' | 'O' | 'P' | 'S'
+            | 'T' | 'u' | 'v' => {
+                cursor.next_char();
+            }
+            _ if c.is_whitespace() => {
+                cursor.next_char();
+            }
+            _ => return Err(error("Unknown symbol").into()),
+        }
+    }
+}
+
+/// Reads a note with its accidental, octave, length and tie, returns its MIDI key, duration and tie
+fn read_note(cursor: &mut TextCursor, state: &mut TuneState) -> Result<(i32, f64, bool)> {
+    let (line, column) = (cursor.line, cursor.column);
+    let mut accidental = String::new();
+    while let Some(c) = cursor.peek_char() {
+        if !"^_=".contains(c) {
+            break;
+        }
+        accidental.push(c);
+        cursor.next_char();
+    }
+    let letter = cursor.next_char().unwrap_or(' ');
+    let index = match note_index(letter.to_ascii_lowercase()) {
+        Some(index) => index,
+        None => {
+            return Err(ParseError {
+                line,
+                column,
+                reason: "Expected a note",
+            }
+            .into())
+        }
+    };
+    let mut octave = if letter.is_ascii_uppercase() { 4 } else { 5 };
+    loop {
+        match cursor.peek_char() {
+            Some('\'') => octave += 1,
+            Some(',') => octave -= 1,
+            _ => break,
+        }
+        cursor.next_char();
+    }
+    let offset = if accidental.is_empty() {
+        match state.bar_accidentals.get(&(index, octave)) {
+            Some(&offset) => offset,
+            None => state.key[index],
+        }
+    } else {
+        let offset = accidental_offset(&accidental).ok_or(ParseError {
+            line,
+            column,
+            reason: "Invalid accidental",
+        })?;
+        state.bar_accidentals.insert((index, octave), offset);
+        offset
+    };
+    let key = (octave + 1) * 12 + NATURAL_SEMITONES[index] + offset;
+    if (key < 0) | (key > 127) {
+        return Err(ParseError {
+            line,
+            column,
+            reason: "Note is out of the MIDI range",
+        }
+        .into());
+    }
+    let duration = get_unit(state) * read_length(cursor)?;
+    let tie = cursor.peek_char() == Some('-');
+    if tie {
+        cursor.next_char();
+    }
+    Ok((key, duration, tie))
+}
+
+/// Reads a length like `3`, `/`, `//`, `/4` or `3/2`, returns it as a factor of the default length
+fn read_length(cursor: &mut TextCursor) -> Result<f64> {
+    let error = cursor.error("Invalid length");
+    let numerator = cursor.read_number().unwrap_or(1);
+    let mut denominator = 1;
+    let mut nb_slashes = 0;
+    while cursor.peek_char() == Some('/') {
+        cursor.next_char();
+        nb_slashes += 1;
+    }
+    if nb_slashes > 0 {
+        denominator = match cursor.read_number() {
+            Some(d) if nb_slashes == 1 => d,
+            Some(_) => return Err(error.into()),
+            None => 1 << nb_slashes.min(16),
+        };
+    }
+    if (numerator == 0) | (denominator == 0) {
+        return Err(error.into());
+    }
+    Ok(f64::from(numerator) / f64::from(denominator))
+}
+
+/// Adds notes starting together, or a rest if there are none, applying tuplets and broken rhythms
+/// # Arguments
+/// * cursor - Where a broken rhythm could follow
+/// * state - The tune the notes are added to
+/// * notes - The MIDI key, duration and tie of every note
+/// * advance - How long until the next token
+fn push_notes(
+    cursor: &mut TextCursor,
+    state: &mut TuneState,
+    mut notes: Vec<(i32, f64, bool)>,
+    mut advance: f64,
+) {
+    let mut factor = state.broken.take().unwrap_or(1f64);
+    if let Some((tuplet_factor, remaining)) = state.tuplet {
+        factor *= tuplet_factor;
+        state.tuplet = if remaining > 1 {
+            Some((tuplet_factor, remaining - 1))
+        } else {
+            None
+        };
+    }
+    // `>` makes this note longer and the next one shorter, `<` does the opposite
+    let mut nb_greater = 0;
+    let mut nb_lower = 0;
+    loop {
+        match cursor.peek_char() {
+            Some('>') => nb_greater += 1,
+            Some('<') => nb_lower += 1,
+            _ => break,
+        }
+        cursor.next_char();
+    }
+    if nb_greater + nb_lower > 0 {
+        let short = 0.5f64.powi(nb_greater + nb_lower);
+        let (this, next) = if nb_greater > 0 {
+            (2f64 - short, short)
+        } else {
+            (short, 2f64 - short)
+        };
+        factor *= this;
+        state.broken = Some(next);
+    }
+    advance *= factor;
+    if notes.is_empty() {
+        state.tokens.push(Token::Rest(advance));
+    } else {
+        for note in &mut notes {
+            note.1 *= factor;
+        }
+        state.tokens.push(Token::Notes { notes, advance });
+    }
+}
+
+/// Reads a bar line like `|`, `||`, `|]`, `[|`, `|:`, `:|`, `::` or `:|2`, ending the accidentals of the bar
+fn read_bar_line(cursor: &mut TextCursor, state: &mut TuneState) {
+    let mut bar = String::new();
+    while let Some(c) = cursor.peek_char() {
+        let is_bar = match c {
+            '|' | ':' => true,
+            '[' => cursor.peek_nth(1) == Some('|'),
+            ']' => bar.ends_with('|'),
+            _ => false,
+        };
+        if !is_bar {
+            break;
+        }
+        bar.push(c);
+        cursor.next_char();
+    }
+    state.bar_accidentals.clear();
+    if bar.starts_with(':') {
+        state.tokens.push(Token::RepeatEnd);
+    }
+    if bar.ends_with(':') {
+        state.tokens.push(Token::RepeatStart);
+    }
+    if let Some(ending) = cursor.read_number() {
+        state.tokens.push(Token::Ending(ending));
+    }
+}
+
+/// Returns the indexes of the tokens in the order they are played, going through repeats and endings
+fn expand_repeats(tokens: &[Token]) -> Vec<usize> {
+    let mut order = Vec::new();
+    let mut pass = 1;
+    let mut start = 0;
+    let mut skipping = false;
+    let mut i = 0;
+    while i < tokens.len() {
+        match tokens[i] {
+            Token::RepeatStart => {
+                if !skipping {
+                    start = i + 1;
+                    pass = 1;
+                }
+            }
+            Token::RepeatEnd => {
+                if skipping {
+                    // End of the first ending, the second one comes next
+                    skipping = false;
+                } else if pass == 1 {
+                    pass = 2;
+                    i = start;
+                    continue;
+                } else {
+                    pass = 1;
+                    start = i + 1;
+                }
+            }
+            Token::Ending(n) => {
+                skipping = n != pass;
+                if !skipping && (pass == 2) {
+                    pass = 1;
+                    start = i + 1;
+                }
+            }
+            _ => {
+                if !skipping {
+                    order.push(i);
+                }
+            }
+        }
+        i += 1;
+    }
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Start, end and Frequency ID of every note
+    fn get_notes(sequence: &Sequence) -> Vec<(f64, f64, usize)> {
+        sequence
+            .notes
+            .iter()
+            .map(|note| {
+                (
+                    note.t_span.start_at().get(),
+                    note.t_span.end_at().get(),
+                    note.f_id,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn tempo_before_unit() {
+        let sequence = parse("X:1\nQ:120\nL:1/4\nK:C\nC D|\n", 0, 1).unwrap();
+        assert_eq!(get_notes(&sequence), vec![(0f64, 0.5, 60), (0.5, 1f64, 62)]);
+    }
+
+    #[test]
+    fn repeats_and_endings() {
+        let sequence = parse("X:1\nQ:120\nL:1/4\nK:G\n|: G F |1 E :|2 c |]\n", 0, 1).unwrap();
+        assert_eq!(
+            get_notes(&sequence),
+            vec![
+                (0f64, 0.5, 67),
+                (0.5, 1f64, 66),
+                (1f64, 1.5, 64),
+                (1.5, 2f64, 67),
+                (2f64, 2.5, 66),
+                (2.5, 3f64, 72),
+            ]
+        );
+    }
+}
@@ -0,0 +1,555 @@
+use error::{
+    MalformedChunkError, MissingChunkError, ReadError, UnsupportedCompressionError,
+    UnsupportedFormatError, WriteError, WrongMagicNumberError,
+};
+use ez_io::{ReadE, WriteE};
+use pcm::{PCMParameters, SpeakerLayout, PCM};
+use std::collections::HashMap;
+use std::io::{Cursor, Read, Result as IOResult, Seek, SeekFrom, Write};
+use std::result::Result;
+use util::{Time, TimeSpan};
+use wave::{
+    check_id, read_chunk_data, Dither, Gain, Quantizer, SampleType, WriteReport, WAVE_FORMAT_PCM,
+};
+
+/// Represents an AIFF File. Float sample types are written as AIFC files.
+/// Only the first two loops of the PCM can be stored, as the sustain and release loops of the instrument.
+pub struct Aiff {
+    pub pcm: PCM,
+    pub sample_type: SampleType,
+    /// How the PCM gets scaled before being written
+    pub gain: Gain,
+    /// How the quantization error is handled when writing integer samples
+    pub dither: Dither,
+}
+
+/// Timestamp of the only version of the AIFC specification
+const AIFC_VERSION_1: u32 = 0xA280_5140;
+
+/// Layout of the audio data, as described by the Common Chunk
+struct Common {
+    nb_channels: u16,
+    nb_frames: u32,
+    sample_rate: u32,
+    sample_type: SampleType,
+    /// Some AIFC files store integers in Little Endian
+    little_endian: bool,
+}
+
+impl Aiff {
+    /// Read an AIFF or AIFC file.
+    /// Integer samples are scaled back to [-1; 1], float samples are kept as they are.
+    pub fn read<R: Read + Seek>(reader: &mut R) -> Result<Aiff, ReadError> {
+        check_id(reader, *b"FORM")?;
+        let form_size = reader.read_be_to_u32()?;
+        let form_end = reader.seek(SeekFrom::Current(0))? + u64::from(form_size);
+        let mut form_type = [0u8; 4];
+        reader.read_exact(&mut form_type)?;
+        let is_aifc = match &form_type {
+            b"AIFF" => false,
+            b"AIFC" => true,
+            _ => {
+                return Err(WrongMagicNumberError {
+                    expected: *b"AIFF",
+                    found: form_type,
+                }
+                .into())
+            }
+        };
+        let mut common: Option<Common> = None;
+        let mut data: Option<Vec<u8>> = None;
+        let mut markers: HashMap<u16, u32> = HashMap::new();
+        let mut loop_markers: Vec<(u16, u16)> = Vec::new();
+        // Go through all the chunks, only keeping track of the ones we know
+        while reader.seek(SeekFrom::Current(0))? + 8 <= form_end {
+            let mut chunk_id = [0u8; 4];
+            reader.read_exact(&mut chunk_id)?;
+            let chunk_size = reader.read_be_to_u32()?;
+            let chunk_start = reader.seek(SeekFrom::Current(0))?;
+            match &chunk_id {
+                b"COMM" => common = Some(Common::read(reader, chunk_size, is_aifc)?),
+                b"SSND" => data = Some(read_sound_data_chunk(reader, chunk_size)?),
+                b"MARK" => markers = read_marker_chunk(reader, chunk_size)?,
+                b"INST" => loop_markers = read_instrument_chunk(reader, chunk_size)?,
+                _ => {}
+            }
+            // Chunks are always aligned on 2 bytes
+            reader.seek(SeekFrom::Start(
+                chunk_start + u64::from(chunk_size) + u64::from(chunk_size % 2),
+            ))?;
+        }
+        let common = common.ok_or(MissingChunkError { chunk_id: *b"COMM" })?;
+        let data = data.ok_or(MissingChunkError { chunk_id: *b"SSND" })?;
+        let sample_rate_float = f64::from(common.sample_rate);
+        let mut loop_info = Vec::with_capacity(loop_markers.len());
+        for (begin, end) in loop_markers {
+            let begin = markers.get(&begin).ok_or(MalformedChunkError {
+                chunk_id: *b"INST",
+                reason: "Loop refers to a marker that does not exist",
+            })?;
+            let end = markers.get(&end).ok_or(MalformedChunkError {
+                chunk_id: *b"INST",
+                reason: "Loop refers to a marker that does not exist",
+            })?;
+            loop_info.push(
+                TimeSpan::new(
+                    Time::new(f64::from(*begin) / sample_rate_float)?,
+                    Time::new(f64::from(*end) / sample_rate_float)?,
+                )
+                .map_err(|_| MalformedChunkError {
+                    chunk_id: *b"INST",
+                    reason: "Loop ends before it starts",
+                })?,
+            );
+        }
+        Ok(Aiff {
+            pcm: PCM {
+                parameters: PCMParameters {
+                    sample_rate: common.sample_rate,
+                    nb_channels: common.nb_channels,
+                    speaker_layout: SpeakerLayout::Unspecified,
+                },
+                loop_info,
+                samples: common.decode(&data)?,
+            },
+            sample_type: common.sample_type,
+            gain: Gain::default(),
+            dither: Dither::default(),
+        })
+    }
+    /// Write to an AIFF file, or an AIFC file for float sample types
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<WriteReport, WriteError> {
+        let factor = self.gain.get_factor(&self.pcm)?;
+        let sample_size = self.sample_type.get_sample_size();
+        let nb_channels = self.pcm.parameters.nb_channels;
+        let is_aifc = self.sample_type.is_float();
+        let data_size = self.pcm.samples.len() as u32 * u32::from(sample_size);
+        let data_padding = data_size % 2;
+        // Chunks that come before the Sound Data Chunk
+        let mut header_chunks = Cursor::new(Vec::new());
+        if is_aifc {
+            header_chunks.write_all(&[b'F', b'V', b'E', b'R'])?; // Format Version Chunk
+            header_chunks.write_be_to_u32(4)?; // Chunk Size
+            header_chunks.write_be_to_u32(AIFC_VERSION_1)?;
+        }
+        self.write_common_chunk(&mut header_chunks)?;
+        self.write_loop_chunks(&mut header_chunks)?;
+        let header_chunks = header_chunks.into_inner();
+        // File Itself
+        writer.write_all(&[b'F', b'O', b'R', b'M'])?; // FORM Chunk
+        writer.write_be_to_u32(4 + header_chunks.len() as u32 + 16 + data_size + data_padding)?;
+        if is_aifc {
+            writer.write_all(&[b'A', b'I', b'F', b'C'])?;
+        } else {
+            writer.write_all(&[b'A', b'I', b'F', b'F'])?;
+        }
+        writer.write_all(&header_chunks)?;
+        writer.write_all(&[b'S', b'S', b'N', b'D'])?; // Sound Data Chunk
+        writer.write_be_to_u32(8 + data_size)?; // Chunk Size
+        writer.write_be_to_u32(0)?; // Offset
+        writer.write_be_to_u32(0)?; // Block Size
+        let mut mem_writer = Cursor::new(Vec::with_capacity(data_size as usize));
+        let mut quantizer = Quantizer::new(self.sample_type, self.dither, nb_channels);
+        let mut nb_clipped = 0;
+        for sample in &self.pcm.samples {
+            let (value, clipped) = self.gain.apply(sample * factor, self.sample_type);
+            if clipped {
+                nb_clipped += 1;
+            }
+            write_sample(&mut mem_writer, self.sample_type, quantizer.quantize(value))?;
+        }
+        writer.write_all(&mem_writer.into_inner())?;
+        if data_padding == 1 {
+            writer.write_all(&[0])?; // Padding Byte
+        }
+        Ok(WriteReport { nb_clipped })
+    }
+    /// Writes the Common Chunk
+    fn write_common_chunk<W: Write>(&self, writer: &mut W) -> IOResult<()> {
+        let nb_channels = self.pcm.parameters.nb_channels;
+        let compression = match self.sample_type {
+            SampleType::Float32 => Some((*b"fl32", "32-bit floating point")),
+            SampleType::Float64 => Some((*b"fl64", "64-bit floating point")),
+            _ => None,
+        };
+        let chunk_size = match compression {
+            Some((_, name)) => 18 + 4 + get_pascal_string_size(name),
+            None => 18,
+        };
+        writer.write_all(&[b'C', b'O', b'M', b'M'])?; // Common Chunk
+        writer.write_be_to_u32(chunk_size)?; // Chunk Size
+        writer.write_be_to_u16(nb_channels)?;
+        writer.write_be_to_u32(self.pcm.samples.len() as u32 / u32::from(nb_channels.max(1)))?; // Number of sample frames
+        writer.write_be_to_u16(u16::from(self.sample_type.get_sample_size()) * 8)?; // Bits per sample
+        write_extended(writer, f64::from(self.pcm.parameters.sample_rate))?;
+        if let Some((compression_type, name)) = compression {
+            writer.write_all(&compression_type)?;
+            write_pascal_string(writer, name)?;
+        }
+        Ok(())
+    }
+    /// Writes the loops of the PCM as a Marker Chunk and an Instrument Chunk, if there are any
+    fn write_loop_chunks<W: Write>(&self, writer: &mut W) -> IOResult<()> {
+        let sample_rate_float = f64::from(self.pcm.parameters.sample_rate);
+        let mut loop_points = Vec::with_capacity(2);
+        for span in self.pcm.loop_info.iter().take(2) {
+            let start = (span.start_at().get() * sample_rate_float).round() as u32; // Lossy
+            let end = (span.end_at().get() * sample_rate_float).round() as u32; // Lossy
+            if end > start {
+                loop_points.push((start, end));
+            }
+        }
+        if loop_points.is_empty() {
+            return Ok(());
+        }
+        // Every loop gets two markers, one at its start and one right after its last sample
+        writer.write_all(&[b'M', b'A', b'R', b'K'])?; // Marker Chunk
+        writer.write_be_to_u32(2 + (loop_points.len() as u32 * 2 * (2 + 4 + 2)))?; // Chunk Size
+        writer.write_be_to_u16(loop_points.len() as u16 * 2)?; // Number of Markers
+        for (loop_nb, (start, end)) in loop_points.iter().enumerate() {
+            writer.write_be_to_u16((loop_nb as u16 * 2) + 1)?; // Marker ID
+            writer.write_be_to_u32(*start)?;
+            write_pascal_string(writer, "")?;
+            writer.write_be_to_u16((loop_nb as u16 * 2) + 2)?; // Marker ID
+            writer.write_be_to_u32(*end)?;
+            write_pascal_string(writer, "")?;
+        }
+        writer.write_all(&[b'I', b'N', b'S', b'T'])?; // Instrument Chunk
+        writer.write_be_to_u32(20)?; // Chunk Size
+        writer.write_to_u8(60)?; // Base Note
+        writer.write_to_i8(0)?; // Detune
+        writer.write_to_u8(0)?; // Low Note
+        writer.write_to_u8(127)?; // High Note
+        writer.write_to_u8(1)?; // Low Velocity
+        writer.write_to_u8(127)?; // High Velocity
+        writer.write_be_to_i16(0)?; // Gain
+        for loop_nb in 0..2 {
+            if loop_nb < loop_points.len() {
+                writer.write_be_to_u16(1)?; // Play Mode, 1 is Forward
+                writer.write_be_to_u16((loop_nb as u16 * 2) + 1)?; // Begin Marker ID
+                writer.write_be_to_u16((loop_nb as u16 * 2) + 2)?; // End Marker ID
+            } else {
+                writer.write_be_to_u16(0)?; // Play Mode, 0 is No Looping
+                writer.write_be_to_u16(0)?;
+                writer.write_be_to_u16(0)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Common {
+    /// Reads the contents of a Common Chunk
+    fn read<R: Read>(reader: &mut R, chunk_size: u32, is_aifc: bool) -> Result<Common, ReadError> {
+        if chunk_size < 18 {
+            return Err(MalformedChunkError {
+                chunk_id: *b"COMM",
+                reason: "Chunk is too small",
+            }
+            .into());
+        }
+        let nb_channels = reader.read_be_to_u16()?;
+        let nb_frames = reader.read_be_to_u32()?;
+        let bits_per_sample = reader.read_be_to_u16()?;
+        let sample_rate = read_extended(reader)?.round();
+        let mut compression_type = *b"NONE";
+        if is_aifc {
+            if chunk_size < 22 {
+                return Err(MalformedChunkError {
+                    chunk_id: *b"COMM",
+                    reason: "Chunk is too small for an AIFC file",
+                }
+                .into());
+            }
+            reader.read_exact(&mut compression_type)?;
+        }
+        if nb_channels == 0 {
+            return Err(MalformedChunkError {
+                chunk_id: *b"COMM",
+                reason: "No channels",
+            }
+            .into());
+        }
+        if !(sample_rate >= 1f64 && sample_rate <= f64::from(u32::max_value())) {
+            return Err(MalformedChunkError {
+                chunk_id: *b"COMM",
+                reason: "Sample rate is invalid",
+            }
+            .into());
+        }
+        // Integer samples that do not fill their bytes are left-justified, so they can be read as if they did
+        let container_bits = ((bits_per_sample + 7) / 8) * 8;
+        let (sample_type, little_endian) = match &compression_type {
+            b"NONE" | b"twos" => (
+                SampleType::from_format(WAVE_FORMAT_PCM, container_bits),
+                false,
+            ),
+            b"sowt" => (
+                SampleType::from_format(WAVE_FORMAT_PCM, container_bits),
+                true,
+            ),
+            b"fl32" | b"FL32" => (Some(SampleType::Float32), false),
+            b"fl64" | b"FL64" => (Some(SampleType::Float64), false),
+            _ => return Err(UnsupportedCompressionError { compression_type }.into()),
+        };
+        let sample_type = sample_type.ok_or(UnsupportedFormatError {
+            format_tag: WAVE_FORMAT_PCM,
+            bits_per_sample,
+        })?;
+        Ok(Common {
+            nb_channels,
+            nb_frames,
+            sample_rate: sample_rate as u32,
+            sample_type,
+            little_endian,
+        })
+    }
+    /// Converts the raw contents of a Sound Data Chunk to f64 samples
+    fn decode(&self, data: &[u8]) -> Result<Vec<f64>, ReadError> {
+        let sample_size = usize::from(self.sample_type.get_sample_size());
+        let nb_samples = self.nb_frames as usize * usize::from(self.nb_channels);
+        if data.len() < nb_samples * sample_size {
+            return Err(MalformedChunkError {
+                chunk_id: *b"SSND",
+                reason: "Not enough data for the number of sample frames",
+            }
+            .into());
+        }
+        let max_value = self.sample_type.get_max_value();
+        let mut samples = Vec::with_capacity(nb_samples);
+        let mut data_reader = Cursor::new(data);
+        for _ in 0..nb_samples {
+            samples.push(match (self.sample_type, self.little_endian) {
+                // Stored as signed, but scaled the same way as Wave files
+                (SampleType::Unsigned8, _) => {
+                    (((f64::from(data_reader.read_to_i8()?) + 128f64) / max_value) * 2f64) - 1f64
+                }
+                (SampleType::Signed16, false) => {
+                    f64::from(data_reader.read_be_to_i16()?) / max_value
+                }
+                (SampleType::Signed16, true) => {
+                    f64::from(data_reader.read_le_to_i16()?) / max_value
+                }
+                (SampleType::Signed24, little_endian) => {
+                    let mut bytes = [0u8; 4];
+                    data_reader.read_exact(&mut bytes[..3])?;
+                    if little_endian {
+                        bytes = [0, bytes[0], bytes[1], bytes[2]];
+                    } else {
+                        bytes = [0, bytes[2], bytes[1], bytes[0]];
+                    }
+                    // Shifting back down keeps the sign
+                    f64::from(i32::from_le_bytes(bytes) >> 8) / max_value
+                }
+                (SampleType::Signed32, false) => {
+                    f64::from(data_reader.read_be_to_i32()?) / max_value
+                }
+                (SampleType::Signed32, true) => {
+                    f64::from(data_reader.read_le_to_i32()?) / max_value
+                }
+                (SampleType::Float32, _) => f64::from(data_reader.read_be_to_f32()?),
+                (SampleType::Float64, _) => data_reader.read_be_to_f64()?,
+            });
+        }
+        Ok(samples)
+    }
+}
+
+/// Writes a single sample in [-1; 1] in Big Endian
+fn write_sample<W: Write>(writer: &mut W, sample_type: SampleType, value: f64) -> IOResult<()> {
+    let max_value = sample_type.get_max_value();
+    match sample_type {
+        // AIFF only has signed integers, the Wave scaling is kept and shifted
+        SampleType::Unsigned8 => {
+            writer.write_to_i8(((((value + 1f64) / 2f64) * max_value).round() - 128f64) as i8)
+        }
+        SampleType::Signed16 => writer.write_be_to_i16((value * max_value).round() as i16),
+        SampleType::Signed24 => {
+            let sample = (value * max_value).round() as i32;
+            writer.write_all(&sample.to_be_bytes()[1..])
+        }
+        SampleType::Signed32 => writer.write_be_to_i32((value * max_value).round() as i32),
+        SampleType::Float32 => writer.write_be_to_f32(value as f32),
+        SampleType::Float64 => writer.write_be_to_f64(value),
+    }
+}
+
+/// Reads the contents of a Sound Data Chunk, skipping the offset
+fn read_sound_data_chunk<R: Read>(reader: &mut R, chunk_size: u32) -> Result<Vec<u8>, ReadError> {
+    if chunk_size < 8 {
+        return Err(MalformedChunkError {
+            chunk_id: *b"SSND",
+            reason: "Chunk is too small",
+        }
+        .into());
+    }
+    let offset = reader.read_be_to_u32()?;
+    let _block_size = reader.read_be_to_u32()?;
+    if u64::from(offset) > u64::from(chunk_size) - 8 {
+        return Err(MalformedChunkError {
+            chunk_id: *b"SSND",
+            reason: "Offset goes past the end of the chunk",
+        }
+        .into());
+    }
+    let mut data = read_chunk_data(reader, u64::from(chunk_size) - 8)?;
+    Ok(data.split_off(offset as usize))
+}
+
+/// Reads the positions of the markers of a Marker Chunk, by Marker ID
+fn read_marker_chunk<R: Read>(
+    reader: &mut R,
+    chunk_size: u32,
+) -> Result<HashMap<u16, u32>, ReadError> {
+    if chunk_size < 2 {
+        return Err(MalformedChunkError {
+            chunk_id: *b"MARK",
+            reason: "Chunk is too small",
+        }
+        .into());
+    }
+    let nb_markers = reader.read_be_to_u16()?;
+    let mut markers = HashMap::with_capacity(usize::from(nb_markers));
+    for _ in 0..nb_markers {
+        let id = reader.read_be_to_u16()?;
+        let position = reader.read_be_to_u32()?;
+        read_pascal_string(reader)?;
+        markers.insert(id, position);
+    }
+    Ok(markers)
+}
+
+/// Reads the Marker IDs of the beginning and end of the loops of an Instrument Chunk
+fn read_instrument_chunk<R: Read>(
+    reader: &mut R,
+    chunk_size: u32,
+) -> Result<Vec<(u16, u16)>, ReadError> {
+    if chunk_size < 20 {
+        return Err(MalformedChunkError {
+            chunk_id: *b"INST",
+            reason: "Chunk is too small",
+        }
+        .into());
+    }
+    // Skip Base Note, Detune, Low Note, High Note, Low Velocity, High Velocity and Gain
+    let mut header = [0u8; 8];
+    reader.read_exact(&mut header)?;
+    let mut loop_markers = Vec::with_capacity(2);
+    // Sustain Loop then Release Loop. The first loop is the one that gets played, so a Release Loop without a Sustain Loop is left out.
+    for _ in 0..2 {
+        let play_mode = reader.read_be_to_u16()?;
+        let begin = reader.read_be_to_u16()?;
+        let end = reader.read_be_to_u16()?;
+        if play_mode == 0 {
+            break;
+        }
+        loop_markers.push((begin, end));
+    }
+    Ok(loop_markers)
+}
+
+/// Get the size of a Pascal-style string, including the count byte and the padding byte
+fn get_pascal_string_size(text: &str) -> u32 {
+    let size = 1 + text.len() as u32;
+    size + (size % 2)
+}
+
+/// Writes a Pascal-style string, padded to an even size
+fn write_pascal_string<W: Write>(writer: &mut W, text: &str) -> IOResult<()> {
+    writer.write_to_u8(text.len() as u8)?;
+    writer.write_all(text.as_bytes())?;
+    if text.len() % 2 == 0 {
+        writer.write_all(&[0])?; // Padding Byte
+    }
+    Ok(())
+}
+
+/// Reads a Pascal-style string, padded to an even size
+fn read_pascal_string<R: Read>(reader: &mut R) -> IOResult<String> {
+    let length = reader.read_to_u8()?;
+    let mut bytes = vec![0u8; usize::from(length) + ((usize::from(length) + 1) % 2)];
+    reader.read_exact(&mut bytes)?;
+    bytes.truncate(usize::from(length));
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Writes a positive number as an 80 bits IEEE Extended float
+fn write_extended<W: Write>(writer: &mut W, value: f64) -> IOResult<()> {
+    if value == 0f64 {
+        return writer.write_all(&[0u8; 10]);
+    }
+    let bits = value.to_bits();
+    let exponent = ((bits >> 52) & 0x7FF) as i64 - 1023;
+    // The integer part of the mantissa is explicit in 80 bits floats
+    let mantissa = ((bits & 0x000F_FFFF_FFFF_FFFF) | (1 << 52)) << 11;
+    writer.write_be_to_u16((exponent + 16383) as u16)?;
+    writer.write_be_to_u64(mantissa)
+}
+
+/// Reads an 80 bits IEEE Extended float
+fn read_extended<R: Read>(reader: &mut R) -> IOResult<f64> {
+    let sign_exponent = reader.read_be_to_u16()?;
+    let mantissa = reader.read_be_to_u64()?;
+    let exponent = i32::from(sign_exponent & 0x7FFF) - 16383;
+    let value = (mantissa as f64) * 2f64.powi(exponent - 63);
+    if sign_exponent & 0x8000 == 0 {
+        Ok(value)
+    } else {
+        Ok(-value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(sample_type: SampleType, nb_channels: u16, samples: Vec<f64>) {
+        let aiff = Aiff {
+            pcm: PCM {
+                parameters: PCMParameters {
+                    sample_rate: 22_050,
+                    nb_channels,
+                    speaker_layout: SpeakerLayout::Unspecified,
+                },
+                loop_info: vec![TimeSpan::new(
+                    Time::new(100f64 / 22_050f64).unwrap(),
+                    Time::new(300f64 / 22_050f64).unwrap(),
+                )
+                .unwrap()],
+                samples: samples.clone(),
+            },
+            sample_type,
+            gain: Gain::HardClip(1f64),
+            dither: Dither::None,
+        };
+        let mut bytes = Vec::new();
+        aiff.write(&mut bytes).unwrap();
+        let read = Aiff::read(&mut Cursor::new(bytes)).unwrap();
+        assert!(read.sample_type == sample_type);
+        assert_eq!(read.pcm.parameters.sample_rate, 22_050);
+        assert_eq!(read.pcm.parameters.nb_channels, nb_channels);
+        assert!(read.pcm.samples == samples);
+        assert_eq!(read.pcm.loop_info.len(), 1);
+        let loop_span = read.pcm.loop_info[0];
+        assert!((loop_span.start_at().get() * 22_050f64 - 100f64).abs() < 1e-6);
+        assert!((loop_span.end_at().get() * 22_050f64 - 300f64).abs() < 1e-6);
+    }
+
+    #[test]
+    fn round_trip_integer() {
+        // An odd number of 24 bits samples needs a padding byte
+        let max_value = SampleType::Signed24.get_max_value();
+        let samples = (0..999)
+            .map(|i| f64::from((i * 16_807) % 16_777_215 - 8_388_607) / max_value)
+            .collect();
+        round_trip(SampleType::Signed24, 1, samples);
+    }
+
+    #[test]
+    fn round_trip_float() {
+        let samples = (0..1000)
+            .map(|i| f64::from(i % 2048 - 1024) / 1024f64)
+            .collect();
+        round_trip(SampleType::Float32, 2, samples);
+    }
+}
@@ -11,6 +11,11 @@ pub enum SynthesizerError {
     EmptySequence(EmptySequenceError),
     NoInstrument(NoInstrumentError),
     NoKeyInInstrument(NoKeyInInstrumentError),
+    NoTempoMap(NoTempoMapError),
+    NoMIDIKey(NoMIDIKeyError),
+    NoInstrumentForProgram(NoInstrumentForProgramError),
+    Parse(ParseError),
+    UnsupportedParameters(UnsupportedParametersError),
 }
 
 impl Error for SynthesizerError {
@@ -22,6 +27,11 @@ impl Error for SynthesizerError {
             SynthesizerError::EmptySequence(ref e) => e.description(),
             SynthesizerError::NoInstrument(ref e) => e.description(),
             SynthesizerError::NoKeyInInstrument(ref e) => e.description(),
+            SynthesizerError::NoTempoMap(ref e) => e.description(),
+            SynthesizerError::NoMIDIKey(ref e) => e.description(),
+            SynthesizerError::NoInstrumentForProgram(ref e) => e.description(),
+            SynthesizerError::Parse(ref e) => e.description(),
+            SynthesizerError::UnsupportedParameters(ref e) => e.description(),
         }
     }
 }
@@ -35,6 +45,11 @@ impl Display for SynthesizerError {
             SynthesizerError::EmptySequence(ref e) => e.fmt(f),
             SynthesizerError::NoInstrument(ref e) => e.fmt(f),
             SynthesizerError::NoKeyInInstrument(ref e) => e.fmt(f),
+            SynthesizerError::NoTempoMap(ref e) => e.fmt(f),
+            SynthesizerError::NoMIDIKey(ref e) => e.fmt(f),
+            SynthesizerError::NoInstrumentForProgram(ref e) => e.fmt(f),
+            SynthesizerError::Parse(ref e) => e.fmt(f),
+            SynthesizerError::UnsupportedParameters(ref e) => e.fmt(f),
         }
     }
 }
@@ -75,6 +90,36 @@ impl From<NoKeyInInstrumentError> for SynthesizerError {
     }
 }
 
+impl From<NoTempoMapError> for SynthesizerError {
+    fn from(e: NoTempoMapError) -> SynthesizerError {
+        SynthesizerError::NoTempoMap(e)
+    }
+}
+
+impl From<NoMIDIKeyError> for SynthesizerError {
+    fn from(e: NoMIDIKeyError) -> SynthesizerError {
+        SynthesizerError::NoMIDIKey(e)
+    }
+}
+
+impl From<NoInstrumentForProgramError> for SynthesizerError {
+    fn from(e: NoInstrumentForProgramError) -> SynthesizerError {
+        SynthesizerError::NoInstrumentForProgram(e)
+    }
+}
+
+impl From<ParseError> for SynthesizerError {
+    fn from(e: ParseError) -> SynthesizerError {
+        SynthesizerError::Parse(e)
+    }
+}
+
+impl From<UnsupportedParametersError> for SynthesizerError {
+    fn from(e: UnsupportedParametersError) -> SynthesizerError {
+        SynthesizerError::UnsupportedParameters(e)
+    }
+}
+
 /// Raised when some f64 value cannot be used as a valid Time (negative, not finite, not a number)
 #[derive(Debug)]
 pub struct TimeInvalidError {
@@ -186,11 +231,107 @@ impl Display for NoKeyInInstrumentError {
     }
 }
 
+/// Raised when moving in ticks with a SequenceHelper that has no Tempo Map
+#[derive(Debug)]
+pub struct NoTempoMapError {}
+
+impl Error for NoTempoMapError {
+    fn description(&self) -> &str {
+        "Ticks cannot be converted to Time without a Tempo Map"
+    }
+}
+
+impl Display for NoTempoMapError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "No Tempo Map set")
+    }
+}
+
+/// Raised when the Frequency of a Frequency ID does not match any MIDI key
+#[derive(Debug)]
+pub struct NoMIDIKeyError {
+    /// The Frequency ID that could not be converted
+    pub f_id: usize,
+    /// Its Frequency
+    pub frequency: f64,
+}
+
+impl Error for NoMIDIKeyError {
+    fn description(&self) -> &str {
+        "A Frequency could not be matched to a MIDI key"
+    }
+}
+
+impl Display for NoMIDIKeyError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(
+            f,
+            "Frequency ID: {}, Frequency: {}Hz",
+            self.f_id, self.frequency
+        )
+    }
+}
+
+/// Raised when the Program Lookup has no Instrument for what a MIDI channel plays
+#[derive(Debug)]
+pub struct NoInstrumentForProgramError {
+    /// The MIDI channel, starting from 0
+    pub channel: u8,
+    /// The selected bank
+    pub bank: u16,
+    /// The selected program
+    pub program: u8,
+}
+
+impl Error for NoInstrumentForProgramError {
+    fn description(&self) -> &str {
+        "No Instrument could be found for a MIDI program"
+    }
+}
+
+impl Display for NoInstrumentForProgramError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(
+            f,
+            "Channel: {}, Bank: {}, Program: {}",
+            self.channel, self.bank, self.program
+        )
+    }
+}
+
+/// Raised when a text music format could not be understood
+#[derive(Debug)]
+pub struct ParseError {
+    /// Line of the problem, starting from 1
+    pub line: usize,
+    /// Column of the problem in characters, starting from 1
+    pub column: usize,
+    /// What went wrong
+    pub reason: &'static str,
+}
+
+impl Error for ParseError {
+    fn description(&self) -> &str {
+        "Some text could not be parsed"
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(
+            f,
+            "Line {}, column {}: {}",
+            self.line, self.column, self.reason
+        )
+    }
+}
+
 /// Possible errors when writing the PCM down
 #[derive(Debug)]
 pub enum WriteError {
     IO(IOError),
     NoSamples(NoSamplesError),
+    UnsupportedParameters(UnsupportedParametersError),
 }
 
 impl Error for WriteError {
@@ -198,6 +339,7 @@ impl Error for WriteError {
         match *self {
             WriteError::IO(ref e) => e.description(),
             WriteError::NoSamples(ref e) => e.description(),
+            WriteError::UnsupportedParameters(ref e) => e.description(),
         }
     }
 }
@@ -207,6 +349,7 @@ impl Display for WriteError {
         match self {
             WriteError::IO(ref e) => e.fmt(f),
             WriteError::NoSamples(ref e) => e.fmt(f),
+            WriteError::UnsupportedParameters(ref e) => e.fmt(f),
         }
     }
 }
@@ -223,6 +366,12 @@ impl From<NoSamplesError> for WriteError {
     }
 }
 
+impl From<UnsupportedParametersError> for WriteError {
+    fn from(e: UnsupportedParametersError) -> WriteError {
+        WriteError::UnsupportedParameters(e)
+    }
+}
+
 /// Raised when there is no Sample in a PCM
 #[derive(Debug)]
 pub struct NoSamplesError {}
@@ -238,3 +387,251 @@ impl Display for NoSamplesError {
         write!(f, "No samples")
     }
 }
+
+/// Raised when a PCM cannot be written with the requested parameters in a format
+#[derive(Debug)]
+pub struct UnsupportedParametersError {
+    /// What the format cannot store
+    pub reason: &'static str,
+}
+
+impl Error for UnsupportedParametersError {
+    fn description(&self) -> &str {
+        "The format cannot store a PCM with these parameters."
+    }
+}
+
+impl Display for UnsupportedParametersError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "{}", self.reason)
+    }
+}
+
+/// Possible errors when reading a file into a PCM
+#[derive(Debug)]
+pub enum ReadError {
+    IO(IOError),
+    WrongMagicNumber(WrongMagicNumberError),
+    MissingChunk(MissingChunkError),
+    MalformedChunk(MalformedChunkError),
+    UnsupportedFormat(UnsupportedFormatError),
+    TimeInvalid(TimeInvalidError),
+    UnsupportedCompression(UnsupportedCompressionError),
+    MalformedModule(MalformedModuleError),
+}
+
+impl Error for ReadError {
+    fn description(&self) -> &str {
+        match *self {
+            ReadError::IO(ref e) => e.description(),
+            ReadError::WrongMagicNumber(ref e) => e.description(),
+            ReadError::MissingChunk(ref e) => e.description(),
+            ReadError::MalformedChunk(ref e) => e.description(),
+            ReadError::UnsupportedFormat(ref e) => e.description(),
+            ReadError::TimeInvalid(ref e) => e.description(),
+            ReadError::UnsupportedCompression(ref e) => e.description(),
+            ReadError::MalformedModule(ref e) => e.description(),
+        }
+    }
+}
+
+impl Display for ReadError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self {
+            ReadError::IO(ref e) => e.fmt(f),
+            ReadError::WrongMagicNumber(ref e) => e.fmt(f),
+            ReadError::MissingChunk(ref e) => e.fmt(f),
+            ReadError::MalformedChunk(ref e) => e.fmt(f),
+            ReadError::UnsupportedFormat(ref e) => e.fmt(f),
+            ReadError::TimeInvalid(ref e) => e.fmt(f),
+            ReadError::UnsupportedCompression(ref e) => e.fmt(f),
+            ReadError::MalformedModule(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl From<IOError> for ReadError {
+    fn from(e: IOError) -> ReadError {
+        ReadError::IO(e)
+    }
+}
+
+impl From<WrongMagicNumberError> for ReadError {
+    fn from(e: WrongMagicNumberError) -> ReadError {
+        ReadError::WrongMagicNumber(e)
+    }
+}
+
+impl From<MissingChunkError> for ReadError {
+    fn from(e: MissingChunkError) -> ReadError {
+        ReadError::MissingChunk(e)
+    }
+}
+
+impl From<MalformedChunkError> for ReadError {
+    fn from(e: MalformedChunkError) -> ReadError {
+        ReadError::MalformedChunk(e)
+    }
+}
+
+impl From<UnsupportedFormatError> for ReadError {
+    fn from(e: UnsupportedFormatError) -> ReadError {
+        ReadError::UnsupportedFormat(e)
+    }
+}
+
+impl From<TimeInvalidError> for ReadError {
+    fn from(e: TimeInvalidError) -> ReadError {
+        ReadError::TimeInvalid(e)
+    }
+}
+
+impl From<UnsupportedCompressionError> for ReadError {
+    fn from(e: UnsupportedCompressionError) -> ReadError {
+        ReadError::UnsupportedCompression(e)
+    }
+}
+
+impl From<MalformedModuleError> for ReadError {
+    fn from(e: MalformedModuleError) -> ReadError {
+        ReadError::MalformedModule(e)
+    }
+}
+
+/// Raised when a file does not start with the expected identifier
+#[derive(Debug)]
+pub struct WrongMagicNumberError {
+    /// What should have been read
+    pub expected: [u8; 4],
+    /// What was actually read
+    pub found: [u8; 4],
+}
+
+impl Error for WrongMagicNumberError {
+    fn description(&self) -> &str {
+        "The file does not start with the expected identifier."
+    }
+}
+
+impl Display for WrongMagicNumberError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(
+            f,
+            "Expected {:?}, found {:?}",
+            String::from_utf8_lossy(&self.expected),
+            String::from_utf8_lossy(&self.found)
+        )
+    }
+}
+
+/// Raised when a chunk required to decode the file could not be found
+#[derive(Debug)]
+pub struct MissingChunkError {
+    /// The identifier of the missing chunk
+    pub chunk_id: [u8; 4],
+}
+
+impl Error for MissingChunkError {
+    fn description(&self) -> &str {
+        "A required chunk is missing from the file."
+    }
+}
+
+impl Display for MissingChunkError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "Chunk: {:?}", String::from_utf8_lossy(&self.chunk_id))
+    }
+}
+
+/// Raised when the content of a chunk does not make sense
+#[derive(Debug)]
+pub struct MalformedChunkError {
+    /// The identifier of the faulty chunk
+    pub chunk_id: [u8; 4],
+    /// What is wrong with it
+    pub reason: &'static str,
+}
+
+impl Error for MalformedChunkError {
+    fn description(&self) -> &str {
+        "A chunk in the file is malformed."
+    }
+}
+
+impl Display for MalformedChunkError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(
+            f,
+            "Chunk {:?}: {}",
+            String::from_utf8_lossy(&self.chunk_id),
+            self.reason
+        )
+    }
+}
+
+/// Raised when the audio is stored in a way this crate does not know how to decode
+#[derive(Debug)]
+pub struct UnsupportedFormatError {
+    /// The format tag found in the file
+    pub format_tag: u16,
+    /// The number of bits per sample found in the file
+    pub bits_per_sample: u16,
+}
+
+impl Error for UnsupportedFormatError {
+    fn description(&self) -> &str {
+        "The audio format of the file is not supported."
+    }
+}
+
+impl Display for UnsupportedFormatError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(
+            f,
+            "Format tag: {}, Bits per sample: {}",
+            self.format_tag, self.bits_per_sample
+        )
+    }
+}
+
+/// Raised when an AIFC file uses a compression this crate does not know how to decode
+#[derive(Debug)]
+pub struct UnsupportedCompressionError {
+    /// The compression type found in the file
+    pub compression_type: [u8; 4],
+}
+
+impl Error for UnsupportedCompressionError {
+    fn description(&self) -> &str {
+        "The compression type of the file is not supported."
+    }
+}
+
+impl Display for UnsupportedCompressionError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(
+            f,
+            "Compression type: {:?}",
+            String::from_utf8_lossy(&self.compression_type)
+        )
+    }
+}
+
+/// Raised when a tracker module is not laid out as its format requires
+#[derive(Debug)]
+pub struct MalformedModuleError {
+    /// What is wrong with it
+    pub reason: &'static str,
+}
+
+impl Error for MalformedModuleError {
+    fn description(&self) -> &str {
+        "The tracker module is malformed."
+    }
+}
+
+impl Display for MalformedModuleError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "{}", self.reason)
+    }
+}
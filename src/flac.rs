@@ -0,0 +1,888 @@
+use error::{UnsupportedParametersError, WriteError};
+use pcm::PCM;
+use std::io::Write;
+use std::result::Result;
+use wave::{Dither, Gain, Quantizer, SampleType, WriteReport};
+
+/// Represents a FLAC stream. Only 8, 16 and 24 bits integer sample types can be used.
+pub struct Flac {
+    pub pcm: PCM,
+    pub sample_type: SampleType,
+    /// How the PCM gets scaled before being encoded
+    pub gain: Gain,
+    /// How the quantization error is handled
+    pub dither: Dither,
+}
+
+/// Number of sample frames in every block but the last one
+const BLOCK_SIZE: usize = 4096;
+/// Highest LPC order tried when encoding a subframe
+const MAX_LPC_ORDER: usize = 8;
+/// Number of bits of the quantized LPC coefficients
+const LPC_PRECISION: u32 = 12;
+/// Highest partition order tried when Rice coding the residual
+const MAX_PARTITION_ORDER: u32 = 8;
+
+/// How a subframe describes its samples
+enum Prediction {
+    /// Every sample has the same value
+    Constant,
+    /// Samples are stored as they are
+    Verbatim,
+    /// One of the fixed polynomial predictors, with its order
+    Fixed(usize),
+    /// Linear prediction with quantized coefficients and a shift
+    Lpc(Vec<i32>, u32),
+}
+
+/// Writes values bit by bit, most significant bit first
+struct BitWriter {
+    bytes: Vec<u8>,
+    /// Bits that do not form a full byte yet, in the lowest bits
+    accumulator: u64,
+    nb_bits: u32,
+}
+
+/// Computes an MD5 hash, as required by the STREAMINFO block
+struct Md5 {
+    state: [u32; 4],
+    /// Bytes that do not form a full 64 bytes block yet
+    buffer: [u8; 64],
+    length: u64,
+}
+
+impl Flac {
+    /// Encodes to a FLAC stream
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<WriteReport, WriteError> {
+        let bits_per_sample: u32 = match self.sample_type {
+            SampleType::Unsigned8 => 8,
+            SampleType::Signed16 => 16,
+            SampleType::Signed24 => 24,
+            _ => {
+                return Err(UnsupportedParametersError {
+                    reason: "FLAC only stores 8, 16 and 24 bits integer samples",
+                }
+                .into())
+            }
+        };
+        let nb_channels = usize::from(self.pcm.parameters.nb_channels);
+        if (nb_channels == 0) | (nb_channels > 8) {
+            return Err(UnsupportedParametersError {
+                reason: "FLAC only stores 1 to 8 channels",
+            }
+            .into());
+        }
+        let sample_rate = self.pcm.parameters.sample_rate;
+        if (sample_rate == 0) | (sample_rate > 655_350) {
+            return Err(UnsupportedParametersError {
+                reason: "FLAC only stores sample rates up to 655350 Hz",
+            }
+            .into());
+        }
+        let factor = self.gain.get_factor(&self.pcm)?;
+        let mut quantizer = Quantizer::new(self.sample_type, self.dither, nb_channels as u16);
+        let max_value = self.sample_type.get_max_value();
+        let nb_frames = self.pcm.samples.len() / nb_channels;
+        let mut nb_clipped = 0;
+        let mut md5 = Md5::new();
+        let mut frames = Vec::new();
+        let mut min_frame_size = u32::max_value();
+        let mut max_frame_size = 0;
+        let mut channels = vec![Vec::with_capacity(BLOCK_SIZE); nb_channels];
+        for (frame_nb, block) in self.pcm.samples[..nb_frames * nb_channels]
+            .chunks(BLOCK_SIZE * nb_channels)
+            .enumerate()
+        {
+            for channel in &mut channels {
+                channel.clear();
+            }
+            for (sample_nb, sample) in block.iter().enumerate() {
+                let (value, clipped) = self.gain.apply(sample * factor, self.sample_type);
+                if clipped {
+                    nb_clipped += 1;
+                }
+                let value = quantizer.quantize(value);
+                let integer = match self.sample_type {
+                    // Scaled the same way as Wave files, but stored as signed
+                    SampleType::Unsigned8 => {
+                        ((((value + 1f64) / 2f64) * max_value).round() - 128f64) as i32
+                    }
+                    _ => (value * max_value).round() as i32,
+                };
+                md5.update(&integer.to_le_bytes()[..(bits_per_sample / 8) as usize]);
+                channels[sample_nb % nb_channels].push(integer);
+            }
+            let frame = encode_frame(&channels, frame_nb as u64, bits_per_sample);
+            min_frame_size = min_frame_size.min(frame.len() as u32);
+            max_frame_size = max_frame_size.max(frame.len() as u32);
+            frames.extend_from_slice(&frame);
+        }
+        // STREAMINFO Block
+        let mut header = BitWriter::new();
+        header.write(1, 1); // Last Metadata Block
+        header.write(0, 7); // STREAMINFO
+        header.write(34, 24); // Length
+        header.write(BLOCK_SIZE as u64, 16); // Minimum Block Size, only the last block can be smaller
+        header.write(BLOCK_SIZE as u64, 16); // Maximum Block Size
+        header.write(u64::from(min_frame_size.min(max_frame_size)), 24);
+        header.write(u64::from(max_frame_size), 24);
+        header.write(u64::from(sample_rate), 20);
+        header.write(nb_channels as u64 - 1, 3);
+        header.write(u64::from(bits_per_sample - 1), 5);
+        header.write(nb_frames as u64, 36); // Total number of sample frames
+        writer.write_all(&[b'f', b'L', b'a', b'C'])?;
+        writer.write_all(&header.into_bytes())?;
+        writer.write_all(&md5.finish())?;
+        writer.write_all(&frames)?;
+        Ok(WriteReport { nb_clipped })
+    }
+}
+
+/// Encodes a whole frame with one subframe per channel
+fn encode_frame(channels: &[Vec<i32>], frame_nb: u64, bits_per_sample: u32) -> Vec<u8> {
+    let block_size = channels[0].len();
+    let mut frame = BitWriter::new();
+    frame.write(0b11_1111_1111_1110, 14); // Sync Code
+    frame.write(0, 1); // Reserved
+    frame.write(0, 1); // Fixed Block Size
+    if block_size == BLOCK_SIZE {
+        frame.write(0b1100, 4); // 4096 sample frames
+    } else {
+        frame.write(0b0111, 4); // Block Size stored after the header
+    }
+    frame.write(0, 4); // Sample Rate from STREAMINFO
+    frame.write(channels.len() as u64 - 1, 4); // Independent channels
+    frame.write(
+        match bits_per_sample {
+            8 => 0b001,
+            16 => 0b100,
+            _ => 0b110,
+        },
+        3,
+    );
+    frame.write(0, 1); // Reserved
+    frame.write_utf8(frame_nb);
+    if block_size != BLOCK_SIZE {
+        frame.write(block_size as u64 - 1, 16);
+    }
+    let crc = crc8(&frame.bytes);
+    frame.write(u64::from(crc), 8);
+    for channel in channels {
+        encode_subframe(&mut frame, channel, bits_per_sample);
+    }
+    frame.align();
+    let crc = crc16(&frame.bytes);
+    frame.write(u64::from(crc), 16);
+    frame.into_bytes()
+}
+
+/// Finds the prediction that takes the least space and writes the subframe with it
+fn encode_subframe(frame: &mut BitWriter, samples: &[i32], bits_per_sample: u32) {
+    let mut best = (
+        Prediction::Verbatim,
+        samples.len() as u64 * u64::from(bits_per_sample),
+    );
+    let mut best_residual = Vec::new();
+    if samples.iter().all(|&s| s == samples[0]) {
+        best = (Prediction::Constant, u64::from(bits_per_sample));
+    } else {
+        for order in 0..=4.min(samples.len() - 1) {
+            if let Some(residual) = fixed_residual(samples, order) {
+                let cost = (order as u64 * u64::from(bits_per_sample))
+                    + rice_partitions(&residual, samples.len(), order).2;
+                if cost < best.1 {
+                    best = (Prediction::Fixed(order), cost);
+                    best_residual = residual;
+                }
+            }
+        }
+        for (coefficients, shift) in lpc_coefficients(samples) {
+            let order = coefficients.len();
+            if let Some(residual) = lpc_residual(samples, &coefficients, shift) {
+                let cost = (order as u64 * u64::from(bits_per_sample + LPC_PRECISION))
+                    + 9
+                    + rice_partitions(&residual, samples.len(), order).2;
+                if cost < best.1 {
+                    best = (Prediction::Lpc(coefficients, shift), cost);
+                    best_residual = residual;
+                }
+            }
+        }
+    }
+    frame.write(0, 1); // Padding
+    match best.0 {
+        Prediction::Constant => {
+            frame.write(0, 6);
+            frame.write(0, 1); // No wasted bits
+            frame.write_signed(i64::from(samples[0]), bits_per_sample);
+        }
+        Prediction::Verbatim => {
+            frame.write(1, 6);
+            frame.write(0, 1); // No wasted bits
+            for sample in samples {
+                frame.write_signed(i64::from(*sample), bits_per_sample);
+            }
+        }
+        Prediction::Fixed(order) => {
+            frame.write(0b00_1000 | order as u64, 6);
+            frame.write(0, 1); // No wasted bits
+            for sample in &samples[..order] {
+                frame.write_signed(i64::from(*sample), bits_per_sample);
+            }
+            write_residual(frame, &best_residual, samples.len(), order);
+        }
+        Prediction::Lpc(ref coefficients, shift) => {
+            let order = coefficients.len();
+            frame.write(0b10_0000 | (order as u64 - 1), 6);
+            frame.write(0, 1); // No wasted bits
+            for sample in &samples[..order] {
+                frame.write_signed(i64::from(*sample), bits_per_sample);
+            }
+            frame.write(u64::from(LPC_PRECISION - 1), 4);
+            frame.write(u64::from(shift), 5);
+            for coefficient in coefficients {
+                frame.write_signed(i64::from(*coefficient), LPC_PRECISION);
+            }
+            write_residual(frame, &best_residual, samples.len(), order);
+        }
+    }
+}
+
+/// Computes the residual of a fixed predictor, if it fits in 32 bits
+fn fixed_residual(samples: &[i32], order: usize) -> Option<Vec<i64>> {
+    let mut residual = Vec::with_capacity(samples.len() - order);
+    for i in order..samples.len() {
+        let s = |back: usize| i64::from(samples[i - back]);
+        residual.push(match order {
+            0 => s(0),
+            1 => s(0) - s(1),
+            2 => s(0) - (2 * s(1)) + s(2),
+            3 => s(0) - (3 * s(1)) + (3 * s(2)) - s(3),
+            _ => s(0) - (4 * s(1)) + (6 * s(2)) - (4 * s(3)) + s(4),
+        });
+    }
+    check_residual(residual)
+}
+
+/// Computes the residual of a linear predictor, if it fits in 32 bits
+fn lpc_residual(samples: &[i32], coefficients: &[i32], shift: u32) -> Option<Vec<i64>> {
+    let order = coefficients.len();
+    let mut residual = Vec::with_capacity(samples.len() - order);
+    for i in order..samples.len() {
+        let mut prediction = 0i64;
+        for (j, coefficient) in coefficients.iter().enumerate() {
+            prediction += i64::from(*coefficient) * i64::from(samples[i - j - 1]);
+        }
+        residual.push(i64::from(samples[i]) - (prediction >> shift));
+    }
+    check_residual(residual)
+}
+
+/// Residuals have to fit in 32 bits signed integers
+fn check_residual(residual: Vec<i64>) -> Option<Vec<i64>> {
+    let limit = i64::from(i32::max_value());
+    if residual.iter().all(|&r| (r <= limit) & (r > -limit)) {
+        Some(residual)
+    } else {
+        None
+    }
+}
+
+/// Computes quantized LPC coefficients and their shift for every order that can be used
+fn lpc_coefficients(samples: &[i32]) -> Vec<(Vec<i32>, u32)> {
+    let max_order = MAX_LPC_ORDER.min(samples.len() - 1);
+    let mut candidates = Vec::with_capacity(max_order);
+    if max_order == 0 {
+        return candidates;
+    }
+    // Welch window to reduce the effect of the block edges
+    let len = samples.len() as f64;
+    let windowed: Vec<f64> = samples
+        .iter()
+        .enumerate()
+        .map(|(i, &s)| {
+            let x = ((2f64 * i as f64) / (len - 1f64)) - 1f64;
+            f64::from(s) * (1f64 - (x * x))
+        })
+        .collect();
+    let mut autocorrelation = vec![0f64; max_order + 1];
+    for (lag, value) in autocorrelation.iter_mut().enumerate() {
+        *value = windowed[lag..]
+            .iter()
+            .zip(windowed.iter())
+            .map(|(a, b)| a * b)
+            .sum();
+    }
+    if autocorrelation[0] <= 0f64 {
+        return candidates;
+    }
+    // Levinson-Durbin recursion, giving the coefficients of every order on the way
+    let mut lpc = vec![0f64; max_order];
+    let mut error = autocorrelation[0];
+    for order in 0..max_order {
+        let mut reflection = -autocorrelation[order + 1];
+        for j in 0..order {
+            reflection -= lpc[j] * autocorrelation[order - j];
+        }
+        reflection /= error;
+        let previous = lpc.clone();
+        lpc[order] = reflection;
+        for j in 0..order {
+            lpc[j] = previous[j] + (reflection * previous[order - 1 - j]);
+        }
+        error *= 1f64 - (reflection * reflection);
+        // Predictor is sum(-lpc[j] * s[i - j - 1])
+        let coefficients: Vec<f64> = lpc[..=order].iter().map(|c| -c).collect();
+        if let Some(quantized) = quantize_coefficients(&coefficients) {
+            candidates.push(quantized);
+        }
+        if error <= 0f64 {
+            break;
+        }
+    }
+    candidates
+}
+
+/// Quantizes LPC coefficients to LPC_PRECISION bits, also finding the shift to use
+fn quantize_coefficients(coefficients: &[f64]) -> Option<(Vec<i32>, u32)> {
+    let max = coefficients.iter().fold(0f64, |m, c| m.max(c.abs()));
+    if !max.is_finite() || max <= 0f64 {
+        return None;
+    }
+    let log2_max = max.log2().floor() as i32 + 1;
+    let shift = (LPC_PRECISION as i32 - 1 - log2_max).max(0).min(15) as u32;
+    let limit = f64::from(1 << (LPC_PRECISION - 1));
+    let mut quantized = Vec::with_capacity(coefficients.len());
+    let mut error = 0f64;
+    for coefficient in coefficients {
+        // Carry the rounding error over to the next coefficient
+        error += coefficient * f64::from(1 << shift);
+        let value = error.round().max(-limit).min(limit - 1f64);
+        error -= value;
+        quantized.push(value as i32);
+    }
+    Some((quantized, shift))
+}
+
+/// Finds the best partition order and Rice parameters for a residual.
+/// Returns the partition order, the parameter of each partition and the number of bits it takes.
+fn rice_partitions(residual: &[i64], block_size: usize, order: usize) -> (u32, Vec<u32>, u64) {
+    let folded: Vec<u64> = residual.iter().map(|&r| zigzag(r)).collect();
+    let mut best: Option<(u32, Vec<u32>, u64)> = None;
+    for partition_order in 0..=MAX_PARTITION_ORDER {
+        let nb_partitions = 1usize << partition_order;
+        if (block_size % nb_partitions != 0) | (block_size / nb_partitions <= order) {
+            break;
+        }
+        let partition_size = block_size / nb_partitions;
+        let mut parameters = Vec::with_capacity(nb_partitions);
+        // Residual coding method and partition order
+        let mut cost = 2 + 4;
+        let mut start = 0;
+        for partition in 0..nb_partitions {
+            let end = ((partition + 1) * partition_size) - order;
+            let (parameter, bits) = best_rice_parameter(&folded[start..end]);
+            parameters.push(parameter);
+            cost += 5 + bits;
+            start = end;
+        }
+        if best.as_ref().map_or(true, |b| cost < b.2) {
+            best = Some((partition_order, parameters, cost));
+        }
+    }
+    best.unwrap_or((0, vec![0], u64::max_value()))
+}
+
+/// Finds the Rice parameter that takes the least space for some folded values
+fn best_rice_parameter(folded: &[u64]) -> (u32, u64) {
+    if folded.is_empty() {
+        return (0, 0);
+    }
+    let mean = folded.iter().sum::<u64>() / folded.len() as u64;
+    let estimate = if mean > 0 {
+        63 - mean.leading_zeros()
+    } else {
+        0
+    };
+    let mut best = (0, u64::max_value());
+    for parameter in estimate.saturating_sub(1)..=(estimate + 1).min(30) {
+        let bits: u64 = folded
+            .iter()
+            .map(|&u| 1 + u64::from(parameter) + (u >> parameter))
+            .sum();
+        if bits < best.1 {
+            best = (parameter, bits);
+        }
+    }
+    best
+}
+
+/// Writes the Rice coded residual of a subframe
+fn write_residual(frame: &mut BitWriter, residual: &[i64], block_size: usize, order: usize) {
+    let (partition_order, parameters, _) = rice_partitions(residual, block_size, order);
+    // 4 bits parameters are enough unless one goes past 14, 15 being reserved for escaping
+    let parameter_bits = if parameters.iter().all(|&p| p < 15) {
+        frame.write(0, 2);
+        4
+    } else {
+        frame.write(1, 2);
+        5
+    };
+    frame.write(u64::from(partition_order), 4);
+    let partition_size = block_size >> partition_order;
+    let mut start = 0;
+    for (partition, parameter) in parameters.iter().enumerate() {
+        let end = ((partition + 1) * partition_size) - order;
+        frame.write(u64::from(*parameter), parameter_bits);
+        for value in &residual[start..end] {
+            let folded = zigzag(*value);
+            frame.write_unary(folded >> parameter);
+            frame.write(folded & ((1 << parameter) - 1), *parameter);
+        }
+        start = end;
+    }
+}
+
+/// Maps signed values to unsigned ones: 0, -1, 1, -2, 2...
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+/// CRC-8 with the polynomial x^8 + x^2 + x^1 + x^0
+fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for byte in bytes {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// CRC-16 with the polynomial x^16 + x^15 + x^2 + x^0
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for byte in bytes {
+        crc ^= u16::from(*byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+impl BitWriter {
+    fn new() -> BitWriter {
+        BitWriter {
+            bytes: Vec::new(),
+            accumulator: 0,
+            nb_bits: 0,
+        }
+    }
+    /// Writes the lowest bits of a value. No more than 32 bits can be written at once.
+    fn write(&mut self, value: u64, bits: u32) {
+        if bits == 0 {
+            return;
+        }
+        if bits > 32 {
+            self.write(value >> 32, bits - 32);
+            self.write(value & 0xFFFF_FFFF, 32);
+            return;
+        }
+        self.accumulator = (self.accumulator << bits) | (value & ((1 << bits) - 1));
+        self.nb_bits += bits;
+        while self.nb_bits >= 8 {
+            self.nb_bits -= 8;
+            self.bytes.push((self.accumulator >> self.nb_bits) as u8);
+        }
+        self.accumulator &= (1 << self.nb_bits) - 1;
+    }
+    /// Writes a two's complement signed value
+    fn write_signed(&mut self, value: i64, bits: u32) {
+        self.write(value as u64, bits);
+    }
+    /// Writes a value as that many zeros followed by a one
+    fn write_unary(&mut self, value: u64) {
+        let mut zeros = value;
+        while zeros >= 32 {
+            self.write(0, 32);
+            zeros -= 32;
+        }
+        self.write(1, zeros as u32 + 1);
+    }
+    /// Writes a number with the same variable length coding as UTF-8
+    fn write_utf8(&mut self, value: u64) {
+        if value < 0x80 {
+            self.write(value, 8);
+            return;
+        }
+        let nb_bytes = match value {
+            0..=0x7FF => 2,
+            0x800..=0xFFFF => 3,
+            0x1_0000..=0x1F_FFFF => 4,
+            0x20_0000..=0x3FF_FFFF => 5,
+            0x400_0000..=0x7FFF_FFFF => 6,
+            _ => 7,
+        };
+        let first_byte = (0xFF00u64 >> nb_bytes) & 0xFF;
+        self.write(first_byte | (value >> (6 * (nb_bytes - 1))), 8);
+        for byte in (0..nb_bytes - 1).rev() {
+            self.write(0x80 | ((value >> (6 * byte)) & 0x3F), 8);
+        }
+    }
+    /// Pads with zeros up to the next byte
+    fn align(&mut self) {
+        if self.nb_bits > 0 {
+            let padding = 8 - self.nb_bits;
+            self.write(0, padding);
+        }
+    }
+    fn into_bytes(mut self) -> Vec<u8> {
+        self.align();
+        self.bytes
+    }
+}
+
+/// Per-round shift amounts of MD5
+const MD5_SHIFTS: [u32; 64] = [
+    7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 5, 9, 14, 20, 5, 9, 14, 20, 5, 9,
+    14, 20, 5, 9, 14, 20, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 6, 10, 15,
+    21, 6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21,
+];
+
+impl Md5 {
+    fn new() -> Md5 {
+        Md5 {
+            state: [0x6745_2301, 0xEFCD_AB89, 0x98BA_DCFE, 0x1032_5476],
+            buffer: [0u8; 64],
+            length: 0,
+        }
+    }
+    fn update(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.buffer[(self.length % 64) as usize] = *byte;
+            self.length += 1;
+            if self.length % 64 == 0 {
+                let block = self.buffer;
+                self.process(&block);
+            }
+        }
+    }
+    fn finish(mut self) -> [u8; 16] {
+        let bit_length = self.length.wrapping_mul(8);
+        self.update(&[0x80]);
+        while self.length % 64 != 56 {
+            self.update(&[0]);
+        }
+        self.update(&bit_length.to_le_bytes());
+        let mut hash = [0u8; 16];
+        for (i, word) in self.state.iter().enumerate() {
+            hash[i * 4..(i + 1) * 4].copy_from_slice(&word.to_le_bytes());
+        }
+        hash
+    }
+    /// Processes a single 64 bytes block
+    fn process(&mut self, block: &[u8]) {
+        let mut words = [0u32; 16];
+        for (i, word) in words.iter_mut().enumerate() {
+            *word = u32::from_le_bytes([
+                block[i * 4],
+                block[i * 4 + 1],
+                block[i * 4 + 2],
+                block[i * 4 + 3],
+            ]);
+        }
+        let [mut a, mut b, mut c, mut d] = self.state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            // The constants are the integer part of abs(sin(i + 1)) * 2^32
+            let k = ((i as f64 + 1f64).sin().abs() * 4_294_967_296f64) as u32;
+            let rotated = a
+                .wrapping_add(f)
+                .wrapping_add(k)
+                .wrapping_add(words[g])
+                .rotate_left(MD5_SHIFTS[i]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(rotated);
+        }
+        self.state[0] = self.state[0].wrapping_add(a);
+        self.state[1] = self.state[1].wrapping_add(b);
+        self.state[2] = self.state[2].wrapping_add(c);
+        self.state[3] = self.state[3].wrapping_add(d);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pcm::{PCMParameters, SpeakerLayout};
+
+    /// Reads values bit by bit, most significant bit first
+    struct BitReader<'a> {
+        bytes: &'a [u8],
+        position: usize,
+    }
+
+    impl<'a> BitReader<'a> {
+        fn read(&mut self, bits: u32) -> u64 {
+            let mut value = 0;
+            for _ in 0..bits {
+                let bit = (self.bytes[self.position / 8] >> (7 - (self.position % 8))) & 1;
+                value = (value << 1) | u64::from(bit);
+                self.position += 1;
+            }
+            value
+        }
+        fn read_signed(&mut self, bits: u32) -> i64 {
+            let value = self.read(bits);
+            ((value << (64 - bits)) as i64) >> (64 - bits)
+        }
+        fn read_unary(&mut self) -> u64 {
+            let mut zeros = 0;
+            while self.read(1) == 0 {
+                zeros += 1;
+            }
+            zeros
+        }
+        fn read_utf8(&mut self) -> u64 {
+            let first_byte = self.read(8);
+            let nb_bytes = (!(first_byte as u8)).leading_zeros();
+            if nb_bytes == 0 {
+                return first_byte;
+            }
+            let mut value = first_byte & (0x7F >> nb_bytes);
+            for _ in 1..nb_bytes {
+                value = (value << 6) | (self.read(8) & 0x3F);
+            }
+            value
+        }
+        fn align(&mut self) {
+            self.position = self.position.div_ceil(8) * 8;
+        }
+    }
+
+    /// What a test stream decoded to
+    struct Decoded {
+        /// Interleaved samples
+        samples: Vec<i32>,
+        nb_fixed: usize,
+        nb_lpc: usize,
+    }
+
+    /// Decodes a stream written by Flac::write, checking the CRCs of every frame and the MD5 of the whole stream
+    fn decode(bytes: &[u8]) -> Decoded {
+        assert_eq!(&bytes[..4], b"fLaC");
+        let mut reader = BitReader {
+            bytes,
+            position: 32,
+        };
+        assert_eq!(reader.read(1), 1); // Last Metadata Block
+        assert_eq!(reader.read(7), 0); // STREAMINFO
+        assert_eq!(reader.read(24), 34);
+        reader.read(16 + 16 + 24 + 24 + 20);
+        let nb_channels = reader.read(3) as usize + 1;
+        let bits_per_sample = reader.read(5) as u32 + 1;
+        let nb_frames = reader.read(36) as usize;
+        let md5 = &bytes[reader.position / 8..reader.position / 8 + 16];
+        reader.position += 128;
+        let mut decoded = Decoded {
+            samples: Vec::new(),
+            nb_fixed: 0,
+            nb_lpc: 0,
+        };
+        let mut frame_nb = 0;
+        while reader.position / 8 < bytes.len() {
+            let frame_start = reader.position / 8;
+            assert_eq!(reader.read(14), 0b11_1111_1111_1110);
+            reader.read(2);
+            let block_size_code = reader.read(4);
+            reader.read(4);
+            assert_eq!(reader.read(4) as usize, nb_channels - 1);
+            reader.read(4);
+            assert_eq!(reader.read_utf8(), frame_nb);
+            let block_size = match block_size_code {
+                0b1100 => BLOCK_SIZE,
+                _ => reader.read(16) as usize + 1,
+            };
+            let crc = crc8(&bytes[frame_start..reader.position / 8]);
+            assert_eq!(reader.read(8) as u8, crc);
+            let channels: Vec<Vec<i32>> = (0..nb_channels)
+                .map(|_| decode_subframe(&mut reader, &mut decoded, block_size, bits_per_sample))
+                .collect();
+            reader.align();
+            let crc = crc16(&bytes[frame_start..reader.position / 8]);
+            assert_eq!(reader.read(16) as u16, crc);
+            for sample_nb in 0..block_size {
+                for channel in &channels {
+                    decoded.samples.push(channel[sample_nb]);
+                }
+            }
+            frame_nb += 1;
+        }
+        assert_eq!(decoded.samples.len(), nb_frames * nb_channels);
+        let mut hash = Md5::new();
+        for sample in &decoded.samples {
+            hash.update(&sample.to_le_bytes()[..(bits_per_sample / 8) as usize]);
+        }
+        assert_eq!(&hash.finish()[..], md5);
+        decoded
+    }
+
+    fn decode_subframe(
+        reader: &mut BitReader,
+        decoded: &mut Decoded,
+        block_size: usize,
+        bits_per_sample: u32,
+    ) -> Vec<i32> {
+        assert_eq!(reader.read(1), 0);
+        let subframe_type = reader.read(6);
+        assert_eq!(reader.read(1), 0); // No wasted bits
+        if subframe_type == 0 {
+            return vec![reader.read_signed(bits_per_sample) as i32; block_size];
+        }
+        if subframe_type == 1 {
+            return (0..block_size)
+                .map(|_| reader.read_signed(bits_per_sample) as i32)
+                .collect();
+        }
+        let lpc = subframe_type & 0b10_0000 != 0;
+        let order = if lpc {
+            decoded.nb_lpc += 1;
+            (subframe_type & 0b1_1111) as usize + 1
+        } else {
+            decoded.nb_fixed += 1;
+            (subframe_type & 0b111) as usize
+        };
+        let mut samples: Vec<i64> = (0..order)
+            .map(|_| reader.read_signed(bits_per_sample))
+            .collect();
+        let (coefficients, shift) = if lpc {
+            let precision = reader.read(4) as u32 + 1;
+            let shift = reader.read(5);
+            let coefficients: Vec<i64> =
+                (0..order).map(|_| reader.read_signed(precision)).collect();
+            (coefficients, shift)
+        } else {
+            let fixed = [
+                vec![],
+                vec![1],
+                vec![2, -1],
+                vec![3, -3, 1],
+                vec![4, -6, 4, -1],
+            ];
+            (fixed[order].clone(), 0)
+        };
+        let parameter_bits = if reader.read(2) == 0 { 4 } else { 5 };
+        let partition_order = reader.read(4);
+        let partition_size = block_size >> partition_order;
+        for partition in 0..1 << partition_order {
+            let parameter = reader.read(parameter_bits) as u32;
+            let nb_values = if partition == 0 {
+                partition_size - order
+            } else {
+                partition_size
+            };
+            for _ in 0..nb_values {
+                let folded = (reader.read_unary() << parameter) | reader.read(parameter);
+                let residual = (folded >> 1) as i64 ^ -((folded & 1) as i64);
+                let i = samples.len();
+                let prediction: i64 = coefficients
+                    .iter()
+                    .enumerate()
+                    .map(|(j, coefficient)| coefficient * samples[i - j - 1])
+                    .sum();
+                samples.push(residual + (prediction >> shift));
+            }
+        }
+        samples.iter().map(|&sample| sample as i32).collect()
+    }
+
+    #[test]
+    fn md5() {
+        // Test suite of RFC 1321
+        let suite = [
+            ("", "d41d8cd98f00b204e9800998ecf8427e"),
+            ("a", "0cc175b9c0f1b6a831c399e269772661"),
+            ("abc", "900150983cd24fb0d6963f7d28e17f72"),
+            ("message digest", "f96b697d7cb7938d525a2f31aaf161d0"),
+            (
+                "abcdefghijklmnopqrstuvwxyz",
+                "c3fcd3d76192e4007dfb496cca67e13b",
+            ),
+            (
+                "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789",
+                "d174ab98d277d9f5a5611c2c9f419d9f",
+            ),
+            (
+                "12345678901234567890123456789012345678901234567890123456789012345678901234567890",
+                "57edf4a22be3c955ac49da2e2107b67a",
+            ),
+        ];
+        for &(message, digest) in &suite {
+            let mut hash = Md5::new();
+            hash.update(message.as_bytes());
+            let hex: String = hash
+                .finish()
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect();
+            assert_eq!(hex, digest);
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        // A smooth ramp suits the fixed predictors, a high pitched tone needs the linear prediction
+        let nb_frames = BLOCK_SIZE + 904;
+        let mut expected = Vec::with_capacity(nb_frames * 2);
+        for i in 0..nb_frames {
+            let t = i as f64;
+            expected.push(((t * 3f64) % 60_000f64 - 30_000f64) as i32);
+            expected.push(
+                ((t * 2.3).sin() * 12_000f64 + (t * 1.9).sin() * 8_000f64 + f64::from(i as i32 % 7))
+                    .round() as i32,
+            );
+        }
+        let max_value = SampleType::Signed16.get_max_value();
+        let flac = Flac {
+            pcm: PCM {
+                parameters: PCMParameters {
+                    sample_rate: 44100,
+                    nb_channels: 2,
+                    speaker_layout: SpeakerLayout::Unspecified,
+                },
+                loop_info: Vec::new(),
+                samples: expected
+                    .iter()
+                    .map(|&sample| f64::from(sample) / max_value)
+                    .collect(),
+            },
+            sample_type: SampleType::Signed16,
+            gain: Gain::HardClip(1f64),
+            dither: Dither::None,
+        };
+        let mut bytes = Vec::new();
+        let report = flac.write(&mut bytes).unwrap();
+        assert_eq!(report.nb_clipped, 0);
+        let decoded = decode(&bytes);
+        assert!(decoded.nb_fixed > 0);
+        assert!(decoded.nb_lpc > 0);
+        assert!(decoded.samples == expected);
+    }
+}
@@ -28,6 +28,9 @@ impl FrequencyLookup for Vec<Frequency> {
     }
 }
 
+/// How far in cents a Frequency can be from a MIDI key and still be considered as that key
+pub const MIDI_KEY_TOLERANCE: f64 = 5f64;
+
 /// Example implementation of FrequencyLookup for MIDI
 #[derive(Clone, Copy)]
 pub struct MIDIFrequencyLookup {}
@@ -39,3 +42,19 @@ impl FrequencyLookup for MIDIFrequencyLookup {
         )?) // Lossy
     }
 }
+
+impl MIDIFrequencyLookup {
+    /// Does the opposite of the lookup, finds the MIDI key of a Frequency if there is one within MIDI_KEY_TOLERANCE
+    pub fn get_key(frequency: Frequency) -> Option<usize> {
+        let key = 69f64 + 12f64 * (frequency.get() / 440f64).log2();
+        let rounded = key.round();
+        if ((key - rounded).abs() * 100f64 <= MIDI_KEY_TOLERANCE)
+            & (rounded >= 0f64)
+            & (rounded <= 127f64)
+        {
+            Some(rounded as usize)
+        } else {
+            None
+        }
+    }
+}
@@ -1,5 +1,7 @@
+use error::NoTempoMapError;
+use midi::TempoMap;
 use sequence::Note;
-use sequence::Sequence;
+use sequence::{GainPoint, Marker, PitchPoint, Sequence};
 use std::collections::HashMap;
 use std::f64::EPSILON;
 use util::{Duration, Frequency, Time, TimeSpan, Volume};
@@ -10,6 +12,14 @@ use Result;
 pub struct PartialNote {
     pub start_at: Time,
     pub vol: Vec<Volume>,
+    /// The channel that started the note, if it was started with start_channel_note
+    pub channel: Option<usize>,
+    /// Changes of pitch so far, relative to start_at
+    pub pitch: Vec<PitchPoint>,
+    /// How hard the note was played, if it was started with start_channel_note
+    pub velocity: Option<Volume>,
+    /// Changes of loudness so far, relative to start_at
+    pub gain: Vec<GainPoint>,
 }
 
 /// Helps the user to build a Sequence usable by the Synthesizer
@@ -21,6 +31,27 @@ pub struct SequenceHelper {
     pub sequence: Sequence,
     /// Where we are at in the sequence. Use functions to modify this and do not forget to reset it when changing tracks !
     pub at_time: f64,
+    /// Used to move in ticks instead of seconds
+    pub tempo_map: Option<TempoMap>,
+    /// Where we are at in ticks, relative to when the Tempo Map was set
+    pub at_tick: u64,
+    /// Controller values of every channel that changed them, used by the channel note functions
+    pub controllers: HashMap<usize, ChannelControllers>,
+    /// Channels with their sustain pedal held, with the notes waiting for it to lift as (f_id, i_id). Use set_sustain to modify this.
+    pub sustained: HashMap<usize, Vec<(usize, usize)>>,
+}
+
+/// Controller values of a channel, applied to the notes it starts
+#[derive(Clone, Copy)]
+pub struct ChannelControllers {
+    /// Main volume of the channel in [0; 1] (MIDI CC7). Use set_volume to modify this.
+    pub volume: f64,
+    /// Second volume in [0; 1], usually used for crescendos (MIDI CC11). Use set_expression to modify this.
+    pub expression: f64,
+    /// Position of the channel from -1 (left) to 1 (right), only used for stereo (MIDI CC10). Notes keep the pan they started with.
+    pub pan: f64,
+    /// Pitch offset in cents of the notes of the channel. Use set_pitch_bend to modify this.
+    pub pitch_bend: f64,
 }
 
 /// Builds a Frequency Lookup Table if nothing else can be used.
@@ -37,6 +68,10 @@ impl SequenceHelper {
             current_notes: HashMap::new(),
             sequence: Sequence::new(),
             at_time: 0f64,
+            tempo_map: None,
+            at_tick: 0,
+            controllers: HashMap::new(),
+            sustained: HashMap::new(),
         }
     }
 
@@ -45,10 +80,188 @@ impl SequenceHelper {
         self.at_time += time_passed;
     }
 
+    /// Uses a Tempo Map to move in ticks from now on. Tick 0 of the map is the current time.
+    pub fn set_tempo_map(&mut self, tempo_map: TempoMap) {
+        self.tempo_map = Some(tempo_map);
+        self.at_tick = 0;
+    }
+
+    /// Moves time forward in the sequence by a number of ticks, following the tempo changes of the Tempo Map
+    pub fn tick_forward(&mut self, ticks_passed: u64) -> Result<()> {
+        let tempo_map = self.tempo_map.as_ref().ok_or(NoTempoMapError {})?;
+        let new_tick = self.at_tick + ticks_passed;
+        self.at_time += tempo_map.get_seconds(new_tick) - tempo_map.get_seconds(self.at_tick);
+        self.at_tick = new_tick;
+        Ok(())
+    }
+
     /// Resets the time to 0 and currently running notes. Useful when changing tracks.
     pub fn reset(&mut self) {
         self.at_time = 0f64;
+        self.at_tick = 0;
         self.current_notes = HashMap::new();
+        self.controllers = HashMap::new();
+        self.sustained = HashMap::new();
+    }
+
+    /// Returns the controller values of a channel to read or modify them
+    pub fn get_controllers(&mut self, channel: usize) -> &mut ChannelControllers {
+        self.controllers
+            .entry(channel)
+            .or_insert_with(ChannelControllers::default)
+    }
+
+    /// Holds or lifts the sustain pedal of a channel. Lifting it stops the notes that were released while it was held.
+    /// # Arguments
+    /// * channel - The channel of the pedal
+    /// * held - If the pedal is down
+    pub fn set_sustain(&mut self, channel: usize, held: bool) -> Result<()> {
+        if held {
+            self.sustained.entry(channel).or_insert_with(Vec::new);
+        } else if let Some(notes) = self.sustained.remove(&channel) {
+            for (f_id, i_id) in notes {
+                self.stop_note(f_id, i_id)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts a note on a channel, its volumes come from the velocity and the pan of the channel.
+    /// With 2 channels of output, the pan of the channel splits the volume between left and right.
+    /// The volume and expression of the channel are applied as changes of loudness, so that they can change while the note plays.
+    /// # Arguments
+    /// * f_id - The Frequency ID
+    /// * i_id - The Instrument ID
+    /// * channel - The channel playing the note
+    /// * velocity - How hard the note is played
+    /// * nb_channels - The number of channels of the final output
+    pub fn start_channel_note(
+        &mut self,
+        f_id: usize,
+        i_id: usize,
+        channel: usize,
+        velocity: Volume,
+        nb_channels: usize,
+    ) -> Result<()> {
+        // A note played again while sustained starts over
+        if let Some(position) = self.sustained.get(&channel).and_then(|notes| {
+            notes
+                .iter()
+                .position(|&(s_f_id, s_i_id)| (s_f_id == f_id) & (s_i_id == i_id))
+        }) {
+            if let Some(notes) = self.sustained.get_mut(&channel) {
+                notes.remove(position);
+            }
+            self.stop_note(f_id, i_id)?;
+        }
+        let controllers = self.controllers.get(&channel).cloned().unwrap_or_default();
+        let gain = velocity.get();
+        let vol = if nb_channels == 2 {
+            vec![
+                Volume::new(gain * (1f64 - controllers.pan).min(1f64))?,
+                Volume::new(gain * (1f64 + controllers.pan).min(1f64))?,
+            ]
+        } else {
+            vec![Volume::new(gain)?; nb_channels]
+        };
+        let mut pitch = Vec::new();
+        if controllers.pitch_bend != 0f64 {
+            pitch.push(PitchPoint {
+                at: Time::new(0f64)?,
+                cents: controllers.pitch_bend,
+            });
+        }
+        self.start_partial_note(
+            f_id,
+            i_id,
+            PartialNote {
+                start_at: Time::new(self.at_time)?,
+                vol,
+                channel: Some(channel),
+                pitch,
+                velocity: Some(velocity),
+                gain: vec![GainPoint {
+                    at: Time::new(0f64)?,
+                    factor: controllers.volume * controllers.expression,
+                }],
+            },
+        );
+        Ok(())
+    }
+
+    /// Changes the main volume of a channel (MIDI CC7), for its playing notes and the ones it will start
+    /// # Arguments
+    /// * channel - The channel to change
+    /// * volume - The new volume, in [0; 1]
+    pub fn set_volume(&mut self, channel: usize, volume: f64) -> Result<()> {
+        self.get_controllers(channel).volume = volume;
+        self.update_gain(channel)
+    }
+
+    /// Changes the expression of a channel (MIDI CC11), for its playing notes and the ones it will start
+    /// # Arguments
+    /// * channel - The channel to change
+    /// * expression - The new expression, in [0; 1]
+    pub fn set_expression(&mut self, channel: usize, expression: f64) -> Result<()> {
+        self.get_controllers(channel).expression = expression;
+        self.update_gain(channel)
+    }
+
+    /// Adds a change of loudness to the playing notes of a channel, following its volume and expression
+    fn update_gain(&mut self, channel: usize) -> Result<()> {
+        let controllers = *self.get_controllers(channel);
+        let factor = controllers.volume * controllers.expression;
+        for inst_map in self.current_notes.values_mut() {
+            for partial_note in inst_map.values_mut() {
+                if partial_note.channel != Some(channel) {
+                    continue;
+                }
+                let at = Time::new(self.at_time - partial_note.start_at.get())?;
+                match partial_note.gain.last_mut() {
+                    // Only keep the last change that happened at a same time
+                    Some(ref mut last) if last.at.get() >= at.get() => last.factor = factor,
+                    _ => partial_note.gain.push(GainPoint { at, factor }),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Bends the pitch of a channel, changing the pitch of its playing notes and the ones it will start
+    /// # Arguments
+    /// * channel - The bent channel
+    /// * cents - The offset from the normal pitch of the notes
+    pub fn set_pitch_bend(&mut self, channel: usize, cents: f64) -> Result<()> {
+        self.get_controllers(channel).pitch_bend = cents;
+        for inst_map in self.current_notes.values_mut() {
+            for partial_note in inst_map.values_mut() {
+                if partial_note.channel != Some(channel) {
+                    continue;
+                }
+                let at = Time::new(self.at_time - partial_note.start_at.get())?;
+                match partial_note.pitch.last_mut() {
+                    // Only keep the last change that happened at a same time
+                    Some(ref mut last) if last.at.get() >= at.get() => last.cents = cents,
+                    _ => partial_note.pitch.push(PitchPoint { at, cents }),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Releases a note started on a channel. It keeps playing until the sustain pedal lifts if it is held.
+    /// # Arguments
+    /// * f_id - The Frequency ID
+    /// * i_id - The Instrument ID
+    /// * channel - The channel playing the note
+    pub fn stop_channel_note(&mut self, f_id: usize, i_id: usize, channel: usize) -> Result<()> {
+        match self.sustained.get_mut(&channel) {
+            Some(notes) => {
+                notes.push((f_id, i_id));
+                Ok(())
+            }
+            None => self.stop_note(f_id, i_id),
+        }
     }
 
     /// Starts a note that will finish later.
@@ -57,20 +270,27 @@ impl SequenceHelper {
     /// * i_id - The Instrument ID
     /// * vol - The volumes of the note for each channel
     pub fn start_note(&mut self, f_id: usize, i_id: usize, vol: Vec<Volume>) -> Result<()> {
+        let partial_note = PartialNote {
+            start_at: Time::new(self.at_time)?,
+            vol,
+            channel: None,
+            pitch: Vec::new(),
+            velocity: None,
+            gain: Vec::new(),
+        };
+        self.start_partial_note(f_id, i_id, partial_note);
+        Ok(())
+    }
+
+    /// Registers a note as playing
+    fn start_partial_note(&mut self, f_id: usize, i_id: usize, partial_note: PartialNote) {
         let instrument_map = self.current_notes.entry(i_id).or_insert_with(HashMap::new);
         match instrument_map.get(&f_id) {
             None => {
-                instrument_map.insert(
-                    f_id,
-                    PartialNote {
-                        start_at: Time::new(self.at_time)?,
-                        vol,
-                    },
-                );
+                instrument_map.insert(f_id, partial_note);
             }
             Some(_) => {} // Ignore if there is already a Note running at the same frequency
         }
-        Ok(())
     }
 
     /// Stops a note started earlier, finalizes it and adds it to the Sequence
@@ -88,6 +308,9 @@ impl SequenceHelper {
                         vol: partial_note.vol.clone(),
                         f_id,
                         i_id,
+                        pitch: partial_note.pitch.clone(),
+                        gain: partial_note.gain.clone(),
+                        velocity: partial_note.velocity,
                     });
                 };
                 to_remove = true;
@@ -99,6 +322,32 @@ impl SequenceHelper {
         Ok(())
     }
 
+    /// Stops every note still playing, as if they were all released now, including the sustained ones
+    pub fn stop_all_notes(&mut self) -> Result<()> {
+        self.sustained = HashMap::new();
+        let mut playing = Vec::new();
+        for (i_id, inst_map) in &self.current_notes {
+            for f_id in inst_map.keys() {
+                playing.push((*f_id, *i_id));
+            }
+        }
+        for (f_id, i_id) in playing {
+            self.stop_note(f_id, i_id)?;
+        }
+        Ok(())
+    }
+
+    /// Places a marker at the current time in the Sequence
+    /// # Arguments
+    /// * label - Text describing the marker
+    pub fn add_marker(&mut self, label: String) -> Result<()> {
+        self.sequence.markers.push(Marker {
+            at: Time::new(self.at_time)?,
+            label,
+        });
+        Ok(())
+    }
+
     /// Directly adds a new note to the Sequence.
     /// # Arguments
     /// * f_id - The Frequency ID
@@ -117,11 +366,26 @@ impl SequenceHelper {
             vol,
             f_id,
             i_id,
+            pitch: Vec::new(),
+            gain: Vec::new(),
+            velocity: None,
         });
         Ok(())
     }
 }
 
+impl Default for ChannelControllers {
+    /// Values of a channel before it receives any controller change, as in General MIDI
+    fn default() -> ChannelControllers {
+        ChannelControllers {
+            volume: 100f64 / 127f64,
+            expression: 1f64,
+            pan: 0f64,
+            pitch_bend: 0f64,
+        }
+    }
+}
+
 impl FrequencyLookupTableBuilder {
     /// Creates a new builder
     pub fn new() -> FrequencyLookupTableBuilder {
@@ -144,3 +408,32 @@ impl FrequencyLookupTableBuilder {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expression_over_held_note() {
+        let mut helper = SequenceHelper::new();
+        helper.set_volume(0, 1f64).unwrap();
+        helper
+            .start_channel_note(60, 0, 0, Volume::new(0.5).unwrap(), 1)
+            .unwrap();
+        helper.time_forward(1f64);
+        helper.set_expression(0, 0.25).unwrap();
+        // Other channels are left alone
+        helper.set_expression(1, 0f64).unwrap();
+        helper.time_forward(1f64);
+        helper.stop_channel_note(60, 0, 0).unwrap();
+        let note = &helper.sequence.notes[0];
+        assert_eq!(note.vol[0].get(), 0.5);
+        assert_eq!(note.velocity.map(|velocity| velocity.get()), Some(0.5));
+        let gain: Vec<(f64, f64)> = note
+            .gain
+            .iter()
+            .map(|point| (point.at.get(), point.factor))
+            .collect();
+        assert_eq!(gain, vec![(0f64, 1f64), (1f64, 0.25)]);
+    }
+}
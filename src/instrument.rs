@@ -1,7 +1,9 @@
 use error::NoKeyInInstrumentError;
 use frequency_lookup::FrequencyLookup;
 use key_generator::KeyGenerator;
-use pcm::PCM;
+use pcm::{PCMParameters, SpeakerLayout, PCM};
+use sequence::PitchPoint;
+use std::cmp::Ordering;
 use std::collections::HashMap;
 use util::{Duration, Frequency};
 use Result;
@@ -13,7 +15,14 @@ pub struct Instrument {
     /// The key generators that creates new keys when needed
     pub key_gen: Box<KeyGenerator>,
     /// When we reach the end of the sound sample when playing this instrument, should we loop back to the beginning or just stop here ?
+    /// Keys with loop points always go back to the start of their loop instead.
     pub loopable: bool,
+    /// Parts of the instrument with their own sound, chosen by Frequency ID and velocity. When there are some, they are used instead of the keys and key generator above.
+    pub zones: Vec<Zone>,
+    /// Range of velocities around the edges of the zones where neighbouring velocity layers are mixed, 0 to never mix them
+    pub velocity_crossfade: f64,
+    /// How the loudness of the notes changes over time, unless their zone has its own
+    pub envelope: Envelope,
 }
 
 /// Key of an Instrument. Think of it as an Instrument having multiple physical keys to press, and everyone of them produces a different sound from each other.
@@ -25,7 +34,107 @@ pub struct Key {
     pub frequency: Frequency,
 }
 
+/// Part of an Instrument playing its own sound for a range of Frequency IDs and velocities
+pub struct Zone {
+    /// Lowest Frequency ID played by the zone
+    pub lowest_f_id: usize,
+    /// Highest Frequency ID played by the zone
+    pub highest_f_id: usize,
+    /// Lowest velocity played by the zone, in [0; 1]
+    pub lowest_velocity: f64,
+    /// Highest velocity played by the zone, in [0; 1]
+    pub highest_velocity: f64,
+    /// Creates the keys of the zone, usually a SampleKeyGenerator with the sample of the zone and its root frequency
+    pub key_gen: Box<KeyGenerator>,
+    /// Keys of the zone. Index is the frequency ID defined by the Frequency Lookup.
+    pub keys: HashMap<usize, Key>,
+    /// How the loudness of the notes of the zone changes over time, the envelope of the Instrument if none
+    pub envelope: Option<Envelope>,
+}
+
+/// Loudness of a note over time: silent during the delay, rising during the attack, held, falling to the sustain level during the decay, then falling to silence during the release once the note ends
+#[derive(Clone, Copy)]
+pub struct Envelope {
+    /// Seconds of silence before the attack
+    pub delay: f64,
+    /// Seconds to go from silence to full volume
+    pub attack: f64,
+    /// Seconds spent at full volume after the attack
+    pub hold: f64,
+    /// Seconds to go from full volume to the sustain level
+    pub decay: f64,
+    /// Level kept until the end of the note, in [0; 1]
+    pub sustain: f64,
+    /// Seconds to go to silence once the note ends. The sound rings on after the end of the TimeSpan of the note.
+    pub release: f64,
+    /// Shape of the changes of level
+    pub curve: EnvelopeCurve,
+}
+
+/// How an Envelope goes from one level to another
+#[derive(Clone, Copy, PartialEq)]
+pub enum EnvelopeCurve {
+    /// Every stage is a straight line
+    Linear,
+    /// The decay and release lose the same number of decibels every second, the attack stays a straight line
+    Exponential,
+}
+
+/// Level considered silent by exponential envelopes, -60 dB. They cannot reach 0 and go quiet from there instead.
+const ENVELOPE_SILENCE: f64 = 0.001;
+
+/// Release of the default Envelope, in seconds. About the 100 samples at 44.1 kHz over which notes used to be faded out to avoid popping sounds.
+pub const DEFAULT_RELEASE: f64 = 0.002;
+
+/// Key generator of the instruments made of zones, that never use it
+struct NoKeyGenerator {}
+
+impl KeyGenerator for NoKeyGenerator {
+    fn gen(&mut self, sample_rate: u32, frequency: Frequency, _duration: Duration) -> Key {
+        Key {
+            audio: PCM {
+                parameters: PCMParameters {
+                    sample_rate,
+                    nb_channels: 1,
+                    speaker_layout: SpeakerLayout::Unspecified,
+                },
+                loop_info: Vec::new(),
+                samples: Vec::new(),
+            },
+            frequency,
+        }
+    }
+}
+
 impl Instrument {
+    /// Creates an instrument playing the keys of a key generator, with the default Envelope
+    /// # Arguments
+    /// * key_gen - Creates the keys when needed
+    /// * loopable - Whether the keys without loop points go back to their beginning once they reach their end
+    pub fn new(key_gen: Box<KeyGenerator>, loopable: bool) -> Instrument {
+        Instrument {
+            keys: HashMap::new(),
+            key_gen,
+            loopable,
+            zones: Vec::new(),
+            velocity_crossfade: 0f64,
+            envelope: Envelope::default(),
+        }
+    }
+    /// Creates an instrument playing its zones
+    /// # Arguments
+    /// * zones - The zones, the nearest one gets pitch-shifted for Frequency IDs that none of them covers
+    /// * velocity_crossfade - Range of velocities where neighbouring velocity layers are mixed, 0 to never mix them
+    pub fn from_zones(zones: Vec<Zone>, velocity_crossfade: f64) -> Instrument {
+        Instrument {
+            keys: HashMap::new(),
+            key_gen: Box::new(NoKeyGenerator {}),
+            loopable: false,
+            zones,
+            velocity_crossfade,
+            envelope: Envelope::default(),
+        }
+    }
     /// Generates keys provided as arguments
     pub fn gen_keys(
         &mut self,
@@ -35,27 +144,263 @@ impl Instrument {
     ) -> Result<()> {
         for (f_id, duration) in f_id_duration {
             let freq = f_lu.get_freq(*f_id)?;
-            self.keys
-                .insert(*f_id, self.key_gen.gen(sample_rate, freq, *duration));
+            // Keys also play during the release
+            if self.zones.is_empty() {
+                let duration = Duration::new(duration.get() + self.envelope.release)?;
+                self.keys
+                    .insert(*f_id, self.key_gen.gen(sample_rate, freq, duration));
+            } else {
+                // Every velocity layer may be needed
+                for index in self.get_nearest_zones(*f_id) {
+                    let zone = &mut self.zones[index];
+                    let release = zone.envelope.unwrap_or(self.envelope).release;
+                    let duration = Duration::new(duration.get() + release)?;
+                    let key = zone.key_gen.gen(sample_rate, freq, duration);
+                    zone.keys.insert(*f_id, key);
+                }
+            }
         }
         Ok(())
     }
     /// "Plays" the instrument and returns a sound with the provided parameters
-    pub fn gen_sound(&self, f_id: usize, duration: Duration) -> Result<PCM> {
-        let key = self
-            .keys
-            .get(&f_id)
-            .ok_or(NoKeyInInstrumentError { f_id })?;
-        let nb_samples = (duration.get() * f64::from(key.audio.parameters.sample_rate)) as usize;
+    /// # Arguments
+    /// * f_id - The Frequency ID of the Key to play
+    /// * duration - How long the sound is
+    /// * pitch - Changes of pitch during the sound, the Key gets resampled to follow them
+    /// * velocity - How hard the note is played in [0; 1], used to choose between the zones
+    /// The Key plays its first loop once it reaches the end of it, if it has one.
+    /// The sound follows the Envelope, and lasts longer than the duration by its release.
+    pub fn gen_sound(
+        &self,
+        f_id: usize,
+        duration: Duration,
+        pitch: &[PitchPoint],
+        velocity: f64,
+    ) -> Result<PCM> {
+        if self.zones.is_empty() {
+            let key = self
+                .keys
+                .get(&f_id)
+                .ok_or(NoKeyInInstrumentError { f_id })?;
+            return Ok(self.play_key(key, duration, pitch, &self.envelope));
+        }
+        let mut sound: Option<PCM> = None;
+        for (index, weight) in self.get_zone_weights(f_id, velocity) {
+            let key = self.zones[index]
+                .keys
+                .get(&f_id)
+                .ok_or(NoKeyInInstrumentError { f_id })?;
+            let envelope = self.zones[index].envelope.unwrap_or(self.envelope);
+            let mut layer = self.play_key(key, duration, pitch, &envelope);
+            match sound {
+                Some(ref mut sound) => {
+                    // Zones may have releases of different lengths
+                    if sound.samples.len() < layer.samples.len() {
+                        sound.samples.resize(layer.samples.len(), 0f64);
+                    }
+                    for (sample, layer_sample) in sound.samples.iter_mut().zip(layer.samples) {
+                        *sample += layer_sample * weight;
+                    }
+                }
+                None => {
+                    for sample in &mut layer.samples {
+                        *sample *= weight;
+                    }
+                    sound = Some(layer);
+                }
+            }
+        }
+        Ok(sound.ok_or(NoKeyInInstrumentError { f_id })?)
+    }
+    /// Finds the zones playing a Frequency ID, or the nearest ones if none of them covers it
+    fn get_nearest_zones(&self, f_id: usize) -> Vec<usize> {
+        let distance = |zone: &Zone| {
+            if f_id < zone.lowest_f_id {
+                zone.lowest_f_id - f_id
+            } else {
+                f_id.saturating_sub(zone.highest_f_id)
+            }
+        };
+        let nearest = self.zones.iter().map(&distance).min().unwrap_or(0);
+        let mut zones = (0..self.zones.len())
+            .filter(|&index| distance(&self.zones[index]) == nearest)
+            .collect::<Vec<usize>>();
+        // Out of every zone, only the first range found at that distance is pitch-shifted
+        if nearest > 0 {
+            if let Some(&first) = zones.first() {
+                let range = (
+                    self.zones[first].lowest_f_id,
+                    self.zones[first].highest_f_id,
+                );
+                zones.retain(|&index| {
+                    (
+                        self.zones[index].lowest_f_id,
+                        self.zones[index].highest_f_id,
+                    ) == range
+                });
+            }
+        }
+        zones
+    }
+    /// Finds the zones to mix for a note, with how much each of them is heard.
+    /// Every zone covering the note plays, overlapping zones being the two sides of a stereo sample or sounds layered on purpose.
+    fn get_zone_weights(&self, f_id: usize, velocity: f64) -> Vec<(usize, f64)> {
+        let zones = self.get_nearest_zones(f_id);
+        let weights = zones
+            .iter()
+            .map(|&index| {
+                let zone = &self.zones[index];
+                let weight = if self.velocity_crossfade > 0f64 {
+                    // Fades out over the crossfade range centered on the edges of the zone
+                    let half = self.velocity_crossfade / 2f64;
+                    let from_edge = (velocity - (zone.lowest_velocity - half))
+                        .min((zone.highest_velocity + half) - velocity);
+                    (from_edge / self.velocity_crossfade).min(1f64).max(0f64)
+                } else if (zone.lowest_velocity <= velocity) & (velocity <= zone.highest_velocity) {
+                    1f64
+                } else {
+                    0f64
+                };
+                (index, weight)
+            })
+            .filter(|&(_, weight)| weight > 0f64)
+            .collect::<Vec<(usize, f64)>>();
+        // The fades of neighbouring velocity layers already add up to full volume
+        if !weights.is_empty() {
+            return weights;
+        }
+        // No zone covers the velocity, the nearest one plays alone
+        let velocity_distance = |index: usize| {
+            let zone = &self.zones[index];
+            (zone.lowest_velocity - velocity).max(velocity - zone.highest_velocity)
+        };
+        zones
+            .into_iter()
+            .min_by(|&a, &b| {
+                velocity_distance(a)
+                    .partial_cmp(&velocity_distance(b))
+                    .unwrap_or(Ordering::Equal)
+            })
+            .map(|index| vec![(index, 1f64)])
+            .unwrap_or_default()
+    }
+    /// Reads a Key for the duration of a sound and its release, following the pitch changes and the Envelope
+    fn play_key(
+        &self,
+        key: &Key,
+        duration: Duration,
+        pitch: &[PitchPoint],
+        envelope: &Envelope,
+    ) -> PCM {
+        let sample_rate = f64::from(key.audio.parameters.sample_rate);
+        let nb_samples = ((duration.get() + envelope.release) * sample_rate) as usize;
         let mut pcm_out = Vec::with_capacity(nb_samples);
-        let last_key_sample = key.audio.samples.len() - 1;
+        let key_samples = &key.audio.samples;
+        let loop_points = key.audio.loop_info.first().map(|span| {
+            (
+                span.start_at().get() * sample_rate,
+                span.end_at().get() * sample_rate,
+            )
+        });
+        // Brings a position past the end of the Key back into it
+        let wrap = |position: f64| -> f64 {
+            match loop_points {
+                Some((start, end)) if position >= end => start + (position - start) % (end - start),
+                _ if self.loopable => position % key_samples.len() as f64,
+                _ => position,
+            }
+        };
+        let read = |position: f64| {
+            key_samples
+                .get(wrap(position) as usize)
+                .cloned()
+                .unwrap_or(0f64)
+        };
+        // Reads the Key faster or slower depending on the pitch, interpolating between samples
+        let mut position = 0f64;
+        let mut speed = 1f64;
+        let mut next_point = 0;
         for current_sample in 0..nb_samples {
-            pcm_out.push(key.audio.samples[current_sample % last_key_sample]);
+            let at = current_sample as f64 / sample_rate;
+            while (next_point < pitch.len()) && (pitch[next_point].at.get() <= at) {
+                speed = 2f64.powf(pitch[next_point].cents / 1200f64);
+                next_point += 1;
+            }
+            let key_position = wrap(position);
+            let sample = read(key_position.floor());
+            let next_sample = read(key_position.floor() + 1f64);
+            pcm_out.push(
+                (sample + (next_sample - sample) * key_position.fract())
+                    * envelope.get_level(at, duration.get()),
+            );
+            position += speed;
         }
-        Ok(PCM {
+        PCM {
             parameters: key.audio.parameters,
             loop_info: Vec::new(),
             samples: pcm_out,
-        })
+        }
+    }
+}
+
+impl Envelope {
+    /// Returns the level of the Envelope
+    /// # Arguments
+    /// * at - Seconds since the start of the note
+    /// * duration - How long the note is held before its release
+    pub fn get_level(&self, at: f64, duration: f64) -> f64 {
+        if at < duration {
+            return self.get_held_level(at);
+        }
+        let released = at - duration;
+        if released >= self.release {
+            return 0f64;
+        }
+        self.fall(self.get_held_level(duration), 0f64, released / self.release)
+    }
+    /// Returns the level of the Envelope while the note is held
+    /// # Arguments
+    /// * at - Seconds since the start of the note
+    pub fn get_held_level(&self, at: f64) -> f64 {
+        let mut at = at - self.delay;
+        if at < 0f64 {
+            return 0f64;
+        }
+        if at < self.attack {
+            return at / self.attack;
+        }
+        at -= self.attack + self.hold;
+        if at < 0f64 {
+            return 1f64;
+        }
+        if at < self.decay {
+            return self.fall(1f64, self.sustain, at / self.decay);
+        }
+        self.sustain
+    }
+    /// Goes from a level to a lower one following the curve, progress being in [0; 1]
+    fn fall(&self, from: f64, to: f64, progress: f64) -> f64 {
+        let to_audible = to.max(ENVELOPE_SILENCE);
+        match self.curve {
+            EnvelopeCurve::Exponential if from > to_audible => {
+                from * (to_audible / from).powf(progress)
+            }
+            _ => from + (to - from) * progress,
+        }
+    }
+}
+
+impl Default for Envelope {
+    /// Notes start at full volume and stay there, then fade out over the short DEFAULT_RELEASE to avoid popping sounds
+    fn default() -> Envelope {
+        Envelope {
+            delay: 0f64,
+            attack: 0f64,
+            hold: 0f64,
+            decay: 0f64,
+            sustain: 1f64,
+            release: DEFAULT_RELEASE,
+            curve: EnvelopeCurve::Linear,
+        }
     }
 }
@@ -1,7 +1,12 @@
 use instrument::Key;
-use pcm::{PCMParameters, PCM};
+use pcm::{PCMParameters, SpeakerLayout, PCM};
 use rand::Rng;
-use util::{Duration, Frequency};
+use std::f64::consts::PI;
+use std::f64::EPSILON;
+use util::{Duration, Frequency, Time, TimeSpan};
+
+/// Number of source samples read on each side of a position when resampling, more when lowering the sample rate
+pub const SINC_HALF_WIDTH: usize = 16;
 
 /// Generates new keys to add to an Instrument
 pub trait KeyGenerator {
@@ -42,6 +47,7 @@ impl KeyGenerator for SquareWaveGenerator {
                 parameters: PCMParameters {
                     sample_rate,
                     nb_channels: 1,
+                    speaker_layout: SpeakerLayout::Unspecified,
                 },
                 loop_info: Vec::new(),
                 samples,
@@ -82,6 +88,7 @@ impl KeyGenerator for TriangleWaveGenerator {
                 parameters: PCMParameters {
                     sample_rate,
                     nb_channels: 1,
+                    speaker_layout: SpeakerLayout::Unspecified,
                 },
                 loop_info: Vec::new(),
                 samples,
@@ -112,6 +119,7 @@ impl KeyGenerator for SawtoothWaveGenerator {
                 parameters: PCMParameters {
                     sample_rate,
                     nb_channels: 1,
+                    speaker_layout: SpeakerLayout::Unspecified,
                 },
                 loop_info: Vec::new(),
                 samples,
@@ -138,6 +146,7 @@ impl KeyGenerator for NoiseGenerator {
                 parameters: PCMParameters {
                     sample_rate,
                     nb_channels: 1,
+                    speaker_layout: SpeakerLayout::Unspecified,
                 },
                 loop_info: Vec::new(),
                 samples,
@@ -146,3 +155,95 @@ impl KeyGenerator for NoiseGenerator {
         }
     }
 }
+
+/// A KeyGenerator that pitch-shifts a recorded sound sample to every frequency
+#[derive(Clone)]
+pub struct SampleKeyGenerator {
+    /// The recorded sound, its first loop is kept in the keys. Multiple channels are mixed down to mono.
+    pub audio: PCM,
+    /// The frequency the recorded sound plays at
+    pub root: Frequency,
+}
+
+impl KeyGenerator for SampleKeyGenerator {
+    /// Resamples the sound with a windowed sinc filter, the duration is ignored since the key keeps the loop of the sound
+    fn gen(&mut self, sample_rate: u32, frequency: Frequency, _duration: Duration) -> Key {
+        let nb_channels = usize::from(self.audio.parameters.nb_channels.max(1));
+        let source = self
+            .audio
+            .samples
+            .chunks(nb_channels)
+            .map(|frame| frame.iter().sum::<f64>() / nb_channels as f64)
+            .collect::<Vec<f64>>();
+        let source_rate = f64::from(self.audio.parameters.sample_rate);
+        // Source samples read for every generated sample
+        let step = frequency.get() / self.root.get() * source_rate / f64::from(sample_rate);
+        let loop_points = self.audio.loop_info.first().and_then(|span| {
+            let start = (span.start_at().get() * source_rate).round() as i64;
+            let end = ((span.end_at().get() * source_rate).round() as i64).min(source.len() as i64);
+            if end > start {
+                Some((start, end))
+            } else {
+                None
+            }
+        });
+        // Reads the source as if its loop went on forever
+        let read = |index: i64| -> f64 {
+            let index = match loop_points {
+                Some((start, end)) if index >= end => start + (index - start) % (end - start),
+                _ => index,
+            };
+            if (index < 0) | (index >= source.len() as i64) {
+                0f64
+            } else {
+                source[index as usize]
+            }
+        };
+        // Frequencies above what the key can hold are filtered out
+        let cutoff = step.recip().min(1f64);
+        let half_width = SINC_HALF_WIDTH as f64 / cutoff;
+        let source_end = match loop_points {
+            // One more sample so that the end of the loop can be read
+            Some((_, end)) => end as f64 + step,
+            None => source.len() as f64,
+        };
+        let nb_samples = (source_end / step).ceil() as usize;
+        let mut samples = Vec::with_capacity(nb_samples);
+        for sample_nb in 0..nb_samples {
+            let position = sample_nb as f64 * step;
+            let mut value = 0f64;
+            for index in
+                (position - half_width).ceil() as i64..=(position + half_width).floor() as i64
+            {
+                let distance = position - index as f64;
+                let x = PI * distance * cutoff;
+                let sinc = if x.abs() < EPSILON { 1f64 } else { x.sin() / x };
+                // Blackman window
+                let w = PI * distance / half_width;
+                let window = 0.42f64 + 0.5f64 * w.cos() + 0.08f64 * (2f64 * w).cos();
+                value += read(index) * cutoff * sinc * window;
+            }
+            samples.push(value);
+        }
+        let loop_info = loop_points
+            .and_then(|(start, end)| {
+                let to_time =
+                    |position: i64| Time::new(position as f64 / step / f64::from(sample_rate));
+                TimeSpan::new(to_time(start).ok()?, to_time(end).ok()?).ok()
+            })
+            .into_iter()
+            .collect();
+        Key {
+            audio: PCM {
+                parameters: PCMParameters {
+                    sample_rate,
+                    nb_channels: 1,
+                    speaker_layout: SpeakerLayout::Unspecified,
+                },
+                loop_info,
+                samples,
+            },
+            frequency,
+        }
+    }
+}
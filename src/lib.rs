@@ -6,8 +6,14 @@
 extern crate ez_io;
 extern crate rand;
 
+/// Imports tunes written in ABC notation into a Sequence
+pub mod abc;
+/// Handles writing and reading AIFF and AIFC files
+pub mod aiff;
 /// Contains the errors in this library
 pub mod error;
+/// Encodes PCM to FLAC streams
+pub mod flac;
 /// Allows to go from a Frequency ID to a Frequency Value
 pub mod frequency_lookup;
 /// Code for help on importing a sequence into something usable here
@@ -16,10 +22,22 @@ pub mod helper;
 pub mod instrument;
 /// Generator for keys in instruments, also contains pre-made tone generators for use as instruments
 pub mod key_generator;
+/// Imports Standard MIDI Files into a Sequence
+pub mod midi;
+/// Parses Music Macro Language into a Sequence
+pub mod mml;
+/// Imports MusicXML scores into a Sequence
+pub mod musicxml;
 /// Types for PCM Audio
 pub mod pcm;
 /// Sequence related data
 pub mod sequence;
+/// Loads SFZ instrument definitions with their WAV samples
+pub mod sfz;
+/// Loads SoundFont 2 banks into Instruments
+pub mod soundfont;
+/// Imports tracker modules (MOD and XM) with their samples
+pub mod tracker;
 /// Useful things to make my life easier
 pub mod util;
 /// Handles writing and reading Wave files
@@ -59,35 +77,40 @@ impl Synthesizer {
                 as usize; // Lossy
         let mut out_pcm_data = vec![0f64; nb_samples];
         for note in &self.seq.notes {
-            let mut to_add = self
+            let velocity = note.get_velocity();
+            let to_add = self
                 .inst
                 .get(&note.i_id)
                 .ok_or(NoInstrumentError { i_id: note.i_id })?
-                .gen_sound(note.f_id, note.t_span.duration())?;
-            let nb_samples = to_add.samples.len();
-            // Forced falloff to prevent popping sounds
-            let falloff = 100;
-            if nb_samples > falloff {
-                for i in 0..falloff {
-                    to_add.samples[nb_samples - (falloff - i)] *=
-                        (i as f64 * (-(1f64 / (falloff - 1) as f64))) + 1f64;
-                }
-            }
+                .gen_sound(note.f_id, note.t_span.duration(), &note.pitch, velocity)?;
             let volumes = note.get_volume(self.params.nb_channels as usize); // Lossy
             let out_start_sample =
                 (note.t_span.start_at().get() * sample_rate_float * nb_channels_float).round()
                     as usize; // Lossy
+            let out_end_sample =
+                out_start_sample + to_add.samples.len() * self.params.nb_channels as usize;
+            if out_pcm_data.len() < out_end_sample {
+                // Releases ring on after the last note ends
+                out_pcm_data.resize(out_end_sample, 0f64);
+            }
+            let mut gain = 1f64;
+            let mut next_point = 0;
             for (sample_nb, sample) in to_add.samples.iter().enumerate() {
+                let at = sample_nb as f64 / sample_rate_float;
+                while (next_point < note.gain.len()) && (note.gain[next_point].at.get() <= at) {
+                    gain = note.gain[next_point].factor;
+                    next_point += 1;
+                }
                 for (channel, volume) in volumes.iter().enumerate() {
                     out_pcm_data[out_start_sample
                         + (sample_nb * self.params.nb_channels as usize)
-                        + channel as usize] += sample * volume; // Lossy
+                        + channel as usize] += sample * volume * gain; // Lossy
                 }
             }
         }
         Ok(PCM {
             parameters: self.params,
-            loop_info: Vec::new(), // Needs to change
+            loop_info: self.seq.loop_info.clone(),
             samples: out_pcm_data,
         })
     }
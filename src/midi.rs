@@ -0,0 +1,1020 @@
+use error::{
+    MalformedChunkError, NoInstrumentForProgramError, NoMIDIKeyError, ReadError, TimeInvalidError,
+    UnsupportedParametersError, WriteError, WrongMagicNumberError,
+};
+use ez_io::{ReadE, WriteE};
+use frequency_lookup::{FrequencyLookup, MIDIFrequencyLookup};
+use helper::SequenceHelper;
+use sequence::Sequence;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::io::{Read, Result as IOResult, Write};
+use std::result::Result;
+use std::slice;
+use util::{Time, Volume};
+use wave::read_chunk_data;
+use Result as SynthesizerResult;
+
+/// A parsed Standard MIDI File
+#[derive(Clone)]
+pub struct MIDIFile {
+    /// 0 for a single track, 1 for simultaneous tracks, 2 for independent tracks played one after the other
+    pub format: u16,
+    /// What a tick represents
+    pub division: Division,
+    /// Events of every track
+    pub tracks: Vec<Vec<TrackEvent>>,
+}
+
+/// What a tick represents in a MIDI File
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Division {
+    /// Ticks are a fraction of a quarter note, their duration depends on the tempo
+    TicksPerQuarterNote(u16),
+    /// Ticks are a fraction of a SMPTE frame, their duration is fixed
+    SMPTE {
+        /// Number of frames per second, 29 meaning 29.97
+        frames_per_second: u8,
+        /// Number of ticks in a frame
+        ticks_per_frame: u8,
+    },
+}
+
+/// Converts tick positions to Time, following the tempo changes
+#[derive(Clone)]
+pub struct TempoMap {
+    /// What a tick represents
+    pub division: Division,
+    /// Tempo changes as their tick and their tempo in microseconds per quarter note, sorted by tick.
+    /// Has no effect with SMPTE divisions.
+    pub changes: Vec<(u64, u32)>,
+}
+
+/// An event of a track, with its delay since the previous one
+#[derive(Clone, Debug)]
+pub struct TrackEvent {
+    /// Number of ticks since the previous event of the track
+    pub delta: u32,
+    /// The event itself
+    pub event: Event,
+}
+
+/// An event in a MIDI Track
+#[derive(Clone, Debug)]
+pub enum Event {
+    NoteOff {
+        channel: u8,
+        key: u8,
+        velocity: u8,
+    },
+    NoteOn {
+        channel: u8,
+        key: u8,
+        velocity: u8,
+    },
+    PolyphonicPressure {
+        channel: u8,
+        key: u8,
+        pressure: u8,
+    },
+    ControlChange {
+        channel: u8,
+        controller: u8,
+        value: u8,
+    },
+    ProgramChange {
+        channel: u8,
+        program: u8,
+    },
+    ChannelPressure {
+        channel: u8,
+        pressure: u8,
+    },
+    /// The value goes from 0 to 16383, 8192 meaning no bend
+    PitchBend {
+        channel: u8,
+        value: u16,
+    },
+    /// Meta event, with its type and raw data
+    Meta {
+        kind: u8,
+        data: Vec<u8>,
+    },
+    /// System Exclusive message, with its raw data
+    SysEx(Vec<u8>),
+}
+
+/// Settings used when turning a MIDI File into a Sequence
+pub struct ImportSettings {
+    /// The number of channels of the final output, the pan of MIDI channels is only used with 2
+    pub nb_channels: usize,
+    /// Decides which Instrument plays the notes
+    pub programs: Box<ProgramLookup>,
+    /// MIDI channels (starting from 0) that play percussions, their bank is always PERCUSSION_BANK
+    pub percussion_channels: Vec<u8>,
+    /// How far the pitch goes with a full pitch bend, in semitones, until a channel changes it with RPN 0
+    pub bend_range: f64,
+}
+
+/// Provides an Instrument ID for what a MIDI channel is set to play
+pub trait ProgramLookup {
+    /// The function that does the link between the channel, bank and program, and the Instrument ID
+    fn get_inst(&self, channel: u8, bank: u16, program: u8) -> SynthesizerResult<usize>;
+}
+
+/// Program Lookup that uses the MIDI channel as the Instrument ID, ignoring banks and programs
+#[derive(Clone, Copy)]
+pub struct ChannelLookup {}
+
+/// Bank, program and pitch bend range of a MIDI channel
+#[derive(Clone, Copy)]
+struct ChannelState {
+    bank_msb: u8,
+    bank_lsb: u8,
+    /// The bank selected when the program was changed
+    bank: u16,
+    program: u8,
+    /// The Registered Parameter modified by Data Entry controllers
+    rpn: (u8, u8),
+    /// Pitch offset of a full pitch bend, in cents
+    bend_range: f64,
+}
+
+/// Bank given to the Program Lookup for percussion channels, above any 14 bits bank number
+pub const PERCUSSION_BANK: u16 = 0x4000;
+/// MIDI channel playing percussions in General MIDI, channel 10 counting from 1
+pub const GM_PERCUSSION_CHANNEL: u8 = 9;
+/// Controller number for the high part of the bank
+pub const CC_BANK_SELECT_MSB: u8 = 0;
+/// Controller number for the high part of a Data Entry
+pub const CC_DATA_ENTRY_MSB: u8 = 6;
+/// Controller number for the volume of a channel
+pub const CC_VOLUME: u8 = 7;
+/// Controller number for the pan of a channel
+pub const CC_PAN: u8 = 10;
+/// Controller number for the expression of a channel
+pub const CC_EXPRESSION: u8 = 11;
+/// Controller number for the low part of the bank
+pub const CC_BANK_SELECT_LSB: u8 = 32;
+/// Controller number for the low part of a Data Entry
+pub const CC_DATA_ENTRY_LSB: u8 = 38;
+/// Controller number for the sustain pedal, held from 64
+pub const CC_SUSTAIN: u8 = 64;
+/// Controller number for the low part of the selected Registered Parameter
+pub const CC_RPN_LSB: u8 = 100;
+/// Controller number for the high part of the selected Registered Parameter
+pub const CC_RPN_MSB: u8 = 101;
+/// Registered Parameter for the pitch bend range, semitones in the high part and cents in the low part
+pub const RPN_PITCH_BEND_RANGE: (u8, u8) = (0, 0);
+/// Registered Parameter selected when none is
+pub const RPN_NULL: (u8, u8) = (127, 127);
+
+/// Meta event type for the name of a track
+pub const META_TRACK_NAME: u8 = 0x03;
+/// Meta event type for a marker
+pub const META_MARKER: u8 = 0x06;
+/// Meta event type for the end of a track
+pub const META_END_OF_TRACK: u8 = 0x2F;
+/// Meta event type for a tempo change
+pub const META_SET_TEMPO: u8 = 0x51;
+/// Tempo used until a tempo change happens, in microseconds per quarter note
+pub const DEFAULT_TEMPO: u32 = 500_000;
+/// Frames per second allowed in SMPTE divisions, 29 meaning 29.97
+pub const SMPTE_FRAME_RATES: [u8; 4] = [24, 25, 29, 30];
+
+impl MIDIFile {
+    /// Reads a Standard MIDI File
+    pub fn read<R: Read>(reader: &mut R) -> Result<MIDIFile, ReadError> {
+        let mut header_id = [0u8; 4];
+        reader.read_exact(&mut header_id)?;
+        if &header_id != b"MThd" {
+            return Err(WrongMagicNumberError {
+                expected: *b"MThd",
+                found: header_id,
+            }
+            .into());
+        }
+        let header_size = reader.read_be_to_u32()?;
+        if header_size < 6 {
+            return Err(MalformedChunkError {
+                chunk_id: *b"MThd",
+                reason: "Chunk is too small",
+            }
+            .into());
+        }
+        let format = reader.read_be_to_u16()?;
+        let nb_tracks = reader.read_be_to_u16()?;
+        let raw_division = reader.read_be_to_u16()?;
+        skip(reader, u64::from(header_size - 6))?;
+        if format > 2 {
+            return Err(MalformedChunkError {
+                chunk_id: *b"MThd",
+                reason: "Unknown format",
+            }
+            .into());
+        }
+        let division = if raw_division & 0x8000 == 0 {
+            Division::TicksPerQuarterNote(raw_division)
+        } else {
+            // Frames per second are stored as a negative number
+            Division::SMPTE {
+                frames_per_second: ((raw_division >> 8) as u8 as i8).wrapping_neg() as u8,
+                ticks_per_frame: (raw_division & 0xFF) as u8,
+            }
+        };
+        let invalid_division = match division {
+            Division::TicksPerQuarterNote(ticks) => ticks == 0,
+            Division::SMPTE {
+                frames_per_second,
+                ticks_per_frame,
+            } => !SMPTE_FRAME_RATES.contains(&frames_per_second) | (ticks_per_frame == 0),
+        };
+        if invalid_division {
+            return Err(MalformedChunkError {
+                chunk_id: *b"MThd",
+                reason: "Invalid division",
+            }
+            .into());
+        }
+        let mut tracks = Vec::with_capacity(usize::from(nb_tracks));
+        while tracks.len() < usize::from(nb_tracks) {
+            let mut chunk_id = [0u8; 4];
+            reader.read_exact(&mut chunk_id)?;
+            let chunk_size = reader.read_be_to_u32()?;
+            let data = read_chunk_data(reader, u64::from(chunk_size))?;
+            // Unknown chunks have to be ignored
+            if &chunk_id == b"MTrk" {
+                tracks.push(read_track(&data)?);
+            }
+        }
+        Ok(MIDIFile {
+            format,
+            division,
+            tracks,
+        })
+    }
+    /// Builds a Sequence from the MIDI events.
+    /// The Frequency IDs are the MIDI note numbers, usable with MIDIFrequencyLookup, and the Instrument IDs come from the Program Lookup of the settings.
+    pub fn to_sequence(&self, settings: &ImportSettings) -> SynthesizerResult<Sequence> {
+        let mut helper = SequenceHelper::new();
+        if self.format == 2 {
+            // Every track is its own song, played after the previous one
+            for track in &self.tracks {
+                self.play_tracks(&mut helper, slice::from_ref(track), settings)?;
+            }
+        } else {
+            self.play_tracks(&mut helper, &self.tracks, settings)?;
+        }
+        Ok(helper.sequence)
+    }
+    /// Builds a format 1 MIDI File from a Sequence. The first track holds the tempo and the markers, then there is one track per Instrument ID.
+    /// Every Instrument ID gets its own MIDI channel, skipping the percussion channel, so no more than 15 of them can be used.
+    /// Their tracks start with a Program Change to the Instrument ID, modulo 128.
+    /// # Arguments
+    /// * seq - The Sequence to convert
+    /// * f_lu - The Frequency Lookup of the Sequence, every Frequency used has to match a MIDI key
+    /// * ticks_per_quarter_note - The resolution of the file
+    /// * tempo - The tempo of the file in microseconds per quarter note
+    pub fn from_sequence(
+        seq: &Sequence,
+        f_lu: &FrequencyLookup,
+        ticks_per_quarter_note: u16,
+        tempo: u32,
+    ) -> SynthesizerResult<MIDIFile> {
+        // Set Tempo events store the tempo in 24 bits
+        if (tempo == 0) | (tempo > 0xFF_FFFF) {
+            return Err(UnsupportedParametersError {
+                reason: "Tempo has to be between 1 and 16777215 microseconds per quarter note",
+            }
+            .into());
+        }
+        let ticks_per_second = f64::from(ticks_per_quarter_note) * 1_000_000f64 / f64::from(tempo);
+        let to_tick = |time: Time| (time.get() * ticks_per_second).round() as u64;
+        // Tempo track
+        let mut tempo_events = vec![(
+            0,
+            Event::Meta {
+                kind: META_SET_TEMPO,
+                data: vec![(tempo >> 16) as u8, (tempo >> 8) as u8, tempo as u8],
+            },
+        )];
+        for marker in &seq.markers {
+            tempo_events.push((
+                to_tick(marker.at),
+                Event::Meta {
+                    kind: META_MARKER,
+                    data: marker.label.clone().into_bytes(),
+                },
+            ));
+        }
+        let mut tracks = vec![to_track(tempo_events)];
+        // Instrument tracks
+        let mut i_ids = seq.notes.iter().map(|n| n.i_id).collect::<Vec<usize>>();
+        i_ids.sort();
+        i_ids.dedup();
+        let mut channels = (0..16).filter(|&channel| channel != GM_PERCUSSION_CHANNEL);
+        let mut keys = HashMap::new();
+        for i_id in i_ids {
+            let channel = channels.next().ok_or(UnsupportedParametersError {
+                reason: "A MIDI File cannot play more than 15 melodic instruments at once",
+            })?;
+            let mut events = vec![
+                (
+                    0,
+                    Event::Meta {
+                        kind: META_TRACK_NAME,
+                        data: format!("Instrument {}", i_id).into_bytes(),
+                    },
+                ),
+                (
+                    0,
+                    Event::ProgramChange {
+                        channel,
+                        program: (i_id % 128) as u8,
+                    },
+                ),
+            ];
+            for note in seq.notes.iter().filter(|n| n.i_id == i_id) {
+                let key = match keys.get(&note.f_id) {
+                    Some(&key) => key,
+                    None => {
+                        let frequency = f_lu.get_freq(note.f_id)?;
+                        let key = MIDIFrequencyLookup::get_key(frequency).ok_or(NoMIDIKeyError {
+                            f_id: note.f_id,
+                            frequency: frequency.get(),
+                        })? as u8;
+                        keys.insert(note.f_id, key);
+                        key
+                    }
+                };
+                // MIDI velocities start at 1
+                let velocity = (note.get_velocity() * 127f64).round().max(1f64) as u8;
+                events.push((
+                    to_tick(note.t_span.start_at()),
+                    Event::NoteOn {
+                        channel,
+                        key,
+                        velocity,
+                    },
+                ));
+                events.push((
+                    to_tick(note.t_span.end_at()),
+                    Event::NoteOff {
+                        channel,
+                        key,
+                        velocity: 64,
+                    },
+                ));
+            }
+            tracks.push(to_track(events));
+        }
+        Ok(MIDIFile {
+            format: 1,
+            division: Division::TicksPerQuarterNote(ticks_per_quarter_note),
+            tracks,
+        })
+    }
+    /// Writes a Standard MIDI File. An End of Track event is added to tracks missing one.
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<(), WriteError> {
+        let raw_division = match self.division {
+            Division::TicksPerQuarterNote(ticks) => {
+                if (ticks == 0) | (ticks & 0x8000 != 0) {
+                    return Err(UnsupportedParametersError {
+                        reason: "Ticks per quarter note have to be between 1 and 32767",
+                    }
+                    .into());
+                }
+                ticks
+            }
+            Division::SMPTE {
+                frames_per_second,
+                ticks_per_frame,
+            } => {
+                if !SMPTE_FRAME_RATES.contains(&frames_per_second) | (ticks_per_frame == 0) {
+                    return Err(UnsupportedParametersError {
+                        reason: "SMPTE divisions need 24, 25, 29 or 30 frames per second and at least 1 tick per frame",
+                    }
+                    .into());
+                }
+                // Frames per second are stored as a negative number
+                (u16::from((frames_per_second as i8).wrapping_neg() as u8) << 8)
+                    | u16::from(ticks_per_frame)
+            }
+        };
+        if self.tracks.len() > usize::from(u16::max_value()) {
+            return Err(UnsupportedParametersError {
+                reason: "A MIDI File cannot hold more than 65535 tracks",
+            }
+            .into());
+        }
+        writer.write_all(b"MThd")?;
+        writer.write_be_to_u32(6)?;
+        writer.write_be_to_u16(self.format)?;
+        writer.write_be_to_u16(self.tracks.len() as u16)?;
+        writer.write_be_to_u16(raw_division)?;
+        for track in &self.tracks {
+            let mut data = Vec::new();
+            let mut has_end = false;
+            for track_event in track {
+                write_variable_length(&mut data, track_event.delta)?;
+                write_event(&mut data, &track_event.event)?;
+                if let Event::Meta { kind, .. } = track_event.event {
+                    if kind == META_END_OF_TRACK {
+                        has_end = true;
+                        break;
+                    }
+                }
+            }
+            if !has_end {
+                write_variable_length(&mut data, 0)?;
+                write_event(
+                    &mut data,
+                    &Event::Meta {
+                        kind: META_END_OF_TRACK,
+                        data: Vec::new(),
+                    },
+                )?;
+            }
+            writer.write_all(b"MTrk")?;
+            writer.write_be_to_u32(data.len() as u32)?;
+            writer.write_all(&data)?;
+        }
+        Ok(())
+    }
+    /// Feeds events sorted by absolute tick to a SequenceHelper, following the tempo changes of the same tracks
+    fn play_tracks(
+        &self,
+        helper: &mut SequenceHelper,
+        tracks: &[Vec<TrackEvent>],
+        settings: &ImportSettings,
+    ) -> SynthesizerResult<()> {
+        helper.set_tempo_map(TempoMap::from_tracks(self.division, tracks));
+        let mut channels = [ChannelState {
+            bank_msb: 0,
+            bank_lsb: 0,
+            bank: 0,
+            program: 0,
+            rpn: RPN_NULL,
+            bend_range: settings.bend_range * 100f64,
+        }; 16];
+        helper.controllers = HashMap::new();
+        // Instrument ID of every playing note by channel and key, as the program can change before the note stops
+        let mut playing = HashMap::new();
+        let mut previous_tick = 0;
+        for (tick, event) in merge_tracks(tracks) {
+            helper.tick_forward(tick - previous_tick)?;
+            previous_tick = tick;
+            match *event {
+                Event::NoteOn {
+                    channel,
+                    key,
+                    velocity,
+                } if velocity > 0 => {
+                    let state = &channels[usize::from(channel)];
+                    let bank = if settings.percussion_channels.contains(&channel) {
+                        PERCUSSION_BANK
+                    } else {
+                        state.bank
+                    };
+                    let i_id = settings.programs.get_inst(channel, bank, state.program)?;
+                    helper.start_channel_note(
+                        usize::from(key),
+                        i_id,
+                        usize::from(channel),
+                        Volume::new(f64::from(velocity) / 127f64)?,
+                        settings.nb_channels,
+                    )?;
+                    playing.insert((channel, key), i_id);
+                }
+                Event::NoteOn { channel, key, .. } | Event::NoteOff { channel, key, .. } => {
+                    if let Some(i_id) = playing.remove(&(channel, key)) {
+                        helper.stop_channel_note(usize::from(key), i_id, usize::from(channel))?
+                    }
+                }
+                Event::ControlChange {
+                    channel,
+                    controller,
+                    value,
+                } => {
+                    let state = &mut channels[usize::from(channel)];
+                    let value_float = f64::from(value) / 127f64;
+                    match controller {
+                        CC_BANK_SELECT_MSB => state.bank_msb = value,
+                        CC_BANK_SELECT_LSB => state.bank_lsb = value,
+                        CC_VOLUME => helper.set_volume(usize::from(channel), value_float)?,
+                        CC_EXPRESSION => {
+                            helper.set_expression(usize::from(channel), value_float)?
+                        }
+                        CC_PAN => {
+                            // 64 is the center, 0 and 1 are both full left
+                            let pan = ((f64::from(value) - 64f64) / 63f64).max(-1f64);
+                            helper.get_controllers(usize::from(channel)).pan = pan
+                        }
+                        CC_SUSTAIN => helper.set_sustain(usize::from(channel), value >= 64)?,
+                        CC_RPN_MSB => state.rpn.0 = value,
+                        CC_RPN_LSB => state.rpn.1 = value,
+                        CC_DATA_ENTRY_MSB if state.rpn == RPN_PITCH_BEND_RANGE => {
+                            state.bend_range = f64::from(value) * 100f64
+                        }
+                        CC_DATA_ENTRY_LSB if state.rpn == RPN_PITCH_BEND_RANGE => {
+                            state.bend_range =
+                                (state.bend_range / 100f64).floor() * 100f64 + f64::from(value)
+                        }
+                        _ => {}
+                    }
+                }
+                Event::PitchBend { channel, value } => {
+                    let bend = (f64::from(value) - 8192f64) / 8192f64;
+                    let bend_range = channels[usize::from(channel)].bend_range;
+                    helper.set_pitch_bend(usize::from(channel), bend * bend_range)?;
+                }
+                Event::ProgramChange { channel, program } => {
+                    // A Bank Select only takes effect with the next Program Change
+                    let state = &mut channels[usize::from(channel)];
+                    state.bank = (u16::from(state.bank_msb) << 7) | u16::from(state.bank_lsb);
+                    state.program = program;
+                }
+                _ => {}
+            }
+        }
+        helper.stop_all_notes()
+    }
+}
+
+impl ImportSettings {
+    /// Creates settings with channel 10 playing percussions and a pitch bend range of 2 semitones
+    /// # Arguments
+    /// * nb_channels - The number of channels of the final output
+    /// * programs - Decides which Instrument plays the notes
+    pub fn new(nb_channels: usize, programs: Box<ProgramLookup>) -> ImportSettings {
+        ImportSettings {
+            nb_channels,
+            programs,
+            percussion_channels: vec![GM_PERCUSSION_CHANNEL],
+            bend_range: 2f64,
+        }
+    }
+}
+
+impl<S: BuildHasher> ProgramLookup for HashMap<(u8, u16, u8), usize, S> {
+    fn get_inst(&self, channel: u8, bank: u16, program: u8) -> SynthesizerResult<usize> {
+        match self.get(&(channel, bank, program)) {
+            Some(i_id) => Ok(*i_id),
+            None => Err(NoInstrumentForProgramError {
+                channel,
+                bank,
+                program,
+            }
+            .into()),
+        }
+    }
+}
+
+impl ProgramLookup for ChannelLookup {
+    fn get_inst(&self, channel: u8, _bank: u16, _program: u8) -> SynthesizerResult<usize> {
+        Ok(usize::from(channel))
+    }
+}
+
+impl TempoMap {
+    /// Creates a Tempo Map with no tempo change, playing at DEFAULT_TEMPO
+    pub fn new(division: Division) -> TempoMap {
+        TempoMap {
+            division,
+            changes: Vec::new(),
+        }
+    }
+    /// Creates a Tempo Map from the Set Tempo events of tracks played together
+    pub fn from_tracks(division: Division, tracks: &[Vec<TrackEvent>]) -> TempoMap {
+        let mut tempo_map = TempoMap::new(division);
+        for (tick, event) in merge_tracks(tracks) {
+            match *event {
+                Event::Meta { kind, ref data } if (kind == META_SET_TEMPO) & (data.len() >= 3) => {
+                    let tempo =
+                        (u32::from(data[0]) << 16) | (u32::from(data[1]) << 8) | u32::from(data[2]);
+                    tempo_map.set_tempo(tick, tempo);
+                }
+                _ => {}
+            }
+        }
+        tempo_map
+    }
+    /// Changes the tempo from a tick onwards, replacing any change already at this tick
+    /// # Arguments
+    /// * tick - Where the change happens
+    /// * tempo - The new tempo in microseconds per quarter note
+    pub fn set_tempo(&mut self, tick: u64, tempo: u32) {
+        match self.changes.binary_search_by_key(&tick, |&(t, _)| t) {
+            Ok(i) => self.changes[i].1 = tempo,
+            Err(i) => self.changes.insert(i, (tick, tempo)),
+        }
+    }
+    /// Returns the tempo in microseconds per quarter note at a tick
+    pub fn get_tempo(&self, tick: u64) -> u32 {
+        self.changes
+            .iter()
+            .take_while(|&&(t, _)| t <= tick)
+            .last()
+            .map_or(DEFAULT_TEMPO, |&(_, tempo)| tempo)
+    }
+    /// Converts a tick position to seconds since tick 0
+    pub fn get_seconds(&self, tick: u64) -> f64 {
+        match self.division {
+            Division::TicksPerQuarterNote(ticks_per_quarter_note) => {
+                let ticks_per_quarter_note = f64::from(ticks_per_quarter_note);
+                let mut seconds = 0f64;
+                let mut segment_start = 0u64;
+                let mut tempo = DEFAULT_TEMPO;
+                for &(change_tick, new_tempo) in &self.changes {
+                    if change_tick >= tick {
+                        break;
+                    }
+                    seconds += ((change_tick - segment_start) as f64 * f64::from(tempo))
+                        / (ticks_per_quarter_note * 1_000_000f64);
+                    segment_start = change_tick;
+                    tempo = new_tempo;
+                }
+                seconds
+                    + ((tick - segment_start) as f64 * f64::from(tempo))
+                        / (ticks_per_quarter_note * 1_000_000f64)
+            }
+            Division::SMPTE {
+                frames_per_second,
+                ticks_per_frame,
+            } => {
+                let frames_per_second = if frames_per_second == 29 {
+                    29.97f64
+                } else {
+                    f64::from(frames_per_second)
+                };
+                tick as f64 / (frames_per_second * f64::from(ticks_per_frame))
+            }
+        }
+    }
+    /// Converts a tick position to a Time
+    pub fn get_time(&self, tick: u64) -> Result<Time, TimeInvalidError> {
+        Time::new(self.get_seconds(tick))
+    }
+}
+
+/// Turns events placed at absolute ticks into a track ending with an End of Track event.
+/// At the same tick, notes are stopped before new ones start.
+fn to_track(mut events: Vec<(u64, Event)>) -> Vec<TrackEvent> {
+    events.sort_by_key(|&(tick, ref event)| match *event {
+        Event::NoteOn { .. } => (tick, 1),
+        _ => (tick, 0),
+    });
+    let mut track = Vec::with_capacity(events.len() + 1);
+    let mut previous_tick = 0;
+    for (tick, event) in events {
+        track.push(TrackEvent {
+            delta: (tick - previous_tick) as u32,
+            event,
+        });
+        previous_tick = tick;
+    }
+    track.push(TrackEvent {
+        delta: 0,
+        event: Event::Meta {
+            kind: META_END_OF_TRACK,
+            data: Vec::new(),
+        },
+    });
+    track
+}
+
+/// Puts the events of multiple tracks together, with their absolute tick, sorted by tick.
+/// Events at the same tick keep the order of their tracks.
+fn merge_tracks(tracks: &[Vec<TrackEvent>]) -> Vec<(u64, &Event)> {
+    let mut events = Vec::new();
+    for track in tracks {
+        let mut tick = 0u64;
+        for track_event in track {
+            tick += u64::from(track_event.delta);
+            events.push((tick, &track_event.event));
+        }
+    }
+    events.sort_by_key(|&(tick, _)| tick);
+    events
+}
+
+/// Parses the events of a Track Chunk
+fn read_track(data: &[u8]) -> Result<Vec<TrackEvent>, ReadError> {
+    let mut reader = data;
+    let mut events = Vec::new();
+    let mut running_status: Option<u8> = None;
+    while !reader.is_empty() {
+        let delta = read_variable_length(&mut reader)?;
+        let mut status = reader.read_to_u8()?;
+        let event = match status {
+            0xFF => {
+                running_status = None;
+                let kind = reader.read_to_u8()?;
+                let data = read_data(&mut reader)?;
+                Event::Meta { kind, data }
+            }
+            0xF0 | 0xF7 => {
+                running_status = None;
+                Event::SysEx(read_data(&mut reader)?)
+            }
+            _ => {
+                // Running Status: the status byte is omitted and the data starts right away
+                let first_data = if status & 0x80 == 0 {
+                    let data = status;
+                    status = running_status.ok_or(MalformedChunkError {
+                        chunk_id: *b"MTrk",
+                        reason: "Data byte without a status",
+                    })?;
+                    data
+                } else {
+                    running_status = Some(status);
+                    reader.read_to_u8()?
+                };
+                let channel = status & 0x0F;
+                match status & 0xF0 {
+                    0x80 => Event::NoteOff {
+                        channel,
+                        key: first_data,
+                        velocity: reader.read_to_u8()?,
+                    },
+                    0x90 => Event::NoteOn {
+                        channel,
+                        key: first_data,
+                        velocity: reader.read_to_u8()?,
+                    },
+                    0xA0 => Event::PolyphonicPressure {
+                        channel,
+                        key: first_data,
+                        pressure: reader.read_to_u8()?,
+                    },
+                    0xB0 => Event::ControlChange {
+                        channel,
+                        controller: first_data,
+                        value: reader.read_to_u8()?,
+                    },
+                    0xC0 => Event::ProgramChange {
+                        channel,
+                        program: first_data,
+                    },
+                    0xD0 => Event::ChannelPressure {
+                        channel,
+                        pressure: first_data,
+                    },
+                    0xE0 => Event::PitchBend {
+                        channel,
+                        value: u16::from(first_data & 0x7F)
+                            | (u16::from(reader.read_to_u8()? & 0x7F) << 7),
+                    },
+                    _ => {
+                        return Err(MalformedChunkError {
+                            chunk_id: *b"MTrk",
+                            reason: "Unknown status byte",
+                        }
+                        .into())
+                    }
+                }
+            }
+        };
+        let is_end = match event {
+            Event::Meta { kind, .. } => kind == META_END_OF_TRACK,
+            _ => false,
+        };
+        events.push(TrackEvent { delta, event });
+        if is_end {
+            break;
+        }
+    }
+    Ok(events)
+}
+
+/// Reads a number stored 7 bits per byte, the highest bit telling if another byte follows
+fn read_variable_length<R: Read>(reader: &mut R) -> Result<u32, ReadError> {
+    let mut value = 0u32;
+    for _ in 0..4 {
+        let byte = reader.read_to_u8()?;
+        value = (value << 7) | u32::from(byte & 0x7F);
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(MalformedChunkError {
+        chunk_id: *b"MTrk",
+        reason: "Variable length number is too long",
+    }
+    .into())
+}
+
+/// Reads data preceded by its variable length size
+fn read_data<R: Read>(reader: &mut R) -> Result<Vec<u8>, ReadError> {
+    let size = read_variable_length(reader)?;
+    Ok(read_chunk_data(reader, u64::from(size))?)
+}
+
+/// Skips some bytes
+fn skip<R: Read>(reader: &mut R, nb_bytes: u64) -> Result<(), ReadError> {
+    let mut skipped = Vec::new();
+    reader.take(nb_bytes).read_to_end(&mut skipped)?;
+    Ok(())
+}
+
+/// Writes an event with its status byte
+fn write_event<W: Write>(writer: &mut W, event: &Event) -> IOResult<()> {
+    match *event {
+        Event::NoteOff {
+            channel,
+            key,
+            velocity,
+        } => writer.write_all(&[0x80 | channel, key, velocity]),
+        Event::NoteOn {
+            channel,
+            key,
+            velocity,
+        } => writer.write_all(&[0x90 | channel, key, velocity]),
+        Event::PolyphonicPressure {
+            channel,
+            key,
+            pressure,
+        } => writer.write_all(&[0xA0 | channel, key, pressure]),
+        Event::ControlChange {
+            channel,
+            controller,
+            value,
+        } => writer.write_all(&[0xB0 | channel, controller, value]),
+        Event::ProgramChange { channel, program } => writer.write_all(&[0xC0 | channel, program]),
+        Event::ChannelPressure { channel, pressure } => {
+            writer.write_all(&[0xD0 | channel, pressure])
+        }
+        Event::PitchBend { channel, value } => writer.write_all(&[
+            0xE0 | channel,
+            (value & 0x7F) as u8,
+            ((value >> 7) & 0x7F) as u8,
+        ]),
+        Event::Meta { kind, ref data } => {
+            writer.write_all(&[0xFF, kind])?;
+            write_variable_length(writer, data.len() as u32)?;
+            writer.write_all(data)
+        }
+        Event::SysEx(ref data) => {
+            writer.write_all(&[0xF0])?;
+            write_variable_length(writer, data.len() as u32)?;
+            writer.write_all(data)
+        }
+    }
+}
+
+/// Writes a number 7 bits per byte, the highest bit telling if another byte follows
+fn write_variable_length<W: Write>(writer: &mut W, value: u32) -> IOResult<()> {
+    let mut bytes = vec![(value & 0x7F) as u8];
+    let mut rest = value >> 7;
+    while rest > 0 {
+        bytes.insert(0, (rest & 0x7F) as u8 | 0x80);
+        rest >>= 7;
+    }
+    writer.write_all(&bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn round_trip(division: Division) {
+        let events = vec![
+            Event::Meta {
+                kind: META_SET_TEMPO,
+                data: vec![0x07, 0xA1, 0x20],
+            },
+            Event::ControlChange {
+                channel: 0,
+                controller: CC_VOLUME,
+                value: 100,
+            },
+            Event::ProgramChange {
+                channel: 0,
+                program: 19,
+            },
+            Event::NoteOn {
+                channel: 0,
+                key: 60,
+                velocity: 90,
+            },
+            Event::PitchBend {
+                channel: 0,
+                value: 12_000,
+            },
+            Event::PolyphonicPressure {
+                channel: 0,
+                key: 60,
+                pressure: 40,
+            },
+            Event::ChannelPressure {
+                channel: 0,
+                pressure: 30,
+            },
+            Event::SysEx(vec![0x7E, 0x7F, 0x09, 0x01, 0xF7]),
+            Event::NoteOff {
+                channel: 0,
+                key: 60,
+                velocity: 64,
+            },
+        ];
+        let track: Vec<TrackEvent> = events
+            .into_iter()
+            .enumerate()
+            .map(|(i, event)| TrackEvent {
+                delta: (i as u32) * 200,
+                event,
+            })
+            .collect();
+        let percussion = vec![TrackEvent {
+            delta: 0x0F_FFFF,
+            event: Event::NoteOn {
+                channel: GM_PERCUSSION_CHANNEL,
+                key: 36,
+                velocity: 127,
+            },
+        }];
+        let midi = MIDIFile {
+            format: 1,
+            division,
+            tracks: vec![track, percussion],
+        };
+        let mut bytes = Vec::new();
+        midi.write(&mut bytes).unwrap();
+        let read = MIDIFile::read(&mut Cursor::new(bytes)).unwrap();
+        assert_eq!(read.format, 1);
+        assert_eq!(read.division, division);
+        assert_eq!(read.tracks.len(), 2);
+        for (written, read) in midi.tracks.iter().zip(&read.tracks) {
+            // The writer ends every track
+            let mut expected = written.clone();
+            expected.push(TrackEvent {
+                delta: 0,
+                event: Event::Meta {
+                    kind: META_END_OF_TRACK,
+                    data: Vec::new(),
+                },
+            });
+            assert_eq!(format!("{:?}", read), format!("{:?}", expected));
+        }
+    }
+
+    #[test]
+    fn round_trip_ticks_per_quarter_note() {
+        round_trip(Division::TicksPerQuarterNote(480));
+    }
+
+    #[test]
+    fn round_trip_smpte() {
+        round_trip(Division::SMPTE {
+            frames_per_second: 25,
+            ticks_per_frame: 40,
+        });
+    }
+
+    #[test]
+    fn from_sequence() {
+        let mut helper = SequenceHelper::new();
+        for i_id in 0..16 {
+            helper
+                .new_note(60, i_id, Time::new(0.5).unwrap(), Vec::new())
+                .unwrap();
+        }
+        // One instrument too many
+        assert!(
+            MIDIFile::from_sequence(&helper.sequence, &MIDIFrequencyLookup {}, 480, 500_000)
+                .is_err()
+        );
+        helper.sequence.notes.pop();
+        assert!(
+            MIDIFile::from_sequence(&helper.sequence, &MIDIFrequencyLookup {}, 480, 0).is_err()
+        );
+        assert!(MIDIFile::from_sequence(
+            &helper.sequence,
+            &MIDIFrequencyLookup {},
+            480,
+            0x100_0000
+        )
+        .is_err());
+        let midi = MIDIFile::from_sequence(&helper.sequence, &MIDIFrequencyLookup {}, 480, 500_000)
+            .unwrap();
+        let channels: Vec<(u8, u8)> = midi.tracks[1..]
+            .iter()
+            .map(|track| match track[1].event {
+                Event::ProgramChange { channel, program } => (channel, program),
+                ref event => panic!("Expected a Program Change, found {:?}", event),
+            })
+            .collect();
+        let expected: Vec<(u8, u8)> = (0..15)
+            .map(|i_id| (if i_id < 9 { i_id } else { i_id + 1 }, i_id))
+            .collect();
+        assert_eq!(channels, expected);
+    }
+
+    #[test]
+    fn unknown_frame_rate() {
+        // -128 frames per second
+        let header = [b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 0, 0, 0, 0x80, 40];
+        assert!(MIDIFile::read(&mut Cursor::new(header.to_vec())).is_err());
+    }
+}
@@ -0,0 +1,488 @@
+use error::ParseError;
+use helper::SequenceHelper;
+use midi::{Division, TempoMap};
+use sequence::Sequence;
+use util::{TextCursor, Volume};
+use Result;
+
+/// Number of ticks in a whole note, divisible by most lengths
+pub const WHOLE_NOTE_TICKS: u32 = 1920;
+/// Octave used until changed, `o4c` being the middle C
+pub const DEFAULT_OCTAVE: i32 = 4;
+/// Length used until changed, as written after `l`
+pub const DEFAULT_LENGTH: u32 = 4;
+/// Highest value of `v`, meaning full volume
+pub const MAX_VOLUME: u32 = 15;
+/// Highest value of `o`, the octave holding the highest MIDI keys
+pub const MAX_OCTAVE: u32 = 9;
+/// Highest value of `t`, faster tempos being too short to place notes on ticks
+pub const MAX_TEMPO: u32 = 1000;
+/// Number of times a loop plays when no count is written after `]`
+pub const DEFAULT_LOOP_COUNT: u32 = 2;
+/// Most commands a channel can play once its loops are repeated, so that nested loops cannot run forever
+pub const MAX_PLAYED_COMMANDS: u64 = 1_000_000;
+
+/// A length as written in MML, like `8.`
+#[derive(Clone, Copy)]
+struct Length {
+    /// Fraction of a whole note, the current default length if there is none
+    number: Option<u32>,
+    /// Each dot adds half of what the previous one added
+    dots: u32,
+}
+
+/// A command of a MML channel
+enum Command {
+    Note {
+        /// Semitones from C in the current octave, accidentals included
+        semitone: i32,
+        /// Lengths added together with `&`
+        lengths: Vec<Length>,
+        /// If the next note continues this one when it has the same pitch
+        tie: bool,
+        line: usize,
+        column: usize,
+    },
+    Rest(Length),
+    Octave(i32),
+    OctaveUp,
+    OctaveDown,
+    DefaultLength(Length),
+    /// Quarter notes per minute
+    Tempo(u32),
+    Volume(u32),
+    Instrument(usize),
+    Loop {
+        commands: Vec<Command>,
+        count: u32,
+        line: usize,
+        column: usize,
+    },
+}
+
+/// A note placed in ticks
+struct PlacedNote {
+    start: u64,
+    end: u64,
+    key: usize,
+    volume: u32,
+    i_id: usize,
+}
+
+/// Where a channel is at while its commands are played
+struct ChannelState {
+    tick: u64,
+    octave: i32,
+    /// Default length in ticks
+    length: u32,
+    volume: u32,
+    i_id: usize,
+    /// If the last note is tied to the next one
+    tie: bool,
+    notes: Vec<PlacedNote>,
+    /// Number of commands played so far, every repetition of the loops included
+    played: u64,
+}
+
+/// Builds a Sequence from Music Macro Language text.
+/// Channels are separated by `;` and play at the same time, each one uses its number (starting from 0) as the Instrument ID until `@` changes it.
+/// The Frequency IDs are MIDI note numbers, usable with MIDIFrequencyLookup.
+///
+/// Supported commands, case insensitive:
+/// * `c` `d` `e` `f` `g` `a` `b` - Notes, followed by `+` or `#` for sharps, `-` for flats, then an optional length and dots
+/// * `r` - Rest, with an optional length and dots
+/// * `&` - Ties the previous note to the next one if they have the same pitch, or adds a length to the previous note when followed by one
+/// * `o` - Sets the octave, `<` goes one octave down and `>` one octave up
+/// * `l` - Sets the default length, as a fraction of a whole note
+/// * `t` - Sets the tempo for every channel, in quarter notes per minute from 1 to 1000
+/// * `v` - Sets the volume, from 0 to 15
+/// * `@` - Sets the Instrument ID
+/// * `[` `]` - Repeats what is inside, as many times as the number following `]` or twice if there is none
+/// # Arguments
+/// * text - The MML to parse
+/// * nb_channels - The number of channels of the final output, every note gets its volume on all of them
+pub fn parse(text: &str, nb_channels: usize) -> Result<Sequence> {
+    let mut cursor = TextCursor::new(text);
+    let mut channels = Vec::new();
+    loop {
+        channels.push(parse_commands(&mut cursor, None)?);
+        // Only a `;` or the end of the text can stop the commands outside a loop
+        if cursor.next_char().is_none() {
+            break;
+        }
+    }
+    let mut tempo_map = TempoMap::new(Division::TicksPerQuarterNote((WHOLE_NOTE_TICKS / 4) as u16));
+    let mut states = Vec::with_capacity(channels.len());
+    for (channel, commands) in channels.iter().enumerate() {
+        let mut state = ChannelState {
+            tick: 0,
+            octave: DEFAULT_OCTAVE,
+            length: WHOLE_NOTE_TICKS / DEFAULT_LENGTH,
+            volume: MAX_VOLUME,
+            i_id: channel,
+            tie: false,
+            notes: Vec::new(),
+            played: 0,
+        };
+        play_commands(commands, &mut state, &mut tempo_map)?;
+        states.push(state);
+    }
+    let mut helper = SequenceHelper::new();
+    for state in states {
+        helper.reset();
+        helper.set_tempo_map(tempo_map.clone());
+        for note in state.notes {
+            let volume = Volume::new(f64::from(note.volume) / f64::from(MAX_VOLUME))?;
+            let at_tick = helper.at_tick;
+            helper.tick_forward(note.start - at_tick)?;
+            helper.start_note(note.key, note.i_id, vec![volume; nb_channels])?;
+            helper.tick_forward(note.end - note.start)?;
+            helper.stop_note(note.key, note.i_id)?;
+        }
+    }
+    Ok(helper.sequence)
+}
+
+/// Parses commands until the end of the channel, or the end of the loop if one is opened
+/// # Arguments
+/// * cursor - Where the text is read
+/// * loop_start - Line and column of the `[` of the loop being parsed
+fn parse_commands(
+    cursor: &mut TextCursor,
+    loop_start: Option<(usize, usize)>,
+) -> Result<Vec<Command>> {
+    let mut commands = Vec::new();
+    loop {
+        cursor.skip_whitespace();
+        let (line, column) = (cursor.line, cursor.column);
+        let c = match cursor.peek_char() {
+            None | Some(';') => match loop_start {
+                Some((line, column)) => {
+                    return Err(ParseError {
+                        line,
+                        column,
+                        reason: "Loop is never closed",
+                    }
+                    .into())
+                }
+                None => return Ok(commands),
+            },
+            Some(c) => c.to_ascii_lowercase(),
+        };
+        cursor.next_char();
+        let command = match c {
+            'c' | 'd' | 'e' | 'f' | 'g' | 'a' | 'b' => {
+                let mut semitone = match c {
+                    'c' => 0,
+                    'd' => 2,
+                    'e' => 4,
+                    'f' => 5,
+                    'g' => 7,
+                    'a' => 9,
+                    _ => 11,
+                };
+                loop {
+                    match cursor.peek_char() {
+                        Some('+') | Some('#') => semitone += 1,
+                        Some('-') => semitone -= 1,
+                        _ => break,
+                    }
+                    cursor.next_char();
+                }
+                let mut lengths = vec![read_length(cursor)?];
+                let mut tie = false;
+                loop {
+                    cursor.skip_whitespace();
+                    if cursor.peek_char() != Some('&') {
+                        break;
+                    }
+                    cursor.next_char();
+                    cursor.skip_whitespace();
+                    if cursor.peek_char().map_or(false, |c| c.is_digit(10)) {
+                        lengths.push(read_length(cursor)?);
+                    } else {
+                        tie = true;
+                        break;
+                    }
+                }
+                Command::Note {
+                    semitone,
+                    lengths,
+                    tie,
+                    line,
+                    column,
+                }
+            }
+            'r' => Command::Rest(read_length(cursor)?),
+            'o' => {
+                let octave = read_required_number(cursor)?;
+                if octave > MAX_OCTAVE {
+                    return Err(ParseError {
+                        line,
+                        column,
+                        reason: "Octave is above 9",
+                    }
+                    .into());
+                }
+                Command::Octave(octave as i32)
+            }
+            '<' => Command::OctaveDown,
+            '>' => Command::OctaveUp,
+            'l' => {
+                if !cursor.peek_char().map_or(false, |c| c.is_digit(10)) {
+                    return Err(cursor.error("Expected a number").into());
+                }
+                Command::DefaultLength(read_length(cursor)?)
+            }
+            't' => {
+                let tempo = read_required_number(cursor)?;
+                if (tempo == 0) | (tempo > MAX_TEMPO) {
+                    return Err(ParseError {
+                        line,
+                        column,
+                        reason: "Tempo has to be between 1 and 1000",
+                    }
+                    .into());
+                }
+                Command::Tempo(tempo)
+            }
+            'v' => {
+                let volume = read_required_number(cursor)?;
+                if volume > MAX_VOLUME {
+                    return Err(ParseError {
+                        line,
+                        column,
+                        reason: "Volume is above 15",
+                    }
+                    .into());
+                }
+                Command::Volume(volume)
+            }
+            '@' => Command::Instrument(read_required_number(cursor)? as usize),
+            '[' => {
+                let commands = parse_commands(cursor, Some((line, column)))?;
+                let count = cursor.read_number().unwrap_or(DEFAULT_LOOP_COUNT);
+                Command::Loop {
+                    commands,
+                    count,
+                    line,
+                    column,
+                }
+            }
+            ']' => match loop_start {
+                Some(_) => return Ok(commands),
+                None => {
+                    return Err(ParseError {
+                        line,
+                        column,
+                        reason: "No loop to close",
+                    }
+                    .into())
+                }
+            },
+            _ => {
+                return Err(ParseError {
+                    line,
+                    column,
+                    reason: "Unknown command",
+                }
+                .into())
+            }
+        };
+        commands.push(command);
+    }
+}
+
+/// Reads an optional length followed by optional dots
+fn read_length(cursor: &mut TextCursor) -> Result<Length> {
+    let error = cursor.error("Length has to be between 1 and 1920");
+    let number = cursor.read_number();
+    if let Some(n) = number {
+        if (n == 0) | (n > WHOLE_NOTE_TICKS) {
+            return Err(error.into());
+        }
+    }
+    let mut dots = 0;
+    while cursor.peek_char() == Some('.') {
+        cursor.next_char();
+        dots += 1;
+    }
+    Ok(Length { number, dots })
+}
+
+/// Reads a number that has to be there
+fn read_required_number(cursor: &mut TextCursor) -> Result<u32> {
+    let error = cursor.error("Expected a number");
+    Ok(cursor.read_number().ok_or(error)?)
+}
+
+/// Converts a length to ticks
+/// # Arguments
+/// * length - The length as written
+/// * default - The default length in ticks, used if the length has no number
+fn get_ticks(length: Length, default: u32) -> u32 {
+    let base = length.number.map_or(default, |n| WHOLE_NOTE_TICKS / n);
+    let mut ticks = base;
+    let mut added = base;
+    for _ in 0..length.dots {
+        added /= 2;
+        ticks += added;
+    }
+    ticks
+}
+
+/// Plays commands on a channel, placing its notes and the tempo changes
+fn play_commands(
+    commands: &[Command],
+    state: &mut ChannelState,
+    tempo_map: &mut TempoMap,
+) -> Result<()> {
+    for command in commands {
+        state.played += 1;
+        match *command {
+            Command::Note {
+                semitone,
+                ref lengths,
+                tie,
+                line,
+                column,
+            } => {
+                // Going up or down octaves many times can take the octave anywhere
+                let key = state
+                    .octave
+                    .checked_add(1)
+                    .and_then(|octave| octave.checked_mul(12))
+                    .and_then(|key| key.checked_add(semitone))
+                    .unwrap_or(-1);
+                if (key < 0) | (key > 127) {
+                    return Err(ParseError {
+                        line,
+                        column,
+                        reason: "Note is out of the MIDI range",
+                    }
+                    .into());
+                }
+                let key = key as usize;
+                let ticks = lengths
+                    .iter()
+                    .map(|&length| u64::from(get_ticks(length, state.length)))
+                    .sum::<u64>();
+                let tied = state.tie
+                    && state
+                        .notes
+                        .last()
+                        .map_or(false, |n| (n.key == key) & (n.end == state.tick));
+                if tied {
+                    if let Some(note) = state.notes.last_mut() {
+                        note.end += ticks;
+                    }
+                } else {
+                    state.notes.push(PlacedNote {
+                        start: state.tick,
+                        end: state.tick + ticks,
+                        key,
+                        volume: state.volume,
+                        i_id: state.i_id,
+                    });
+                }
+                state.tick += ticks;
+                state.tie = tie;
+            }
+            Command::Rest(length) => {
+                state.tick += u64::from(get_ticks(length, state.length));
+                state.tie = false;
+            }
+            Command::Octave(octave) => state.octave = octave,
+            Command::OctaveUp => state.octave = state.octave.saturating_add(1),
+            Command::OctaveDown => state.octave = state.octave.saturating_sub(1),
+            Command::DefaultLength(length) => state.length = get_ticks(length, state.length),
+            Command::Tempo(tempo) => tempo_map.set_tempo(state.tick, 60_000_000 / tempo),
+            Command::Volume(volume) => state.volume = volume,
+            Command::Instrument(i_id) => state.i_id = i_id,
+            Command::Loop {
+                ref commands,
+                count,
+                line,
+                column,
+            } => {
+                for _ in 0..count {
+                    state.played += 1;
+                    if state.played > MAX_PLAYED_COMMANDS {
+                        return Err(ParseError {
+                            line,
+                            column,
+                            reason: "Loop plays too many commands",
+                        }
+                        .into());
+                    }
+                    play_commands(commands, state, tempo_map)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use error::SynthesizerError;
+
+    /// Instrument ID, start, end and Frequency ID of every note, sorted
+    fn get_notes(sequence: &Sequence) -> Vec<(usize, f64, f64, usize)> {
+        let mut notes: Vec<(usize, f64, f64, usize)> = sequence
+            .notes
+            .iter()
+            .map(|note| {
+                (
+                    note.i_id,
+                    note.t_span.start_at().get(),
+                    note.t_span.end_at().get(),
+                    note.f_id,
+                )
+            })
+            .collect();
+        notes.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        notes
+    }
+
+    /// Line, column and reason of the error of an invalid text
+    fn get_error(text: &str) -> (usize, usize, &'static str) {
+        match parse(text, 1) {
+            Err(SynthesizerError::Parse(error)) => (error.line, error.column, error.reason),
+            _ => panic!("Expected a parse error for {:?}", text),
+        }
+    }
+
+    #[test]
+    fn notes_and_loops() {
+        let sequence = parse("t120 l8 c d4 [e]3; @5 >c&c r c", 2).unwrap();
+        assert_eq!(
+            get_notes(&sequence),
+            vec![
+                (0, 0f64, 0.25, 60),
+                (0, 0.25, 0.75, 62),
+                (0, 0.75, 1f64, 64),
+                (0, 1f64, 1.25, 64),
+                (0, 1.25, 1.5, 64),
+                (5, 0f64, 1f64, 72),
+                (5, 1.5, 2f64, 72),
+            ]
+        );
+        assert!(sequence.notes.iter().all(|note| note.vol.len() == 2));
+    }
+
+    #[test]
+    fn error_positions() {
+        assert_eq!(get_error("c d\n  o10 c"), (2, 3, "Octave is above 9"));
+        assert_eq!(get_error("c [d e"), (1, 3, "Loop is never closed"));
+        assert_eq!(
+            get_error("c\nd t2000"),
+            (2, 3, "Tempo has to be between 1 and 1000")
+        );
+        assert_eq!(
+            get_error("[[[c]1000]1000]1000"),
+            (1, 3, "Loop plays too many commands")
+        );
+    }
+}
@@ -0,0 +1,692 @@
+use error::ParseError;
+use helper::SequenceHelper;
+use midi::{Division, TempoMap};
+use sequence::Sequence;
+use std::collections::HashMap;
+use util::{TextCursor, Time, Volume};
+use Result;
+
+/// Number of ticks in a quarter note when placing notes
+pub const TICKS_PER_QUARTER_NOTE: u16 = 3840;
+/// MIDI velocity of notes before any dynamics, the one of mezzo-forte
+pub const DEFAULT_VELOCITY: f64 = 80f64;
+/// MIDI velocity meant by a dynamics percentage of 100, the one of forte
+pub const FORTE_VELOCITY: f64 = 90f64;
+
+/// Deepest nesting of elements that is read, MusicXML documents do not go past a dozen
+const MAX_DEPTH: usize = 100;
+
+/// MIDI velocities of the dynamics marks
+const DYNAMICS: [(&str, f64); 23] = [
+    ("pppppp", 1f64),
+    ("ppppp", 5f64),
+    ("pppp", 10f64),
+    ("ppp", 16f64),
+    ("pp", 33f64),
+    ("p", 49f64),
+    ("mp", 64f64),
+    ("mf", 80f64),
+    ("f", 96f64),
+    ("ff", 112f64),
+    ("fff", 126f64),
+    ("ffff", 127f64),
+    ("fffff", 127f64),
+    ("ffffff", 127f64),
+    ("fp", 96f64),
+    ("pf", 49f64),
+    ("sf", 112f64),
+    ("sfp", 112f64),
+    ("sfpp", 112f64),
+    ("sfz", 112f64),
+    ("sffz", 126f64),
+    ("fz", 112f64),
+    ("rfz", 112f64),
+];
+
+/// An element of an XML document
+struct Element {
+    name: String,
+    attributes: Vec<(String, String)>,
+    children: Vec<Element>,
+    /// Text directly inside the element
+    text: String,
+    line: usize,
+    column: usize,
+}
+
+/// What is known about a part while its measures are read
+struct PartState {
+    /// Divisions of a quarter note used by durations
+    divisions: f64,
+    /// Current MIDI velocity from the dynamics
+    velocity: f64,
+    /// Position in quarter notes
+    position: f64,
+    /// Start of the previous note, where chord notes start
+    last_start: f64,
+    /// Index of the notes tied to the next note of the same key
+    tied: HashMap<usize, usize>,
+}
+
+/// A note placed in quarter notes
+struct PlacedNote {
+    start: f64,
+    end: f64,
+    key: usize,
+    velocity: f64,
+    i_id: usize,
+}
+
+/// Builds a Sequence from an uncompressed MusicXML score, partwise or timewise.
+/// Every part uses its position in the part list (starting from 0) as the Instrument ID.
+/// The Frequency IDs are MIDI note numbers, usable with MIDIFrequencyLookup.
+/// Supports divisions, tempo directions, ties, chords, backups and forwards, and dynamics that become the Volume of the notes.
+/// Repeats are played as written and grace notes are ignored.
+/// # Arguments
+/// * text - The MusicXML document
+/// * nb_channels - The number of channels of the final output, every note gets its volume on all of them
+pub fn parse(text: &str, nb_channels: usize) -> Result<Sequence> {
+    let root = read_document(text)?;
+    let part_ids = root
+        .child("part-list")
+        .ok_or_else(|| root.error("Score has no part list"))?
+        .children_named("score-part")
+        .map(|score_part| score_part.attribute("id").unwrap_or("").to_string())
+        .collect::<Vec<String>>();
+    // Measures of every part in order
+    let mut measures: Vec<Vec<&Element>> = vec![Vec::new(); part_ids.len()];
+    let find_part = |part: &Element| -> Result<usize> {
+        let id = part.attribute("id").unwrap_or("");
+        Ok(part_ids
+            .iter()
+            .position(|part_id| part_id == id)
+            .ok_or_else(|| part.error("Part is not in the part list"))?)
+    };
+    match root.name.as_str() {
+        "score-partwise" => {
+            for part in root.children_named("part") {
+                let index = find_part(part)?;
+                measures[index].extend(part.children_named("measure"));
+            }
+        }
+        "score-timewise" => {
+            for measure in root.children_named("measure") {
+                for part in measure.children_named("part") {
+                    let index = find_part(part)?;
+                    measures[index].push(part);
+                }
+            }
+        }
+        _ => return Err(root.error("Not a MusicXML score").into()),
+    }
+    // Tempo changes as their position in quarter notes and their tempo in quarter notes per minute
+    let mut tempos = Vec::new();
+    let mut notes = Vec::new();
+    for (i_id, part_measures) in measures.iter().enumerate() {
+        let mut state = PartState {
+            divisions: 1f64,
+            velocity: DEFAULT_VELOCITY,
+            position: 0f64,
+            last_start: 0f64,
+            tied: HashMap::new(),
+        };
+        for measure in part_measures {
+            read_measure(measure, i_id, &mut state, &mut notes, &mut tempos)?;
+        }
+    }
+    let mut tempo_map = TempoMap::new(Division::TicksPerQuarterNote(TICKS_PER_QUARTER_NOTE));
+    let to_tick = |position: f64| (position * f64::from(TICKS_PER_QUARTER_NOTE)).round() as u64;
+    for (position, tempo) in tempos {
+        tempo_map.set_tempo(to_tick(position), (60_000_000f64 / tempo).round() as u32);
+    }
+    let mut helper = SequenceHelper::new();
+    helper.set_tempo_map(tempo_map.clone());
+    notes.sort_by_key(|note| to_tick(note.start));
+    for note in notes {
+        let (start, end) = (to_tick(note.start), to_tick(note.end));
+        if end <= start {
+            continue;
+        }
+        let at_tick = helper.at_tick;
+        helper.tick_forward(start - at_tick)?;
+        let duration = Time::new(tempo_map.get_seconds(end) - tempo_map.get_seconds(start))?;
+        let volume = Volume::new((note.velocity / 127f64).min(1f64).max(0f64))?;
+        helper.new_note(note.key, note.i_id, duration, vec![volume; nb_channels])?;
+    }
+    Ok(helper.sequence)
+}
+
+/// Reads the content of a measure of a part
+fn read_measure(
+    measure: &Element,
+    i_id: usize,
+    state: &mut PartState,
+    notes: &mut Vec<PlacedNote>,
+    tempos: &mut Vec<(f64, f64)>,
+) -> Result<()> {
+    let measure_start = state.position;
+    let mut measure_end = state.position;
+    for element in &measure.children {
+        match element.name.as_str() {
+            "attributes" => {
+                if let Some(divisions) = element.child("divisions") {
+                    state.divisions = divisions.get_number()?;
+                    if state.divisions <= 0f64 {
+                        return Err(divisions.error("Divisions have to be positive").into());
+                    }
+                }
+            }
+            "note" => read_note(element, i_id, state, notes)?,
+            "backup" => {
+                let duration = element.get_child_number("duration")?;
+                state.position = (state.position - duration / state.divisions).max(measure_start);
+            }
+            "forward" => state.position += element.get_child_number("duration")? / state.divisions,
+            "direction" => {
+                for direction_type in element.children_named("direction-type") {
+                    for dynamics in direction_type.children_named("dynamics") {
+                        for mark in &dynamics.children {
+                            if let Some(&(_, velocity)) =
+                                DYNAMICS.iter().find(|&&(name, _)| name == mark.name)
+                            {
+                                state.velocity = velocity;
+                            }
+                        }
+                    }
+                }
+                let sound = element.child("sound");
+                if let Some(sound) = sound {
+                    read_sound(sound, state, tempos)?;
+                }
+                // The metronome mark is only used if no sound gives the tempo
+                if sound.and_then(|s| s.attribute("tempo")).is_none() {
+                    if let Some(tempo) = element
+                        .children_named("direction-type")
+                        .filter_map(|d| d.child("metronome"))
+                        .filter_map(read_metronome)
+                        .next()
+                    {
+                        tempos.push((state.position, tempo));
+                    }
+                }
+            }
+            "sound" => read_sound(element, state, tempos)?,
+            _ => {}
+        }
+        measure_end = measure_end.max(state.position);
+    }
+    state.position = measure_end;
+    Ok(())
+}
+
+/// Reads a note or rest and places it
+fn read_note(
+    note: &Element,
+    i_id: usize,
+    state: &mut PartState,
+    notes: &mut Vec<PlacedNote>,
+) -> Result<()> {
+    if note.child("grace").is_some() {
+        return Ok(());
+    }
+    let duration = note.get_child_number("duration")? / state.divisions;
+    let start = if note.child("chord").is_some() {
+        state.last_start
+    } else {
+        let start = state.position;
+        state.position += duration;
+        start
+    };
+    state.last_start = start;
+    let (step, alter, octave) = if let Some(pitch) = note.child("pitch") {
+        (
+            pitch.child("step"),
+            pitch.child("alter"),
+            pitch.child("octave"),
+        )
+    } else if let Some(unpitched) = note.child("unpitched") {
+        (
+            unpitched.child("display-step"),
+            None,
+            unpitched.child("display-octave"),
+        )
+    } else {
+        return Ok(()); // Rest
+    };
+    let step = step.ok_or_else(|| note.error("Note has no step"))?;
+    let semitone = match step.text.trim() {
+        "C" => 0,
+        "D" => 2,
+        "E" => 4,
+        "F" => 5,
+        "G" => 7,
+        "A" => 9,
+        "B" => 11,
+        _ => return Err(step.error("Invalid step").into()),
+    };
+    let alter = match alter {
+        Some(alter) => alter.get_number()?.round(),
+        None => 0f64,
+    };
+    let octave = octave
+        .ok_or_else(|| note.error("Note has no octave"))?
+        .get_number()?
+        .trunc();
+    // Computed as a float so that huge octaves cannot overflow
+    let key = (octave + 1f64) * 12f64 + f64::from(semitone) + alter;
+    if (key < 0f64) | (key > 127f64) {
+        return Err(note.error("Note is out of the MIDI range").into());
+    }
+    let key = key as usize;
+    let velocity = match note.attribute("dynamics") {
+        Some(dynamics) => {
+            dynamics
+                .trim()
+                .parse::<f64>()
+                .map_err(|_| note.error("Invalid dynamics"))?
+                * FORTE_VELOCITY
+                / 100f64
+        }
+        None => state.velocity,
+    };
+    let end = start + duration;
+    let ties = note
+        .children_named("tie")
+        .filter_map(|tie| tie.attribute("type"))
+        .collect::<Vec<&str>>();
+    let continued = match state.tied.get(&key) {
+        Some(&index) if ties.contains(&"stop") && (notes[index].end - start).abs() < 1e-9 => {
+            notes[index].end = end;
+            Some(index)
+        }
+        _ => None,
+    };
+    let index = continued.unwrap_or_else(|| {
+        notes.push(PlacedNote {
+            start,
+            end,
+            key,
+            velocity,
+            i_id,
+        });
+        notes.len() - 1
+    });
+    if ties.contains(&"start") {
+        state.tied.insert(key, index);
+    } else {
+        state.tied.remove(&key);
+    }
+    Ok(())
+}
+
+/// Reads the tempo and dynamics of a sound element
+fn read_sound(sound: &Element, state: &mut PartState, tempos: &mut Vec<(f64, f64)>) -> Result<()> {
+    if let Some(tempo) = sound.attribute("tempo") {
+        let tempo = tempo
+            .trim()
+            .parse::<f64>()
+            .map_err(|_| sound.error("Invalid tempo"))?;
+        if tempo > 0f64 {
+            tempos.push((state.position, tempo));
+        }
+    }
+    if let Some(dynamics) = sound.attribute("dynamics") {
+        state.velocity = dynamics
+            .trim()
+            .parse::<f64>()
+            .map_err(|_| sound.error("Invalid dynamics"))?
+            * FORTE_VELOCITY
+            / 100f64;
+    }
+    Ok(())
+}
+
+/// Reads a metronome mark, returns its tempo in quarter notes per minute
+fn read_metronome(metronome: &Element) -> Option<f64> {
+    let quarters = match metronome.child("beat-unit")?.text.trim() {
+        "long" => 16f64,
+        "breve" => 8f64,
+        "whole" => 4f64,
+        "half" => 2f64,
+        "quarter" => 1f64,
+        "eighth" => 0.5f64,
+        "16th" => 0.25f64,
+        "32nd" => 0.125f64,
+        _ => return None,
+    };
+    let dots = metronome.children_named("beat-unit-dot").count() as i32;
+    let quarters = quarters * (2f64 - 0.5f64.powi(dots));
+    let per_minute = metronome
+        .child("per-minute")?
+        .text
+        .trim()
+        .parse::<f64>()
+        .ok()?;
+    if per_minute > 0f64 {
+        Some(per_minute * quarters)
+    } else {
+        None
+    }
+}
+
+impl Element {
+    /// Returns the first child with a name
+    fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|child| child.name == name)
+    }
+    /// Returns all children with a name
+    fn children_named<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Element> + 'a {
+        self.children.iter().filter(move |child| child.name == name)
+    }
+    /// Returns the value of an attribute
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|&&(ref attribute, _)| attribute == name)
+            .map(|&(_, ref value)| value.as_str())
+    }
+    /// Reads the text of the element as a number
+    fn get_number(&self) -> Result<f64> {
+        match self.text.trim().parse::<f64>() {
+            Ok(value) if value.is_finite() => Ok(value),
+            _ => Err(self.error("Expected a number").into()),
+        }
+    }
+    /// Reads the text of a child as a number
+    fn get_child_number(&self, name: &'static str) -> Result<f64> {
+        self.child(name)
+            .ok_or_else(|| self.error("Missing a number"))?
+            .get_number()
+    }
+    /// Creates an error at the position of the element
+    fn error(&self, reason: &'static str) -> ParseError {
+        ParseError {
+            line: self.line,
+            column: self.column,
+            reason,
+        }
+    }
+}
+
+/// Parses an XML document, returns its root element
+fn read_document(text: &str) -> Result<Element> {
+    let mut cursor = TextCursor::new(text);
+    loop {
+        cursor.skip_whitespace();
+        if cursor.starts_with("<?") {
+            skip_past(&mut cursor, "?>")?;
+        } else if cursor.starts_with("<!--") {
+            skip_past(&mut cursor, "-->")?;
+        } else if cursor.starts_with("<!") {
+            // Document type, its internal subset can contain other `>`
+            let mut depth = 0;
+            loop {
+                match cursor.next_char() {
+                    Some('[') => depth += 1,
+                    Some(']') => depth -= 1,
+                    Some('>') if depth == 0 => break,
+                    Some(_) => {}
+                    None => return Err(cursor.error("Unexpected end of the document").into()),
+                }
+            }
+        } else {
+            return read_element(&mut cursor, 0);
+        }
+    }
+}
+
+/// Parses an element and everything inside it
+/// # Arguments
+/// * cursor - Placed on the start of the element
+/// * depth - Number of elements the element is in
+fn read_element(cursor: &mut TextCursor, depth: usize) -> Result<Element> {
+    let (line, column) = (cursor.line, cursor.column);
+    if depth > MAX_DEPTH {
+        return Err(ParseError {
+            line,
+            column,
+            reason: "Elements are nested too deeply",
+        }
+        .into());
+    }
+    if cursor.next_char() != Some('<') {
+        return Err(ParseError {
+            line,
+            column,
+            reason: "Expected an element",
+        }
+        .into());
+    }
+    let mut element = Element {
+        name: read_name(cursor)?,
+        attributes: Vec::new(),
+        children: Vec::new(),
+        text: String::new(),
+        line,
+        column,
+    };
+    loop {
+        cursor.skip_whitespace();
+        match cursor.peek_char() {
+            Some('/') => {
+                cursor.next_char();
+                if cursor.next_char() != Some('>') {
+                    return Err(cursor.error("Expected the end of the tag").into());
+                }
+                return Ok(element);
+            }
+            Some('>') => {
+                cursor.next_char();
+                break;
+            }
+            Some(_) => {
+                let name = read_name(cursor)?;
+                cursor.skip_whitespace();
+                if cursor.next_char() != Some('=') {
+                    return Err(cursor.error("Expected an attribute value").into());
+                }
+                cursor.skip_whitespace();
+                let quote = match cursor.next_char() {
+                    Some(q) if (q == '"') | (q == '\'') => q,
+                    _ => return Err(cursor.error("Expected a quote").into()),
+                };
+                let mut value = String::new();
+                loop {
+                    match cursor.peek_char() {
+                        Some(c) if c == quote => {
+                            cursor.next_char();
+                            break;
+                        }
+                        Some('&') => value.push(read_entity(cursor)?),
+                        Some(c) => {
+                            cursor.next_char();
+                            value.push(c);
+                        }
+                        None => return Err(cursor.error("Unexpected end of the document").into()),
+                    }
+                }
+                element.attributes.push((name, value));
+            }
+            None => return Err(cursor.error("Unexpected end of the document").into()),
+        }
+    }
+    // Content of the element
+    loop {
+        if cursor.starts_with("</") {
+            cursor.next_char();
+            cursor.next_char();
+            let name = read_name(cursor)?;
+            if name != element.name {
+                return Err(cursor.error("Closing tag does not match").into());
+            }
+            cursor.skip_whitespace();
+            if cursor.next_char() != Some('>') {
+                return Err(cursor.error("Expected the end of the tag").into());
+            }
+            return Ok(element);
+        } else if cursor.starts_with("<!--") {
+            skip_past(cursor, "-->")?;
+        } else if cursor.starts_with("<![CDATA[") {
+            for _ in 0..9 {
+                cursor.next_char();
+            }
+            while !cursor.starts_with("]]>") {
+                match cursor.next_char() {
+                    Some(c) => element.text.push(c),
+                    None => return Err(cursor.error("Unexpected end of the document").into()),
+                }
+            }
+            skip_past(cursor, "]]>")?;
+        } else if cursor.starts_with("<?") {
+            skip_past(cursor, "?>")?;
+        } else {
+            match cursor.peek_char() {
+                Some('<') => element.children.push(read_element(cursor, depth + 1)?),
+                Some('&') => element.text.push(read_entity(cursor)?),
+                Some(c) => {
+                    cursor.next_char();
+                    element.text.push(c);
+                }
+                None => return Err(cursor.error("Unexpected end of the document").into()),
+            }
+        }
+    }
+}
+
+/// Reads the name of an element or attribute
+fn read_name(cursor: &mut TextCursor) -> Result<String> {
+    let mut name = String::new();
+    while let Some(c) = cursor.peek_char() {
+        if c.is_whitespace() | (c == '/') | (c == '>') | (c == '=') {
+            break;
+        }
+        name.push(c);
+        cursor.next_char();
+    }
+    if name.is_empty() {
+        return Err(cursor.error("Expected a name").into());
+    }
+    Ok(name)
+}
+
+/// Reads an entity like `&amp;` or `&#233;` and returns its character
+fn read_entity(cursor: &mut TextCursor) -> Result<char> {
+    let error = cursor.error("Unknown entity");
+    cursor.next_char();
+    let mut name = String::new();
+    loop {
+        match cursor.next_char() {
+            Some(';') => break,
+            Some(c) if name.len() < 10 => name.push(c),
+            _ => return Err(error.into()),
+        }
+    }
+    let c = match name.as_str() {
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "amp" => Some('&'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        _ if name.starts_with("#x") => u32::from_str_radix(&name[2..], 16)
+            .ok()
+            .and_then(::std::char::from_u32),
+        _ if name.starts_with('#') => name[1..]
+            .parse::<u32>()
+            .ok()
+            .and_then(::std::char::from_u32),
+        _ => None,
+    };
+    Ok(c.ok_or(error)?)
+}
+
+/// Moves after the next occurrence of some text
+fn skip_past(cursor: &mut TextCursor, end: &str) -> Result<()> {
+    while !cursor.starts_with(end) {
+        if cursor.next_char().is_none() {
+            return Err(cursor.error("Unexpected end of the document").into());
+        }
+    }
+    for _ in end.chars() {
+        cursor.next_char();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use error::SynthesizerError;
+
+    /// Wraps measures into a single part score
+    fn score(measures: &str) -> String {
+        format!(
+            "<?xml version=\"1.0\"?>\n<score-partwise>\n<part-list><score-part id=\"P1\"/></part-list>\n<part id=\"P1\">{}</part>\n</score-partwise>\n",
+            measures
+        )
+    }
+
+    /// Start, end and Frequency ID of every note, sorted
+    fn get_notes(sequence: &Sequence) -> Vec<(f64, f64, usize)> {
+        let mut notes: Vec<(f64, f64, usize)> = sequence
+            .notes
+            .iter()
+            .map(|note| {
+                (
+                    note.t_span.start_at().get(),
+                    note.t_span.end_at().get(),
+                    note.f_id,
+                )
+            })
+            .collect();
+        notes.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        notes
+    }
+
+    /// Reason of the error of an invalid document
+    fn get_reason(text: &str) -> &'static str {
+        match parse(text, 1) {
+            Err(SynthesizerError::Parse(error)) => error.reason,
+            _ => panic!("Expected a parse error"),
+        }
+    }
+
+    #[test]
+    fn ties_and_backup() {
+        let text = score(
+            "<measure number=\"1\">
+                <attributes><divisions>2</divisions></attributes>
+                <sound tempo=\"120\"/>
+                <note><pitch><step>C</step><octave>4</octave></pitch><duration>2</duration><tie type=\"start\"/></note>
+                <note><pitch><step>C</step><octave>4</octave></pitch><duration>2</duration><tie type=\"stop\"/><tie type=\"start\"/></note>
+                <backup><duration>4</duration></backup>
+                <note><pitch><step>G</step><octave>3</octave></pitch><duration>2</duration></note>
+                <note><rest/><duration>2</duration></note>
+            </measure>
+            <measure number=\"2\">
+                <note><pitch><step>C</step><octave>4</octave></pitch><duration>2</duration><tie type=\"stop\"/></note>
+                <note><pitch><step>D</step><alter>-1</alter><octave>4</octave></pitch><duration>2</duration></note>
+            </measure>",
+        );
+        let sequence = parse(&text, 2).unwrap();
+        assert_eq!(
+            get_notes(&sequence),
+            vec![(0f64, 0.5, 55), (0f64, 1.5, 60), (1.5, 2f64, 61)]
+        );
+    }
+
+    #[test]
+    fn invalid_documents() {
+        let text = score(
+            "<measure number=\"1\"><note><pitch><step>C</step><octave>3000000000</octave></pitch><duration>1</duration></note></measure>",
+        );
+        assert_eq!(get_reason(&text), "Note is out of the MIDI range");
+        // The root element is at depth 0
+        match parse(&"<a>".repeat(MAX_DEPTH + 2), 1) {
+            Err(SynthesizerError::Parse(error)) => {
+                assert_eq!(error.reason, "Elements are nested too deeply");
+                assert_eq!((error.line, error.column), (1, 3 * MAX_DEPTH + 4));
+            }
+            _ => panic!("Expected a parse error"),
+        }
+    }
+}
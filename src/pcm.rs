@@ -22,6 +22,27 @@ pub struct PCMParameters {
     pub sample_rate: u32,
     /// How many channels
     pub nb_channels: u16,
+    /// Which speaker each channel is meant for
+    pub speaker_layout: SpeakerLayout,
+}
+
+/// Describes which speaker each channel of a stream is meant for, in the order of the channels
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SpeakerLayout {
+    /// No information about the speakers
+    Unspecified,
+    /// Front Center
+    Mono,
+    /// Front Left, Front Right
+    Stereo,
+    /// Front Left, Front Right, Back Left, Back Right
+    Quad,
+    /// Front Left, Front Right, Front Center, Low Frequency, Back Left, Back Right
+    Surround51,
+    /// Front Left, Front Right, Front Center, Low Frequency, Back Left, Back Right, Side Left, Side Right
+    Surround71,
+    /// Any combination of speakers, described by a WAVE_FORMAT_EXTENSIBLE channel mask
+    Custom(u32),
 }
 
 impl PCM {
@@ -33,7 +54,7 @@ impl PCM {
                 Some(e) => {
                     change = sample.abs() > e;
                 }
-                None => extreme = Some(*sample),
+                None => extreme = Some(sample.abs()),
             }
             if change {
                 extreme = Some(sample.abs());
@@ -42,3 +63,36 @@ impl PCM {
         extreme.ok_or(NoSamplesError {})
     }
 }
+
+impl SpeakerLayout {
+    /// Get the WAVE_FORMAT_EXTENSIBLE channel mask for this layout. It is 0 when unspecified.
+    pub fn get_channel_mask(&self) -> u32 {
+        match *self {
+            SpeakerLayout::Unspecified => 0,
+            SpeakerLayout::Mono => 0x4,
+            SpeakerLayout::Stereo => 0x3,
+            SpeakerLayout::Quad => 0x33,
+            SpeakerLayout::Surround51 => 0x3F,
+            SpeakerLayout::Surround71 => 0x63F,
+            SpeakerLayout::Custom(mask) => mask,
+        }
+    }
+    /// Finds the layout corresponding to a WAVE_FORMAT_EXTENSIBLE channel mask
+    pub fn from_channel_mask(mask: u32) -> SpeakerLayout {
+        match mask {
+            0 => SpeakerLayout::Unspecified,
+            0x4 => SpeakerLayout::Mono,
+            0x3 => SpeakerLayout::Stereo,
+            0x33 => SpeakerLayout::Quad,
+            0x3F => SpeakerLayout::Surround51,
+            0x63F => SpeakerLayout::Surround71,
+            _ => SpeakerLayout::Custom(mask),
+        }
+    }
+}
+
+impl Default for SpeakerLayout {
+    fn default() -> SpeakerLayout {
+        SpeakerLayout::Unspecified
+    }
+}
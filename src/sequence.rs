@@ -2,7 +2,7 @@ use error::EmptySequenceError;
 use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::result::Result;
-use util::{Duration, TimeSpan, Volume};
+use util::{Duration, Time, TimeSpan, Volume};
 
 /// Represents a Sequence of notes forming music. Think of it as a music sheet
 #[derive(Clone, Default)]
@@ -11,6 +11,17 @@ pub struct Sequence {
     pub notes: Vec<Note>,
     /// The optional Looping information about the music
     pub loop_info: Vec<TimeSpan>,
+    /// Named points in time, for example to mark the sections of the music
+    pub markers: Vec<Marker>,
+}
+
+/// A named point in time
+#[derive(Clone)]
+pub struct Marker {
+    /// Where the marker is placed
+    pub at: Time,
+    /// Text describing the marker, can be empty
+    pub label: String,
 }
 
 /// A single note played by a single instrument a a certain point in time. It is part of a Sequence
@@ -24,6 +35,30 @@ pub struct Note {
     pub f_id: usize,
     /// Specifies which instrument to use when playing this note
     pub i_id: usize,
+    /// Changes of pitch while the note plays, sorted by time. Empty if the pitch never changes.
+    pub pitch: Vec<PitchPoint>,
+    /// Changes of loudness while the note plays, sorted by time. Empty if the loudness never changes.
+    pub gain: Vec<GainPoint>,
+    /// How hard the note is played, used to choose between velocity layers. None if unknown, the loudest volume being used instead.
+    pub velocity: Option<Volume>,
+}
+
+/// A change of pitch during a Note. The offset holds until the next change.
+#[derive(Clone, Copy)]
+pub struct PitchPoint {
+    /// When the change happens, relative to the start of the Note
+    pub at: Time,
+    /// Offset from the Frequency of the Note, in cents
+    pub cents: f64,
+}
+
+/// A change of loudness during a Note. The factor holds until the next change.
+#[derive(Clone, Copy)]
+pub struct GainPoint {
+    /// When the change happens, relative to the start of the Note
+    pub at: Time,
+    /// Factor applied to the volumes of the Note, in [0; 1]
+    pub factor: f64,
 }
 
 impl Sequence {
@@ -32,6 +67,7 @@ impl Sequence {
         Sequence {
             notes: Vec::new(),
             loop_info: Vec::new(),
+            markers: Vec::new(),
         }
     }
     /// Adds a Note to the Sequence
@@ -62,20 +98,22 @@ impl Sequence {
         }
     }
     /// Generates a map of every frequency used by every instrument in the Sequence.
-    /// The HashMap key is the Instrument ID, the Tuple inside the Vec contains the Frequency ID and the Duration indicating how long it is going to play for
+    /// The HashMap key is the Instrument ID, the Tuple inside the Vec contains the Frequency ID and the Duration of the Key needed to play it.
+    /// Notes going up in pitch read their Key faster, so they need a Key longer than themselves.
     pub fn list_freq_by_inst(&self) -> HashMap<usize, Vec<(usize, Duration)>> {
         let mut list = HashMap::new();
         for note in &self.notes {
             let inst_map = list.entry(note.i_id).or_insert_with(Vec::new);
+            let duration = note.get_key_duration();
             match inst_map
                 .iter()
                 .position(|x: &(usize, Duration)| x.0 == note.f_id)
             {
-                None => inst_map.push((note.f_id, note.t_span.duration())),
+                None => inst_map.push((note.f_id, duration)),
                 Some(id) => {
                     let ft = &mut inst_map[id]; // Should not fail
-                    if let Ordering::Greater = note.t_span.duration().compare(ft.1) {
-                        ft.1 = note.t_span.duration()
+                    if let Ordering::Greater = duration.compare(ft.1) {
+                        ft.1 = duration
                     }
                 }
             }
@@ -85,6 +123,25 @@ impl Sequence {
 }
 
 impl Note {
+    /// Returns how much of its Key the Note reads, at the speed of its highest pitch
+    pub fn get_key_duration(&self) -> Duration {
+        let duration = self.t_span.duration();
+        let highest_ratio = self
+            .pitch
+            .iter()
+            .map(|point| 2f64.powf(point.cents / 1200f64))
+            .fold(1f64, f64::max);
+        Duration::new(duration.get() * highest_ratio).unwrap_or(duration)
+    }
+    /// Returns how hard the note is played, in [0; 1]
+    pub fn get_velocity(&self) -> f64 {
+        match self.velocity {
+            Some(velocity) => velocity.get(),
+            // No volumes means full volume, like get_volume
+            None if self.vol.is_empty() => 1f64,
+            None => self.vol.iter().fold(0f64, |max, vol| max.max(vol.get())),
+        }
+    }
     /// Returns the volume for a defined number of channels
     pub fn get_volume(&self, nb_channels: usize) -> Vec<f64> {
         if self.vol.len() == nb_channels {
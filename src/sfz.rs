@@ -0,0 +1,575 @@
+use error::{ParseError, ReadError};
+use instrument::{Envelope, EnvelopeCurve, Instrument, Zone};
+use key_generator::SampleKeyGenerator;
+use pcm::PCM;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::result::Result;
+use util::{Frequency, TextCursor, Time, TimeSpan};
+use wave::Wave;
+use Result as SynthesizerResult;
+
+/// A sample played by an SFZ instrument, with the opcodes of its headers combined
+#[derive(Clone)]
+pub struct Region {
+    /// Path of the WAV file, relative to the SFZ file
+    pub sample: PathBuf,
+    /// Lowest MIDI key played by the region
+    pub lowest_key: u8,
+    /// Highest MIDI key played by the region
+    pub highest_key: u8,
+    /// MIDI key played by the sample
+    pub pitch_keycenter: u8,
+    /// Lowest MIDI velocity played by the region
+    pub lowest_velocity: u8,
+    /// Highest MIDI velocity played by the region
+    pub highest_velocity: u8,
+    /// Semitones added to the notes
+    pub transpose: i32,
+    /// Cents added to the notes
+    pub tune: i32,
+    /// Volume in decibels
+    pub volume: f64,
+    /// First sample frame played
+    pub offset: u32,
+    /// Last sample frame played, the whole sample if none
+    pub end: Option<u32>,
+    /// How the sample loops, depending on the loop points of the WAV file if none
+    pub loop_mode: Option<LoopMode>,
+    /// First sample frame of the loop, replacing the one of the WAV file
+    pub loop_start: Option<u32>,
+    /// Last sample frame of the loop, replacing the one of the WAV file
+    pub loop_end: Option<u32>,
+    /// Volume envelope of the notes, from the ampeg opcodes
+    pub volume_envelope: Envelope,
+}
+
+/// How a Region plays its sample
+#[derive(Clone, Copy, PartialEq)]
+pub enum LoopMode {
+    /// Plays the sample once, stopping with the note
+    NoLoop,
+    /// Plays the whole sample once. The sample stops with the note like NoLoop as notes cannot outlast their TimeSpan.
+    OneShot,
+    /// Loops until the end of the note
+    LoopContinuous,
+    /// Loops while the note is held. Plays like LoopContinuous.
+    LoopSustain,
+}
+
+/// Headers of an SFZ file, the later ones inheriting the opcodes of the earlier ones
+#[derive(Clone, Copy, PartialEq)]
+enum Header {
+    Control,
+    Global,
+    Master,
+    Group,
+    Region,
+    /// Headers that do not describe regions, like <curve> or <effect>
+    Other,
+}
+
+/// An opcode as written in the file
+#[derive(Clone)]
+struct Opcode {
+    name: String,
+    value: String,
+    /// Position of the opcode, for errors
+    line: usize,
+    column: usize,
+}
+
+/// Semitones of the natural notes from C to B
+const NATURAL_SEMITONES: [i32; 7] = [0, 2, 4, 5, 7, 9, 11];
+
+/// Reads the regions of an SFZ file.
+/// Supports the <control>, <global>, <master>, <group> and <region> headers, comments and #define.
+/// Understands the sample, default_path, key, lokey, hikey, pitch_keycenter, lovel, hivel, transpose, tune, volume, offset, end, loop_mode, loop_start, loop_end and ampeg opcodes, other ones are ignored.
+/// Keys can be MIDI note numbers or names like c#4, c4 being the middle C.
+/// # Arguments
+/// * text - The SFZ file to parse
+pub fn parse(text: &str) -> SynthesizerResult<Vec<Region>> {
+    let mut cursor = TextCursor::new(text);
+    let mut header = Header::Other;
+    let mut default_path = String::new();
+    let mut defines: Vec<(String, String)> = Vec::new();
+    // Opcodes of every header currently applying, from <global> to <region>
+    let mut inherited: HashMap<u8, Vec<Opcode>> = HashMap::new();
+    let mut region_at = (0, 0);
+    let mut regions = Vec::new();
+    loop {
+        cursor.skip_whitespace();
+        let c = match cursor.peek_char() {
+            Some(c) => c,
+            None => break,
+        };
+        if cursor.starts_with("//") {
+            while cursor.peek_char().map_or(false, |c| c != '\n') {
+                cursor.next_char();
+            }
+        } else if cursor.starts_with("/*") {
+            while !cursor.starts_with("*/") {
+                if cursor.next_char().is_none() {
+                    return Err(cursor.error("Unterminated comment").into());
+                }
+            }
+            cursor.next_char();
+            cursor.next_char();
+        } else if c == '<' {
+            if header == Header::Region {
+                regions.push(build_region(&inherited, &default_path, region_at)?);
+            }
+            region_at = (cursor.line, cursor.column);
+            cursor.next_char();
+            let mut name = String::new();
+            loop {
+                match cursor.next_char() {
+                    Some('>') => break,
+                    Some(c) => name.push(c),
+                    None => return Err(cursor.error("Unterminated header").into()),
+                }
+            }
+            header = match name.trim() {
+                "control" => Header::Control,
+                "global" => Header::Global,
+                "master" => Header::Master,
+                "group" => Header::Group,
+                "region" => Header::Region,
+                _ => Header::Other,
+            };
+            // A header replaces its own opcodes and the ones of the headers it contains
+            let level = header_level(header);
+            if level > 0 {
+                inherited.retain(|&other, _| other < level);
+            }
+        } else if c == '#' {
+            let error = cursor.error("Unsupported directive");
+            let line = read_line(&mut cursor);
+            let mut words = line.splitn(3, char::is_whitespace);
+            match (words.next(), words.next(), words.next()) {
+                (Some("#define"), Some(name), Some(value)) if name.starts_with('$') => {
+                    defines.push((name.to_string(), value.trim().to_string()));
+                    // Longer names first, so that $AB is not replaced as $A followed by B
+                    defines.sort_by_key(|define| Reverse(define.0.len()));
+                }
+                _ => return Err(error.into()),
+            }
+        } else {
+            let (line, column) = (cursor.line, cursor.column);
+            let mut name = String::new();
+            while let Some(c) = cursor.peek_char() {
+                if !(c.is_alphanumeric() | (c == '_') | (c == '$')) {
+                    break;
+                }
+                name.push(c);
+                cursor.next_char();
+            }
+            if name.is_empty() | (cursor.next_char() != Some('=')) {
+                return Err(ParseError {
+                    line,
+                    column,
+                    reason: "Expected an opcode",
+                }
+                .into());
+            }
+            let mut value = read_value(&mut cursor);
+            for (define, replacement) in &defines {
+                value = value.replace(define.as_str(), replacement);
+            }
+            let opcode = Opcode {
+                name,
+                value,
+                line,
+                column,
+            };
+            match header {
+                Header::Control if opcode.name == "default_path" => {
+                    default_path = opcode.value.replace('\\', "/");
+                }
+                Header::Control | Header::Other => {}
+                _ => inherited
+                    .entry(header_level(header))
+                    .or_default()
+                    .push(opcode),
+            }
+        }
+    }
+    if header == Header::Region {
+        regions.push(build_region(&inherited, &default_path, region_at)?);
+    }
+    Ok(regions)
+}
+
+/// Creates an Instrument playing the regions of an SFZ file, with MIDI note numbers as Frequency IDs.
+/// Every WAV file is read once, even when several regions use it.
+/// # Arguments
+/// * regions - The regions of the SFZ file
+/// * directory - Directory of the SFZ file, that the paths of the samples are relative to
+pub fn load_instrument(regions: &[Region], directory: &Path) -> Result<Instrument, ReadError> {
+    let mut waves: HashMap<PathBuf, PCM> = HashMap::new();
+    let mut zones = Vec::with_capacity(regions.len());
+    for region in regions {
+        if !waves.contains_key(&region.sample) {
+            let mut reader = BufReader::new(File::open(directory.join(&region.sample))?);
+            waves.insert(region.sample.clone(), Wave::read(&mut reader)?.pcm);
+        }
+        let pcm = &waves[&region.sample];
+        let nb_channels = usize::from(pcm.parameters.nb_channels.max(1));
+        let sample_rate = f64::from(pcm.parameters.sample_rate);
+        let nb_frames = pcm.samples.len() / nb_channels;
+        let start = (region.offset as usize).min(nb_frames);
+        let end = region
+            .end
+            .map_or(nb_frames, |end| (end as usize + 1).min(nb_frames))
+            .max(start);
+        // Loop points of the region, in frames from the start of the whole sample
+        let file_loop = pcm.loop_info.first().map(|span| {
+            (
+                (span.start_at().get() * sample_rate).round() as usize,
+                (span.end_at().get() * sample_rate).round() as usize,
+            )
+        });
+        let loop_mode = region.loop_mode.unwrap_or(match file_loop {
+            Some(_) => LoopMode::LoopContinuous,
+            None => LoopMode::NoLoop,
+        });
+        let mut loop_info = Vec::new();
+        if (loop_mode == LoopMode::LoopContinuous) | (loop_mode == LoopMode::LoopSustain) {
+            let loop_start = region
+                .loop_start
+                .map(|point| point as usize)
+                .or_else(|| file_loop.map(|points| points.0))
+                .unwrap_or(start);
+            let loop_end = region
+                .loop_end
+                .map(|point| point as usize + 1)
+                .or_else(|| file_loop.map(|points| points.1))
+                .unwrap_or(end);
+            let (loop_start, loop_end) = (loop_start.max(start), loop_end.min(end));
+            if loop_end > loop_start {
+                loop_info.push(TimeSpan::new(
+                    Time::new((loop_start - start) as f64 / sample_rate)?,
+                    Time::new((loop_end - start) as f64 / sample_rate)?,
+                )?);
+            }
+        }
+        let gain = 10f64.powf(region.volume / 20f64);
+        let root_key = f64::from(region.pitch_keycenter)
+            - f64::from(region.transpose)
+            - f64::from(region.tune) / 100f64;
+        zones.push(Zone {
+            lowest_f_id: usize::from(region.lowest_key),
+            highest_f_id: usize::from(region.highest_key),
+            lowest_velocity: f64::from(region.lowest_velocity) / 127f64,
+            highest_velocity: f64::from(region.highest_velocity) / 127f64,
+            key_gen: Box::new(SampleKeyGenerator {
+                audio: PCM {
+                    parameters: pcm.parameters,
+                    loop_info,
+                    samples: pcm.samples[start * nb_channels..end * nb_channels]
+                        .iter()
+                        .map(|sample| sample * gain)
+                        .collect(),
+                },
+                root: Frequency::new(440f64 * 2f64.powf((root_key - 69f64) / 12f64))?,
+            }),
+            keys: HashMap::new(),
+            envelope: Some(region.volume_envelope),
+        });
+    }
+    Ok(Instrument::from_zones(zones, 0f64))
+}
+
+/// Returns how deep a header is, the opcodes of a header applying to the deeper ones after it
+fn header_level(header: Header) -> u8 {
+    match header {
+        Header::Global => 1,
+        Header::Master => 2,
+        Header::Group => 3,
+        Header::Region => 4,
+        Header::Control | Header::Other => 0,
+    }
+}
+
+/// Combines the opcodes of a region with the ones it inherits
+/// # Arguments
+/// * inherited - The opcodes of the headers applying to the region, by header level
+/// * default_path - Directory of the samples relative to the SFZ file, from <control>
+/// * at - Position of the <region> header, for errors
+fn build_region(
+    inherited: &HashMap<u8, Vec<Opcode>>,
+    default_path: &str,
+    at: (usize, usize),
+) -> SynthesizerResult<Region> {
+    let mut region = Region {
+        sample: PathBuf::new(),
+        lowest_key: 0,
+        highest_key: 127,
+        pitch_keycenter: 60,
+        lowest_velocity: 0,
+        highest_velocity: 127,
+        transpose: 0,
+        tune: 0,
+        volume: 0f64,
+        offset: 0,
+        end: None,
+        loop_mode: None,
+        loop_start: None,
+        loop_end: None,
+        volume_envelope: Envelope {
+            delay: 0f64,
+            attack: 0f64,
+            hold: 0f64,
+            decay: 0f64,
+            sustain: 1f64,
+            // Default of the SFZ format, short enough to sound like no release
+            release: 0.001,
+            curve: EnvelopeCurve::Exponential,
+        },
+    };
+    let mut has_sample = false;
+    for level in 1..=header_level(Header::Region) {
+        for opcode in inherited
+            .get(&level)
+            .map_or(&[][..], |opcodes| &opcodes[..])
+        {
+            let error = || ParseError {
+                line: opcode.line,
+                column: opcode.column,
+                reason: "Invalid value",
+            };
+            let value = opcode.value.as_str();
+            let key = || parse_key(value).ok_or_else(error);
+            let velocity = || parse_velocity(value).ok_or_else(error);
+            let integer = || value.parse::<i32>().map_err(|_| error());
+            let frames = || value.parse::<u32>().map_err(|_| error());
+            let seconds = || match value.parse::<f64>() {
+                Ok(seconds) if seconds >= 0f64 => Ok(seconds),
+                _ => Err(error()),
+            };
+            match opcode.name.as_str() {
+                "sample" => {
+                    has_sample = true;
+                    region.sample =
+                        PathBuf::from(format!("{}{}", default_path, value.replace('\\', "/")));
+                }
+                "key" => {
+                    region.lowest_key = key()?;
+                    region.highest_key = region.lowest_key;
+                    region.pitch_keycenter = region.lowest_key;
+                }
+                "lokey" => region.lowest_key = key()?,
+                "hikey" => region.highest_key = key()?,
+                "pitch_keycenter" => region.pitch_keycenter = key()?,
+                "lovel" => region.lowest_velocity = velocity()?,
+                "hivel" => region.highest_velocity = velocity()?,
+                "transpose" => region.transpose = integer()?,
+                "tune" => region.tune = integer()?,
+                "volume" => region.volume = value.parse().map_err(|_| error())?,
+                "offset" => region.offset = frames()?,
+                "end" => region.end = Some(frames()?),
+                "loop_start" | "loopstart" => region.loop_start = Some(frames()?),
+                "loop_end" | "loopend" => region.loop_end = Some(frames()?),
+                "loop_mode" | "loopmode" => {
+                    region.loop_mode = Some(match value {
+                        "no_loop" => LoopMode::NoLoop,
+                        "one_shot" => LoopMode::OneShot,
+                        "loop_continuous" => LoopMode::LoopContinuous,
+                        "loop_sustain" => LoopMode::LoopSustain,
+                        _ => return Err(error().into()),
+                    })
+                }
+                "ampeg_delay" => region.volume_envelope.delay = seconds()?,
+                "ampeg_attack" => region.volume_envelope.attack = seconds()?,
+                "ampeg_hold" => region.volume_envelope.hold = seconds()?,
+                "ampeg_decay" => region.volume_envelope.decay = seconds()?,
+                "ampeg_sustain" => region.volume_envelope.sustain = seconds()?.min(100f64) / 100f64,
+                "ampeg_release" => region.volume_envelope.release = seconds()?,
+                _ => {}
+            }
+        }
+    }
+    if !has_sample {
+        return Err(ParseError {
+            line: at.0,
+            column: at.1,
+            reason: "Region without a sample",
+        }
+        .into());
+    }
+    Ok(region)
+}
+
+/// Reads a MIDI key written as a number or as a note name like c#4
+fn parse_key(value: &str) -> Option<u8> {
+    if let Ok(key) = value.parse::<u8>() {
+        return if key <= 127 { Some(key) } else { None };
+    }
+    let mut chars = value.chars();
+    let letter = chars.next()?.to_ascii_lowercase();
+    let mut semitone = NATURAL_SEMITONES["cdefgab".find(letter)?];
+    let rest = chars.as_str();
+    let octave = if let Some(octave) = rest.strip_prefix('#') {
+        semitone += 1;
+        octave
+    } else if let Some(octave) = rest.strip_prefix('b') {
+        semitone -= 1;
+        octave
+    } else {
+        rest
+    };
+    let key = (octave.parse::<i32>().ok()? + 1) * 12 + semitone;
+    if (0..=127).contains(&key) {
+        Some(key as u8)
+    } else {
+        None
+    }
+}
+
+/// Reads a MIDI velocity
+fn parse_velocity(value: &str) -> Option<u8> {
+    value.parse::<u8>().ok().filter(|&velocity| velocity <= 127)
+}
+
+/// Reads the rest of the line
+fn read_line(cursor: &mut TextCursor) -> String {
+    let mut line = String::new();
+    while let Some(c) = cursor.peek_char() {
+        if c == '\n' {
+            break;
+        }
+        line.push(c);
+        cursor.next_char();
+    }
+    line.trim().to_string()
+}
+
+/// Reads the value of an opcode, that goes on until the end of the line, the next opcode, header or comment.
+/// Values can contain spaces, as file names often do.
+fn read_value(cursor: &mut TextCursor) -> String {
+    let mut value = String::new();
+    while let Some(c) = cursor.peek_char() {
+        if (c == '\n') | (c == '\r') {
+            break;
+        }
+        if c.is_whitespace() {
+            // Finds what comes after the spaces
+            let mut next = 0;
+            while cursor
+                .peek_nth(next)
+                .map_or(false, |c| c.is_whitespace() & (c != '\n'))
+            {
+                next += 1;
+            }
+            let mut name_length = 0;
+            while cursor
+                .peek_nth(next + name_length)
+                .map_or(false, |c| c.is_alphanumeric() | (c == '_') | (c == '$'))
+            {
+                name_length += 1;
+            }
+            let after = cursor.peek_nth(next + name_length);
+            let is_opcode = (name_length > 0) & (after == Some('='));
+            let comment = (after == Some('/'))
+                & ((cursor.peek_nth(next + 1) == Some('/'))
+                    | (cursor.peek_nth(next + 1) == Some('*')));
+            let is_other =
+                (name_length == 0) & ((after == Some('<')) | (after == Some('#')) | comment);
+            if is_opcode | is_other {
+                break;
+            }
+        }
+        value.push(c);
+        cursor.next_char();
+    }
+    value.trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use error::SynthesizerError;
+
+    #[test]
+    fn inheritance_and_defines() {
+        let text = "// An instrument
+#define $LOW 0
+<control> default_path=samples\\
+<global> ampeg_release=0.3 ampeg_attack=0.01
+<group> lovel=$LOW hivel=63 volume=-6
+<region> sample=low sine.wav lokey=c-1 hikey=b3 pitch_keycenter=a4 /* block */
+<region> sample=high.wav key=c#5 tune=-100 ampeg_sustain=50
+<group> lovel=64
+<region> sample=low sine.wav lokey=0 hikey=127 pitch_keycenter=69 loop_mode=no_loop offset=10 end=2000 transpose=12
+";
+        let regions = parse(text).unwrap();
+        let found = regions
+            .iter()
+            .map(|region| {
+                (
+                    region.sample.clone(),
+                    (
+                        region.lowest_key,
+                        region.highest_key,
+                        region.pitch_keycenter,
+                    ),
+                    (region.lowest_velocity, region.highest_velocity),
+                    (region.volume, region.tune, region.transpose),
+                )
+            })
+            .collect::<Vec<(PathBuf, (u8, u8, u8), (u8, u8), (f64, i32, i32))>>();
+        assert_eq!(
+            found,
+            vec![
+                (
+                    PathBuf::from("samples/low sine.wav"),
+                    (0, 59, 69),
+                    (0, 63),
+                    (-6f64, 0, 0)
+                ),
+                (
+                    PathBuf::from("samples/high.wav"),
+                    (73, 73, 73),
+                    (0, 63),
+                    (-6f64, -100, 0)
+                ),
+                (
+                    PathBuf::from("samples/low sine.wav"),
+                    (0, 127, 69),
+                    (64, 127),
+                    (0f64, 0, 12)
+                ),
+            ]
+        );
+        assert!(regions[0].loop_mode.is_none());
+        assert!(regions[2].loop_mode == Some(LoopMode::NoLoop));
+        assert_eq!((regions[2].offset, regions[2].end), (10, Some(2000)));
+        for region in &regions {
+            assert_eq!(region.volume_envelope.attack, 0.01);
+            assert_eq!(region.volume_envelope.release, 0.3);
+        }
+        assert_eq!(regions[0].volume_envelope.sustain, 1f64);
+        assert_eq!(regions[1].volume_envelope.sustain, 0.5);
+    }
+
+    #[test]
+    fn error_positions() {
+        for &(text, position) in &[
+            ("<region> lokey=60", (1, 1, "Region without a sample")),
+            ("<region sample=a.wav", (1, 21, "Unterminated header")),
+            ("<region>\n sample=a.wav lokey=x9", (2, 15, "Invalid value")),
+            ("#include \"a.sfz\"", (1, 1, "Unsupported directive")),
+            ("<region> =3", (1, 10, "Expected an opcode")),
+        ] {
+            match parse(text) {
+                Err(SynthesizerError::Parse(error)) => {
+                    assert_eq!((error.line, error.column, error.reason), position)
+                }
+                _ => panic!("Expected a parse error for {:?}", text),
+            }
+        }
+    }
+}
@@ -0,0 +1,738 @@
+use error::{MalformedChunkError, MissingChunkError, NoInstrumentForProgramError, ReadError};
+use ez_io::ReadE;
+use instrument::{Envelope, EnvelopeCurve, Instrument, Zone};
+use key_generator::SampleKeyGenerator;
+use midi::{ProgramLookup, PERCUSSION_BANK};
+use pcm::{PCMParameters, SpeakerLayout, PCM};
+use std::collections::HashMap;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::result::Result;
+use util::{Frequency, Time, TimeSpan};
+use wave::{check_id, read_chunk_data};
+use Result as SynthesizerResult;
+
+/// A SoundFont 2 bank, with its presets, instruments and samples
+#[derive(Clone)]
+pub struct SoundFont {
+    /// Name of the bank
+    pub name: String,
+    /// What every MIDI bank and program plays
+    pub presets: Vec<Preset>,
+    /// Instruments used by the presets
+    pub instruments: Vec<SoundFontInstrument>,
+    /// Samples used by the instruments
+    pub samples: Vec<SampleHeader>,
+    /// Every sample point of the bank in [-1; 1], the samples being parts of it
+    pub sample_data: Vec<f64>,
+}
+
+/// A preset of a SoundFont, played for a MIDI bank and program
+#[derive(Clone)]
+pub struct Preset {
+    pub name: String,
+    /// MIDI program
+    pub program: u16,
+    /// MIDI bank, 128 for percussions
+    pub bank: u16,
+    /// Zones of the preset, each one playing an instrument. The global zone is merged into them.
+    pub zones: Vec<GeneratorZone>,
+}
+
+/// An instrument of a SoundFont
+#[derive(Clone)]
+pub struct SoundFontInstrument {
+    pub name: String,
+    /// Zones of the instrument, each one playing a sample. The global zone is merged into them.
+    pub zones: Vec<GeneratorZone>,
+}
+
+/// The generators of a zone, as their raw amount by generator number
+#[derive(Clone, Default)]
+pub struct GeneratorZone {
+    pub generators: HashMap<u16, u16>,
+}
+
+/// Where a sample is in the sample data of a SoundFont, and how it sounds
+#[derive(Clone)]
+pub struct SampleHeader {
+    pub name: String,
+    /// Index of the first sample point
+    pub start: u32,
+    /// Index after the last sample point
+    pub end: u32,
+    /// Index of the first sample point of the loop
+    pub loop_start: u32,
+    /// Index after the last sample point of the loop
+    pub loop_end: u32,
+    pub sample_rate: u32,
+    /// MIDI key played by the sample
+    pub original_key: u8,
+    /// Pitch correction in cents
+    pub correction: i8,
+    /// Kind of sample, ROM samples having 0x8000 set
+    pub sample_type: u16,
+}
+
+/// A sample played by a preset, with the generators of the preset and its instrument combined
+#[derive(Clone)]
+pub struct Region {
+    /// Lowest and highest MIDI keys
+    pub keys: (u8, u8),
+    /// Lowest and highest MIDI velocities
+    pub velocities: (u8, u8),
+    /// Audio of the sample, attenuation included, with its loop if it has one
+    pub audio: PCM,
+    /// Frequency played by the audio
+    pub root: Frequency,
+    /// Volume envelope of the notes
+    pub volume_envelope: Envelope,
+}
+
+/// Offset of the first sample point
+pub const GEN_START_ADDRS_OFFSET: u16 = 0;
+/// Offset of the end of the sample
+pub const GEN_END_ADDRS_OFFSET: u16 = 1;
+/// Offset of the start of the loop
+pub const GEN_STARTLOOP_ADDRS_OFFSET: u16 = 2;
+/// Offset of the end of the loop
+pub const GEN_ENDLOOP_ADDRS_OFFSET: u16 = 3;
+/// Offset of the first sample point, in steps of 32768
+pub const GEN_START_ADDRS_COARSE_OFFSET: u16 = 4;
+/// Offset of the end of the sample, in steps of 32768
+pub const GEN_END_ADDRS_COARSE_OFFSET: u16 = 12;
+/// Delay of the volume envelope, in timecents
+pub const GEN_DELAY_VOL_ENV: u16 = 33;
+/// Attack of the volume envelope, in timecents
+pub const GEN_ATTACK_VOL_ENV: u16 = 34;
+/// Hold of the volume envelope, in timecents
+pub const GEN_HOLD_VOL_ENV: u16 = 35;
+/// Decay of the volume envelope, in timecents
+pub const GEN_DECAY_VOL_ENV: u16 = 36;
+/// Sustain of the volume envelope, as an attenuation in centibels
+pub const GEN_SUSTAIN_VOL_ENV: u16 = 37;
+/// Release of the volume envelope, in timecents
+pub const GEN_RELEASE_VOL_ENV: u16 = 38;
+/// Index of the instrument played by a preset zone
+pub const GEN_INSTRUMENT: u16 = 41;
+/// Range of MIDI keys, lowest in the low byte
+pub const GEN_KEY_RANGE: u16 = 43;
+/// Range of MIDI velocities, lowest in the low byte
+pub const GEN_VEL_RANGE: u16 = 44;
+/// Offset of the start of the loop, in steps of 32768
+pub const GEN_STARTLOOP_ADDRS_COARSE_OFFSET: u16 = 45;
+/// Attenuation in centibels
+pub const GEN_INITIAL_ATTENUATION: u16 = 48;
+/// Offset of the end of the loop, in steps of 32768
+pub const GEN_ENDLOOP_ADDRS_COARSE_OFFSET: u16 = 50;
+/// Tuning in semitones
+pub const GEN_COARSE_TUNE: u16 = 51;
+/// Tuning in cents
+pub const GEN_FINE_TUNE: u16 = 52;
+/// Index of the sample played by an instrument zone
+pub const GEN_SAMPLE_ID: u16 = 53;
+/// 1 or 3 if the sample loops
+pub const GEN_SAMPLE_MODES: u16 = 54;
+/// MIDI key played by the sample, replacing the one of its header
+pub const GEN_OVERRIDING_ROOT_KEY: u16 = 58;
+
+/// Bank of the percussion presets
+pub const SF2_PERCUSSION_BANK: u16 = 128;
+/// Timecents of the envelope stages that are not set, meaning about a millisecond
+const DEFAULT_ENVELOPE_TIMECENTS: i16 = -12000;
+/// Sample rate of the samples that do not have a valid one
+const DEFAULT_SAMPLE_RATE: u32 = 44100;
+
+impl SoundFont {
+    /// Reads a SoundFont 2 file
+    pub fn read<R: Read + Seek>(reader: &mut R) -> Result<SoundFont, ReadError> {
+        check_id(reader, *b"RIFF")?;
+        let riff_size = reader.read_le_to_u32()?;
+        let riff_end = reader.seek(SeekFrom::Current(0))? + u64::from(riff_size);
+        check_id(reader, *b"sfbk")?;
+        let mut name = String::new();
+        let mut sample_data = None;
+        let mut sample_lsb = None;
+        let mut pdta = HashMap::new();
+        // Go through the LIST chunks and the chunks inside them
+        while reader.seek(SeekFrom::Current(0))? + 12 <= riff_end {
+            check_id(reader, *b"LIST")?;
+            let list_size = reader.read_le_to_u32()?;
+            let list_start = reader.seek(SeekFrom::Current(0))?;
+            let list_end = list_start + u64::from(list_size);
+            let mut list_type = [0u8; 4];
+            reader.read_exact(&mut list_type)?;
+            while reader.seek(SeekFrom::Current(0))? + 8 <= list_end {
+                let mut chunk_id = [0u8; 4];
+                reader.read_exact(&mut chunk_id)?;
+                let chunk_size = u64::from(reader.read_le_to_u32()?);
+                let chunk_start = reader.seek(SeekFrom::Current(0))?;
+                if chunk_start + chunk_size > list_end {
+                    return Err(MalformedChunkError {
+                        chunk_id,
+                        reason: "Chunk goes past the end of its list",
+                    }
+                    .into());
+                }
+                match (&list_type, &chunk_id) {
+                    (b"INFO", b"INAM") => {
+                        let data = read_chunk_data(reader, chunk_size)?;
+                        name = String::from_utf8_lossy(&data)
+                            .trim_end_matches('\0')
+                            .to_string();
+                    }
+                    (b"sdta", b"smpl") => {
+                        let data = read_chunk_data(reader, chunk_size)?;
+                        sample_data = Some(data);
+                    }
+                    (b"sdta", b"sm24") => {
+                        let data = read_chunk_data(reader, chunk_size)?;
+                        sample_lsb = Some(data);
+                    }
+                    (b"pdta", _) => {
+                        let data = read_chunk_data(reader, chunk_size)?;
+                        pdta.insert(chunk_id, data);
+                    }
+                    _ => {}
+                }
+                // Chunks are always aligned on 2 bytes
+                reader.seek(SeekFrom::Start(chunk_start + chunk_size + (chunk_size % 2)))?;
+            }
+            reader.seek(SeekFrom::Start(list_end + u64::from(list_size % 2)))?;
+        }
+        let sample_data = sample_data.ok_or(MissingChunkError { chunk_id: *b"smpl" })?;
+        // The 24 bits extension is only used if it matches the 16 bits samples
+        let sample_lsb = sample_lsb.filter(|lsb| lsb.len() * 2 >= sample_data.len());
+        let sample_data = sample_data
+            .chunks(2)
+            .filter(|bytes| bytes.len() == 2)
+            .enumerate()
+            .map(|(index, bytes)| {
+                let msb = i32::from(i16::from(bytes[0]) | (i16::from(bytes[1] as i8) << 8));
+                match sample_lsb {
+                    Some(ref lsb) => f64::from((msb << 8) | i32::from(lsb[index])) / 8_388_608f64,
+                    None => f64::from(msb) / 32768f64,
+                }
+            })
+            .collect();
+        let mut get_records = |chunk_id: [u8; 4], size: usize| -> Result<Vec<Vec<u8>>, ReadError> {
+            let data = pdta
+                .remove(&chunk_id)
+                .ok_or(MissingChunkError { chunk_id })?;
+            // The last record only marks the end of the previous one
+            if (data.len() % size != 0) | (data.len() < size) {
+                return Err(MalformedChunkError {
+                    chunk_id,
+                    reason: "Chunk size does not match its records",
+                }
+                .into());
+            }
+            Ok(data.chunks(size).map(|record| record.to_vec()).collect())
+        };
+        let preset_headers = get_records(*b"phdr", 38)?;
+        let preset_bags = get_records(*b"pbag", 4)?;
+        let preset_generators = get_records(*b"pgen", 4)?;
+        let instrument_headers = get_records(*b"inst", 22)?;
+        let instrument_bags = get_records(*b"ibag", 4)?;
+        let instrument_generators = get_records(*b"igen", 4)?;
+        let sample_headers = get_records(*b"shdr", 46)?;
+        let mut presets = Vec::with_capacity(preset_headers.len() - 1);
+        for (header, next_header) in preset_headers.iter().zip(preset_headers.iter().skip(1)) {
+            let mut reader = Cursor::new(&header[20..]);
+            let program = reader.read_le_to_u16()?;
+            let bank = reader.read_le_to_u16()?;
+            let bag_index = reader.read_le_to_u16()?;
+            let next_bag_index = Cursor::new(&next_header[24..]).read_le_to_u16()?;
+            presets.push(Preset {
+                name: read_name(&header[..20]),
+                program,
+                bank,
+                zones: read_zones(
+                    (bag_index, next_bag_index),
+                    &preset_bags,
+                    &preset_generators,
+                    GEN_INSTRUMENT,
+                    *b"pbag",
+                )?,
+            });
+        }
+        let mut instruments = Vec::with_capacity(instrument_headers.len() - 1);
+        for (header, next_header) in instrument_headers
+            .iter()
+            .zip(instrument_headers.iter().skip(1))
+        {
+            let bag_index = Cursor::new(&header[20..]).read_le_to_u16()?;
+            let next_bag_index = Cursor::new(&next_header[20..]).read_le_to_u16()?;
+            instruments.push(SoundFontInstrument {
+                name: read_name(&header[..20]),
+                zones: read_zones(
+                    (bag_index, next_bag_index),
+                    &instrument_bags,
+                    &instrument_generators,
+                    GEN_SAMPLE_ID,
+                    *b"ibag",
+                )?,
+            });
+        }
+        let mut samples = Vec::with_capacity(sample_headers.len() - 1);
+        for header in &sample_headers[..sample_headers.len() - 1] {
+            let mut reader = Cursor::new(&header[20..]);
+            let start = reader.read_le_to_u32()?;
+            let end = reader.read_le_to_u32()?;
+            let loop_start = reader.read_le_to_u32()?;
+            let loop_end = reader.read_le_to_u32()?;
+            let sample_rate = reader.read_le_to_u32()?;
+            let original_key = reader.read_to_u8()?;
+            let correction = reader.read_to_u8()? as i8;
+            let _sample_link = reader.read_le_to_u16()?;
+            let sample_type = reader.read_le_to_u16()?;
+            samples.push(SampleHeader {
+                name: read_name(&header[..20]),
+                start,
+                end,
+                loop_start,
+                loop_end,
+                sample_rate,
+                original_key,
+                correction,
+                sample_type,
+            });
+        }
+        Ok(SoundFont {
+            name,
+            presets,
+            instruments,
+            samples,
+            sample_data,
+        })
+    }
+
+    /// Combines the generators of a preset and its instruments into the samples it plays.
+    /// Zones pointing to missing instruments or samples, ROM samples, and zones that do not overlap are skipped.
+    /// # Arguments
+    /// * preset - Index of the preset in the presets of the SoundFont
+    pub fn get_regions(&self, preset: usize) -> SynthesizerResult<Vec<Region>> {
+        let mut regions = Vec::new();
+        let preset_zones = match self.presets.get(preset) {
+            Some(preset) => &preset.zones,
+            None => return Ok(regions),
+        };
+        for preset_zone in preset_zones {
+            let instrument = match preset_zone
+                .get(GEN_INSTRUMENT)
+                .and_then(|index| self.instruments.get(usize::from(index)))
+            {
+                Some(instrument) => instrument,
+                None => continue,
+            };
+            for zone in &instrument.zones {
+                let sample = match zone
+                    .get(GEN_SAMPLE_ID)
+                    .and_then(|index| self.samples.get(usize::from(index)))
+                {
+                    Some(sample) if sample.sample_type & 0x8000 == 0 => sample,
+                    _ => continue,
+                };
+                let keys = intersect(
+                    zone.get_range(GEN_KEY_RANGE),
+                    preset_zone.get_range(GEN_KEY_RANGE),
+                );
+                let velocities = intersect(
+                    zone.get_range(GEN_VEL_RANGE),
+                    preset_zone.get_range(GEN_VEL_RANGE),
+                );
+                let (keys, velocities) = match (keys, velocities) {
+                    (Some(keys), Some(velocities)) => (keys, velocities),
+                    _ => continue,
+                };
+                // Preset generators are added to the ones of the instrument
+                let sum = |generator: u16, default: i16| {
+                    i32::from(zone.get_signed(generator).unwrap_or(default))
+                        + i32::from(preset_zone.get_signed(generator).unwrap_or(0))
+                };
+                let offset = |fine: u16, coarse: u16| {
+                    i64::from(zone.get_signed(fine).unwrap_or(0))
+                        + i64::from(zone.get_signed(coarse).unwrap_or(0)) * 32768
+                };
+                let data_len = self.sample_data.len() as i64;
+                let clamp = |index: i64| index.max(0).min(data_len) as usize;
+                let start = clamp(
+                    i64::from(sample.start)
+                        + offset(GEN_START_ADDRS_OFFSET, GEN_START_ADDRS_COARSE_OFFSET),
+                );
+                let end = clamp(
+                    i64::from(sample.end)
+                        + offset(GEN_END_ADDRS_OFFSET, GEN_END_ADDRS_COARSE_OFFSET),
+                );
+                if end <= start {
+                    continue;
+                }
+                let loop_start = clamp(
+                    i64::from(sample.loop_start)
+                        + offset(
+                            GEN_STARTLOOP_ADDRS_OFFSET,
+                            GEN_STARTLOOP_ADDRS_COARSE_OFFSET,
+                        ),
+                )
+                .max(start);
+                let loop_end = clamp(
+                    i64::from(sample.loop_end)
+                        + offset(GEN_ENDLOOP_ADDRS_OFFSET, GEN_ENDLOOP_ADDRS_COARSE_OFFSET),
+                )
+                .min(end);
+                let sample_rate = if sample.sample_rate == 0 {
+                    DEFAULT_SAMPLE_RATE
+                } else {
+                    sample.sample_rate
+                };
+                let mut loop_info = Vec::new();
+                let sample_mode = zone.get(GEN_SAMPLE_MODES).unwrap_or(0) & 0x3;
+                if ((sample_mode == 1) | (sample_mode == 3)) & (loop_end > loop_start) {
+                    let rate = f64::from(sample_rate);
+                    loop_info.push(TimeSpan::new(
+                        Time::new((loop_start - start) as f64 / rate)?,
+                        Time::new((loop_end - start) as f64 / rate)?,
+                    )?);
+                }
+                let attenuation = f64::from(sum(GEN_INITIAL_ATTENUATION, 0).max(0));
+                let gain = 10f64.powf(-attenuation / 200f64);
+                let root_key = match zone.get_signed(GEN_OVERRIDING_ROOT_KEY) {
+                    Some(key) if (0..=127).contains(&key) => f64::from(key),
+                    _ if sample.original_key <= 127 => f64::from(sample.original_key),
+                    _ => 60f64,
+                };
+                let cents = f64::from(sample.correction)
+                    + f64::from(sum(GEN_COARSE_TUNE, 0)) * 100f64
+                    + f64::from(sum(GEN_FINE_TUNE, 0));
+                let root = Frequency::new(
+                    440f64 * 2f64.powf((root_key - 69f64 - cents / 100f64) / 12f64),
+                )?;
+                let seconds = |generator: u16| {
+                    2f64.powf(f64::from(sum(generator, DEFAULT_ENVELOPE_TIMECENTS)) / 1200f64)
+                };
+                let sustain = f64::from(sum(GEN_SUSTAIN_VOL_ENV, 0).clamp(0, 1440));
+                regions.push(Region {
+                    keys,
+                    velocities,
+                    audio: PCM {
+                        parameters: PCMParameters {
+                            sample_rate,
+                            nb_channels: 1,
+                            speaker_layout: SpeakerLayout::Unspecified,
+                        },
+                        loop_info,
+                        samples: self.sample_data[start..end]
+                            .iter()
+                            .map(|sample| sample * gain)
+                            .collect(),
+                    },
+                    root,
+                    volume_envelope: Envelope {
+                        delay: seconds(GEN_DELAY_VOL_ENV),
+                        attack: seconds(GEN_ATTACK_VOL_ENV),
+                        hold: seconds(GEN_HOLD_VOL_ENV),
+                        decay: seconds(GEN_DECAY_VOL_ENV),
+                        sustain: 10f64.powf(-sustain / 200f64),
+                        release: seconds(GEN_RELEASE_VOL_ENV),
+                        // Decays and releases are measured in centibels
+                        curve: EnvelopeCurve::Exponential,
+                    },
+                });
+            }
+        }
+        Ok(regions)
+    }
+
+    /// Creates the Instrument of a preset, its zones using MIDI note numbers as Frequency IDs.
+    /// Pans and sample links are not used: the left and right samples of stereo presets both play in the middle, making them mono.
+    /// # Arguments
+    /// * preset - Index of the preset in the presets of the SoundFont
+    pub fn to_instrument(&self, preset: usize) -> SynthesizerResult<Instrument> {
+        let zones = self
+            .get_regions(preset)?
+            .into_iter()
+            .map(|region| Zone {
+                lowest_f_id: usize::from(region.keys.0),
+                highest_f_id: usize::from(region.keys.1),
+                lowest_velocity: f64::from(region.velocities.0) / 127f64,
+                highest_velocity: f64::from(region.velocities.1) / 127f64,
+                key_gen: Box::new(SampleKeyGenerator {
+                    audio: region.audio,
+                    root: region.root,
+                }),
+                keys: HashMap::new(),
+                envelope: Some(region.volume_envelope),
+            })
+            .collect();
+        Ok(Instrument::from_zones(zones, 0f64))
+    }
+
+    /// Creates the Instrument of every preset, its Instrument ID being the index of the preset.
+    /// The SoundFont is also a ProgramLookup giving these IDs to MIDI channels.
+    pub fn to_instruments(&self) -> SynthesizerResult<HashMap<usize, Instrument>> {
+        let mut instruments = HashMap::new();
+        for preset in 0..self.presets.len() {
+            instruments.insert(preset, self.to_instrument(preset)?);
+        }
+        Ok(instruments)
+    }
+}
+
+impl ProgramLookup for SoundFont {
+    /// Finds the preset of the bank and program. Like General MIDI synthesizers, the first bank of the same kind is used if there is none.
+    /// Only the high part of MIDI banks are used, PERCUSSION_BANK being the percussion bank of the SoundFont.
+    fn get_inst(&self, channel: u8, bank: u16, program: u8) -> SynthesizerResult<usize> {
+        let find = |sf_bank: u16, sf_program: u8| {
+            self.presets.iter().position(|preset| {
+                (preset.bank == sf_bank) & (preset.program == u16::from(sf_program))
+            })
+        };
+        let found = if bank == PERCUSSION_BANK {
+            find(SF2_PERCUSSION_BANK, program).or_else(|| find(SF2_PERCUSSION_BANK, 0))
+        } else {
+            find(bank >> 7, program).or_else(|| find(0, program))
+        };
+        Ok(found.ok_or(NoInstrumentForProgramError {
+            channel,
+            bank,
+            program,
+        })?)
+    }
+}
+
+impl GeneratorZone {
+    /// Returns the raw amount of a generator
+    pub fn get(&self, generator: u16) -> Option<u16> {
+        self.generators.get(&generator).cloned()
+    }
+    /// Returns the amount of a generator as a signed number
+    pub fn get_signed(&self, generator: u16) -> Option<i16> {
+        self.get(generator).map(|amount| amount as i16)
+    }
+    /// Returns the range of a range generator, everything if it is not set
+    pub fn get_range(&self, generator: u16) -> (u8, u8) {
+        match self.get(generator) {
+            Some(amount) => ((amount & 0xFF) as u8, (amount >> 8) as u8),
+            None => (0, 127),
+        }
+    }
+}
+
+/// Reads the zones of a preset or instrument, merging the global zone into the other ones
+/// # Arguments
+/// * bags - Index of the first bag of the preset or instrument, and of the first bag of the next one
+/// * bag_records - Every bag of the presets or instruments
+/// * generator_records - Every generator of the presets or instruments
+/// * last_generator - Generator that ends every zone but the global one
+/// * chunk_id - Chunk of the bags, for errors
+fn read_zones(
+    bags: (u16, u16),
+    bag_records: &[Vec<u8>],
+    generator_records: &[Vec<u8>],
+    last_generator: u16,
+    chunk_id: [u8; 4],
+) -> Result<Vec<GeneratorZone>, ReadError> {
+    let (first_bag, end_bag) = (usize::from(bags.0), usize::from(bags.1));
+    if (first_bag > end_bag) | (end_bag >= bag_records.len()) {
+        return Err(MalformedChunkError {
+            chunk_id,
+            reason: "Bag index out of range",
+        }
+        .into());
+    }
+    let mut global = None;
+    let mut zones = Vec::with_capacity(end_bag - first_bag);
+    for bag in first_bag..end_bag {
+        let first_generator = usize::from(Cursor::new(&bag_records[bag]).read_le_to_u16()?);
+        let end_generator = usize::from(Cursor::new(&bag_records[bag + 1]).read_le_to_u16()?);
+        if (first_generator > end_generator) | (end_generator > generator_records.len()) {
+            return Err(MalformedChunkError {
+                chunk_id,
+                reason: "Generator index out of range",
+            }
+            .into());
+        }
+        let mut zone = GeneratorZone::default();
+        for record in &generator_records[first_generator..end_generator] {
+            let mut reader = Cursor::new(record);
+            let generator = reader.read_le_to_u16()?;
+            let amount = reader.read_le_to_u16()?;
+            zone.generators.insert(generator, amount);
+        }
+        if zone.generators.contains_key(&last_generator) {
+            zones.push(zone);
+        } else if (bag == first_bag) & global.is_none() {
+            global = Some(zone);
+        }
+    }
+    if let Some(global) = global {
+        for zone in &mut zones {
+            for (&generator, &amount) in &global.generators {
+                zone.generators.entry(generator).or_insert(amount);
+            }
+        }
+    }
+    Ok(zones)
+}
+
+/// Finds what two ranges have in common
+fn intersect(a: (u8, u8), b: (u8, u8)) -> Option<(u8, u8)> {
+    let range = (a.0.max(b.0), a.1.min(b.1));
+    if range.0 <= range.1 {
+        Some(range)
+    } else {
+        None
+    }
+}
+
+/// Reads a name padded with zeros
+fn read_name(data: &[u8]) -> String {
+    let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a RIFF chunk, padded to an even size
+    fn chunk(id: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut result = id.to_vec();
+        result.extend(&(data.len() as u32).to_le_bytes());
+        result.extend(data);
+        if data.len() % 2 == 1 {
+            result.push(0);
+        }
+        result
+    }
+
+    /// Builds a LIST chunk
+    fn list(kind: &[u8], chunks: &[Vec<u8>]) -> Vec<u8> {
+        chunk(
+            b"LIST",
+            &[vec![kind.to_vec()], chunks.to_vec()].concat().concat(),
+        )
+    }
+
+    /// A name padded to 20 bytes
+    fn name(text: &str) -> Vec<u8> {
+        let mut result = text.as_bytes().to_vec();
+        result.resize(20, 0);
+        result
+    }
+
+    /// Bag or generator records, as pairs of numbers
+    fn records(values: &[(u16, u16)]) -> Vec<u8> {
+        let mut result = Vec::new();
+        for &(a, b) in values {
+            result.extend(&a.to_le_bytes());
+            result.extend(&b.to_le_bytes());
+        }
+        result
+    }
+
+    /// A bank with a preset attenuated by 6 dB and a percussion preset, both playing one looping sample on two zones
+    fn build_bank() -> Vec<u8> {
+        let mut smpl = Vec::new();
+        for _ in 0..100 {
+            smpl.extend(&16384i16.to_le_bytes());
+        }
+        smpl.extend(&[0u8; 92]);
+        let mut phdr = Vec::new();
+        for &(preset_name, program, bank, bag) in &[
+            ("Piano", 0u16, 0u16, 0u16),
+            ("Drums", 0, SF2_PERCUSSION_BANK, 2),
+            ("EOP", 0, 0, 3),
+        ] {
+            phdr.extend(name(preset_name));
+            for value in &[program, bank, bag] {
+                phdr.extend(&value.to_le_bytes());
+            }
+            phdr.extend(&[0u8; 12]);
+        }
+        let pbag = records(&[(0, 0), (1, 0), (2, 0), (3, 0)]);
+        let pgen = records(&[
+            (GEN_INITIAL_ATTENUATION, 60),
+            (GEN_INSTRUMENT, 0),
+            (GEN_INSTRUMENT, 0),
+            (0, 0),
+        ]);
+        let mut inst = name("Square");
+        inst.extend(&0u16.to_le_bytes());
+        inst.extend(name("EOI"));
+        inst.extend(&3u16.to_le_bytes());
+        // A global zone, then zones on the low keys and on the high keys played loud an octave lower
+        let ibag = records(&[(0, 0), (1, 0), (3, 0), (7, 0)]);
+        let igen = records(&[
+            (GEN_SAMPLE_MODES, 1),
+            (GEN_KEY_RANGE, 60 << 8),
+            (GEN_SAMPLE_ID, 0),
+            (GEN_KEY_RANGE, 61 | (127 << 8)),
+            (GEN_VEL_RANGE, 64 | (127 << 8)),
+            (GEN_COARSE_TUNE, 12),
+            (GEN_SAMPLE_ID, 0),
+            (0, 0),
+        ]);
+        let mut shdr = name("square");
+        for value in &[0u32, 100, 10, 90, 1000] {
+            shdr.extend(&value.to_le_bytes());
+        }
+        shdr.extend(&[69, 0, 0, 0, 1, 0]); // Key, correction, link and mono type
+        shdr.extend(name("EOS"));
+        shdr.extend(&[0u8; 26]);
+        let body = [
+            b"sfbk".to_vec(),
+            list(
+                b"INFO",
+                &[
+                    chunk(b"ifil", &[2, 0, 1, 0]),
+                    chunk(b"INAM", b"Test bank\0"),
+                ],
+            ),
+            list(b"sdta", &[chunk(b"smpl", &smpl)]),
+            list(
+                b"pdta",
+                &[
+                    chunk(b"phdr", &phdr),
+                    chunk(b"pbag", &pbag),
+                    chunk(b"pmod", &[0; 10]),
+                    chunk(b"pgen", &pgen),
+                    chunk(b"inst", &inst),
+                    chunk(b"ibag", &ibag),
+                    chunk(b"imod", &[0; 10]),
+                    chunk(b"igen", &igen),
+                    chunk(b"shdr", &shdr),
+                ],
+            ),
+        ]
+        .concat();
+        chunk(b"RIFF", &body)
+    }
+
+    #[test]
+    fn regions() {
+        let bank = SoundFont::read(&mut Cursor::new(build_bank())).unwrap();
+        assert_eq!(bank.name, "Test bank");
+        assert_eq!(bank.presets.len(), 2);
+        assert_eq!(bank.presets[1].bank, SF2_PERCUSSION_BANK);
+        for &(preset, amplitude) in &[(0, 0.5 * 10f64.powf(-0.3)), (1, 0.5)] {
+            let regions = bank.get_regions(preset).unwrap();
+            let found = regions
+                .iter()
+                .map(|region| (region.keys, region.velocities, region.root.get().round()))
+                .collect::<Vec<((u8, u8), (u8, u8), f64)>>();
+            assert_eq!(
+                found,
+                vec![((0, 60), (0, 127), 440f64), ((61, 127), (64, 127), 220f64)]
+            );
+            for region in &regions {
+                assert_eq!(region.audio.parameters.sample_rate, 1000);
+                assert_eq!(region.audio.samples.len(), 100);
+                assert!((region.audio.samples[0] - amplitude).abs() < 1e-3);
+                assert_eq!(region.audio.loop_info.len(), 1);
+                let span = &region.audio.loop_info[0];
+                assert!((span.start_at().get() - 0.01).abs() < 1e-9);
+                assert!((span.end_at().get() - 0.09).abs() < 1e-9);
+            }
+        }
+    }
+}
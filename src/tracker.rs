@@ -0,0 +1,1163 @@
+use error::{MalformedModuleError, ReadError, TimeInvalidError};
+use ez_io::ReadE;
+use frequency_lookup::MIDIFrequencyLookup;
+use instrument::Instrument;
+use key_generator::SampleKeyGenerator;
+use pcm::{PCMParameters, SpeakerLayout, PCM};
+use sequence::{GainPoint, Note, Sequence};
+use std::collections::HashMap;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::result::Result;
+use util::{Frequency, Time, TimeSpan, Volume};
+use wave::read_chunk_data;
+use Result as SynthesizerResult;
+use Synthesizer;
+
+/// A tracker module, holding both the music and the samples playing it
+#[derive(Clone)]
+pub struct Module {
+    /// Title of the module
+    pub name: String,
+    /// Number of channels of every pattern
+    pub nb_channels: usize,
+    /// Indexes of the patterns in the order they are played
+    pub order: Vec<usize>,
+    /// Position in the order the module goes back to once it reaches the end
+    pub restart_position: usize,
+    /// Patterns of the module, as rows of cells, one cell per channel
+    pub patterns: Vec<Vec<Vec<Cell>>>,
+    /// Instruments that the cells refer to
+    pub instruments: Vec<ModuleInstrument>,
+    /// Samples played by the instruments
+    pub samples: Vec<ModuleSample>,
+    /// Position of every channel before a sample changes it, from -1 (left) to 1 (right)
+    pub channel_pan: Vec<f64>,
+    /// Number of ticks per row when the module starts
+    pub initial_speed: u32,
+    /// Beats per minute when the module starts, a tick lasting 2.5 / tempo seconds
+    pub initial_tempo: u32,
+}
+
+/// What a channel is told to do on a row
+#[derive(Clone, Copy, Default)]
+pub struct Cell {
+    /// MIDI key of the note to play, before the sample transposes it
+    pub note: Option<usize>,
+    /// If the playing note is released
+    pub key_off: bool,
+    /// Instrument to play from now on, starting from 0
+    pub instrument: Option<usize>,
+    /// Volume column of XM modules, 0 if empty
+    pub volume: u8,
+    /// Effect number, using the MOD numbers for the effects both formats share
+    pub effect: u8,
+    /// Parameter of the effect
+    pub param: u8,
+}
+
+/// An instrument of a module, choosing a sample for every note
+#[derive(Clone)]
+pub struct ModuleInstrument {
+    pub name: String,
+    /// Index in the samples of the module for every note, starting from C-0 (MIDI key 12)
+    pub sample_map: Vec<Option<usize>>,
+}
+
+/// A sample of a module
+#[derive(Clone)]
+pub struct ModuleSample {
+    pub name: String,
+    /// Mono audio of the sample at C4_SAMPLE_RATE, its loop being the first of loop_info. Use get_forward_audio to play it.
+    pub pcm: PCM,
+    /// If the loop goes back and forth instead of starting over
+    pub ping_pong: bool,
+    /// Volume of the notes when the sample is chosen, in [0; 1]
+    pub volume: f64,
+    /// Position of the notes when the sample is chosen, from -1 (left) to 1 (right). None to keep the one of the channel.
+    pub pan: Option<f64>,
+    /// Semitones added to the notes played with the sample, finetune included
+    pub transpose: f64,
+}
+
+/// Sample rate at which the samples play the middle C, before transposing
+pub const C4_SAMPLE_RATE: u32 = 8363;
+/// Amiga clock used to convert MOD periods to sample rates, chosen so that period 428 plays at C4_SAMPLE_RATE
+pub const AMIGA_CLOCK: f64 = 7_159_090.5;
+/// How far left or right the channels of a MOD are, from 0 (mono) to 1 (hard panning like an Amiga)
+pub const MOD_STEREO_SEPARATION: f64 = 0.5;
+/// Highest volume of a channel
+pub const MAX_VOLUME: u8 = 64;
+
+const MOD_NB_SAMPLES: usize = 31;
+const MOD_NB_ROWS: usize = 64;
+const XM_ID: &[u8; 17] = b"Extended Module: ";
+const XM_KEY_OFF: u8 = 97;
+/// Size of an XM instrument header up to its volume envelope
+const XM_INSTRUMENT_HEADER_SIZE: usize = 263;
+/// Limits of FastTracker 2
+const XM_MAX_CHANNELS: usize = 32;
+const XM_MAX_ROWS: usize = 256;
+const XM_MAX_PATTERNS: usize = 256;
+const XM_MAX_INSTRUMENTS: usize = 128;
+
+const EFFECT_TONE_PORTAMENTO: u8 = 0x3;
+const EFFECT_TONE_PORTAMENTO_VOLUME_SLIDE: u8 = 0x5;
+const EFFECT_VIBRATO_VOLUME_SLIDE: u8 = 0x6;
+const EFFECT_SET_PANNING: u8 = 0x8;
+const EFFECT_VOLUME_SLIDE: u8 = 0xA;
+const EFFECT_POSITION_JUMP: u8 = 0xB;
+const EFFECT_SET_VOLUME: u8 = 0xC;
+const EFFECT_PATTERN_BREAK: u8 = 0xD;
+const EFFECT_EXTENDED: u8 = 0xE;
+const EFFECT_SET_SPEED: u8 = 0xF;
+const EFFECT_KEY_OFF: u8 = 0x14;
+const EXTENDED_FINE_VOLUME_UP: u8 = 0xA;
+const EXTENDED_FINE_VOLUME_DOWN: u8 = 0xB;
+const EXTENDED_NOTE_CUT: u8 = 0xC;
+const EXTENDED_NOTE_DELAY: u8 = 0xD;
+
+/// A note being played by a channel
+struct PlayingNote {
+    /// When the note started, in seconds
+    start: f64,
+    key: usize,
+    sample: usize,
+    pan: f64,
+    gain: Vec<GainPoint>,
+}
+
+/// When a row is played
+#[derive(Clone, Copy)]
+struct RowTiming {
+    /// Start of the row in seconds
+    time: f64,
+    /// Duration of a tick in seconds
+    tick: f64,
+    /// Number of ticks of the row
+    speed: u32,
+}
+
+/// Where a channel is at while the module is played
+struct ChannelState {
+    instrument: Option<usize>,
+    /// Key of the last note, used when only an instrument is given
+    key: Option<usize>,
+    /// Volume in [0; 1]
+    volume: f64,
+    pan: f64,
+    /// Last parameter of a volume slide, used by the slides without one
+    last_slide: u8,
+    note: Option<PlayingNote>,
+}
+
+impl Module {
+    /// Reads a MOD or XM module, finding out which one it is
+    pub fn read<R: Read + Seek>(reader: &mut R) -> Result<Module, ReadError> {
+        let mut id = [0u8; 17];
+        let is_xm = match reader.read_exact(&mut id) {
+            Ok(()) => &id == XM_ID,
+            Err(_) => false,
+        };
+        reader.seek(SeekFrom::Start(0))?;
+        if is_xm {
+            Module::read_xm(reader)
+        } else {
+            Module::read_mod(reader)
+        }
+    }
+
+    /// Reads a 31 samples ProTracker module, or one of its variants with more channels
+    pub fn read_mod<R: Read>(reader: &mut R) -> Result<Module, ReadError> {
+        let name = read_text(reader, 20)?;
+        let mut headers = Vec::with_capacity(MOD_NB_SAMPLES);
+        for _ in 0..MOD_NB_SAMPLES {
+            let name = read_text(reader, 22)?;
+            let length = usize::from(reader.read_be_to_u16()?) * 2;
+            // Stored as a signed nibble, in eighths of semitones
+            let finetune = ((reader.read_to_u8()? << 4) as i8) >> 4;
+            let volume = reader.read_to_u8()?.min(MAX_VOLUME);
+            let loop_start = usize::from(reader.read_be_to_u16()?) * 2;
+            let loop_length = usize::from(reader.read_be_to_u16()?) * 2;
+            headers.push((name, length, finetune, volume, loop_start, loop_length));
+        }
+        let song_length = usize::from(reader.read_to_u8()?);
+        let restart_position = usize::from(reader.read_to_u8()?);
+        let mut order = [0u8; 128];
+        reader.read_exact(&mut order)?;
+        let mut signature = [0u8; 4];
+        reader.read_exact(&mut signature)?;
+        let nb_channels = match &signature {
+            b"M.K." | b"M!K!" | b"M&K!" | b"FLT4" | b"4CHN" => 4,
+            b"FLT8" | b"CD81" | b"OKTA" | b"OCTA" => 8,
+            [c, b'C', b'H', b'N'] if c.is_ascii_digit() => usize::from(c - b'0'),
+            [c1, c2, b'C', b'H'] | [c1, c2, b'C', b'N']
+                if c1.is_ascii_digit() && c2.is_ascii_digit() =>
+            {
+                usize::from(c1 - b'0') * 10 + usize::from(c2 - b'0')
+            }
+            _ => {
+                return Err(MalformedModuleError {
+                    reason: "Unknown MOD signature",
+                }
+                .into())
+            }
+        };
+        if (song_length == 0) | (song_length > 128) | (nb_channels == 0) {
+            return Err(MalformedModuleError {
+                reason: "Invalid song length or number of channels",
+            }
+            .into());
+        }
+        // Every pattern of the table is stored, even after the song length
+        let nb_patterns = order.iter().map(|&p| usize::from(p) + 1).max().unwrap_or(1);
+        let mut patterns = Vec::with_capacity(nb_patterns);
+        for _ in 0..nb_patterns {
+            let mut rows = Vec::with_capacity(MOD_NB_ROWS);
+            for _ in 0..MOD_NB_ROWS {
+                let mut cells = Vec::with_capacity(nb_channels);
+                for _ in 0..nb_channels {
+                    let mut data = [0u8; 4];
+                    reader.read_exact(&mut data)?;
+                    let period = (u16::from(data[0] & 0x0F) << 8) | u16::from(data[1]);
+                    let instrument = (data[0] & 0xF0) | (data[2] >> 4);
+                    cells.push(Cell {
+                        note: get_period_key(period),
+                        key_off: false,
+                        instrument: match instrument {
+                            0 => None,
+                            i => Some(usize::from(i) - 1),
+                        },
+                        volume: 0,
+                        effect: data[2] & 0x0F,
+                        param: data[3],
+                    });
+                }
+                rows.push(cells);
+            }
+            patterns.push(rows);
+        }
+        let mut samples = Vec::with_capacity(MOD_NB_SAMPLES);
+        let mut instruments = Vec::with_capacity(MOD_NB_SAMPLES);
+        for (index, (name, length, finetune, volume, loop_start, loop_length)) in
+            headers.into_iter().enumerate()
+        {
+            // The last samples of some modules are cut short
+            let mut data = Vec::with_capacity(length);
+            reader.by_ref().take(length as u64).read_to_end(&mut data)?;
+            let pcm_samples = data
+                .iter()
+                .map(|&b| f64::from(b as i8) / 128f64)
+                .collect::<Vec<f64>>();
+            let loop_points = if loop_length > 2 {
+                Some((loop_start, loop_start + loop_length))
+            } else {
+                None
+            };
+            instruments.push(ModuleInstrument {
+                name: name.clone(),
+                sample_map: vec![Some(index); 96],
+            });
+            samples.push(ModuleSample {
+                name,
+                pcm: make_pcm(pcm_samples, loop_points)?,
+                ping_pong: false,
+                volume: f64::from(volume) / f64::from(MAX_VOLUME),
+                pan: None,
+                transpose: f64::from(finetune) / 8f64,
+            });
+        }
+        let channel_pan = (0..nb_channels)
+            .map(|channel| match channel % 4 {
+                0 | 3 => -MOD_STEREO_SEPARATION,
+                _ => MOD_STEREO_SEPARATION,
+            })
+            .collect();
+        Ok(Module {
+            name,
+            nb_channels,
+            order: order[..song_length]
+                .iter()
+                .map(|&p| usize::from(p))
+                .collect(),
+            restart_position: if restart_position < song_length {
+                restart_position
+            } else {
+                0
+            },
+            patterns,
+            instruments,
+            samples,
+            channel_pan,
+            initial_speed: 6,
+            initial_tempo: 125,
+        })
+    }
+
+    /// Reads a FastTracker 2 module, version 1.04
+    pub fn read_xm<R: Read>(reader: &mut R) -> Result<Module, ReadError> {
+        let mut id = [0u8; 17];
+        reader.read_exact(&mut id)?;
+        if &id != XM_ID {
+            return Err(MalformedModuleError {
+                reason: "Not an XM module",
+            }
+            .into());
+        }
+        let name = read_text(reader, 20)?;
+        let mut tracker = [0u8; 21];
+        reader.read_exact(&mut tracker)?;
+        if reader.read_le_to_u16()? < 0x0104 {
+            return Err(MalformedModuleError {
+                reason: "XM versions before 1.04 are not supported",
+            }
+            .into());
+        }
+        let header_size = reader.read_le_to_u32()? as usize;
+        let header = read_padded(reader, header_size.saturating_sub(4), 256 + 16)?;
+        let mut header = Cursor::new(header);
+        let song_length = usize::from(header.read_le_to_u16()?);
+        let restart_position = usize::from(header.read_le_to_u16()?);
+        let nb_channels = usize::from(header.read_le_to_u16()?);
+        let nb_patterns = usize::from(header.read_le_to_u16()?);
+        let nb_instruments = usize::from(header.read_le_to_u16()?);
+        let _flags = header.read_le_to_u16()?;
+        let initial_speed = u32::from(header.read_le_to_u16()?);
+        let initial_tempo = u32::from(header.read_le_to_u16()?);
+        let mut order = [0u8; 256];
+        header.read_exact(&mut order)?;
+        if (song_length == 0)
+            | (song_length > 256)
+            | (nb_channels == 0)
+            | (nb_channels > XM_MAX_CHANNELS)
+        {
+            return Err(MalformedModuleError {
+                reason: "Invalid song length or number of channels",
+            }
+            .into());
+        }
+        if (nb_patterns > XM_MAX_PATTERNS) | (nb_instruments > XM_MAX_INSTRUMENTS) {
+            return Err(MalformedModuleError {
+                reason: "Too many patterns or instruments",
+            }
+            .into());
+        }
+        let mut patterns = Vec::with_capacity(nb_patterns);
+        for _ in 0..nb_patterns {
+            let pattern_header_size = reader.read_le_to_u32()? as usize;
+            let pattern_header = read_padded(reader, pattern_header_size.saturating_sub(4), 5)?;
+            let mut pattern_header = Cursor::new(pattern_header);
+            let _packing = pattern_header.read_to_u8()?;
+            let nb_rows = usize::from(pattern_header.read_le_to_u16()?);
+            if (nb_rows == 0) | (nb_rows > XM_MAX_ROWS) {
+                return Err(MalformedModuleError {
+                    reason: "Invalid number of rows in a pattern",
+                }
+                .into());
+            }
+            let data_size = u64::from(pattern_header.read_le_to_u16()?);
+            let mut data = Vec::new();
+            reader.by_ref().take(data_size).read_to_end(&mut data)?;
+            patterns.push(read_xm_pattern(&data, nb_rows, nb_channels)?);
+        }
+        let mut instruments = Vec::with_capacity(nb_instruments);
+        let mut samples = Vec::new();
+        for _ in 0..nb_instruments {
+            let instrument_size = reader.read_le_to_u32()? as usize;
+            let instrument_header = read_padded(
+                reader,
+                instrument_size.saturating_sub(4),
+                XM_INSTRUMENT_HEADER_SIZE,
+            )?;
+            let mut instrument_header = Cursor::new(instrument_header);
+            let name = read_text(&mut instrument_header, 22)?;
+            let _kind = instrument_header.read_to_u8()?;
+            let nb_samples = usize::from(instrument_header.read_le_to_u16()?);
+            let _sample_header_size = instrument_header.read_le_to_u32()?;
+            let mut keymap = [0u8; 96];
+            instrument_header.read_exact(&mut keymap)?;
+            let mut headers = Vec::with_capacity(nb_samples);
+            for _ in 0..nb_samples {
+                let length = reader.read_le_to_u32()? as usize;
+                let loop_start = reader.read_le_to_u32()? as usize;
+                let loop_length = reader.read_le_to_u32()? as usize;
+                let volume = reader.read_to_u8()?.min(MAX_VOLUME);
+                let finetune = reader.read_to_u8()? as i8;
+                let kind = reader.read_to_u8()?;
+                let pan = reader.read_to_u8()?;
+                let relative_note = reader.read_to_u8()? as i8;
+                let _reserved = reader.read_to_u8()?;
+                let sample_name = read_text(reader, 22)?;
+                headers.push((
+                    sample_name,
+                    length,
+                    loop_start,
+                    loop_length,
+                    volume,
+                    finetune,
+                    kind,
+                    pan,
+                    relative_note,
+                ));
+            }
+            let first_sample = samples.len();
+            for (
+                sample_name,
+                length,
+                loop_start,
+                loop_length,
+                volume,
+                finetune,
+                kind,
+                pan,
+                relative_note,
+            ) in headers
+            {
+                // The length is not trusted for allocation, as corrupt modules can declare gigabytes
+                let mut data = Vec::new();
+                reader.by_ref().take(length as u64).read_to_end(&mut data)?;
+                let sixteen_bits = kind & 0x10 != 0;
+                let pcm_samples = decode_xm_sample(&data, sixteen_bits);
+                let (loop_start, loop_length) = if sixteen_bits {
+                    (loop_start / 2, loop_length / 2)
+                } else {
+                    (loop_start, loop_length)
+                };
+                let loop_points = if (kind & 0x3 != 0) & (loop_length > 0) {
+                    Some((loop_start, loop_start + loop_length))
+                } else {
+                    None
+                };
+                samples.push(ModuleSample {
+                    name: sample_name,
+                    pcm: make_pcm(pcm_samples, loop_points)?,
+                    ping_pong: kind & 0x3 == 2,
+                    volume: f64::from(volume) / f64::from(MAX_VOLUME),
+                    pan: Some((f64::from(pan) - 128f64) / 128f64),
+                    transpose: f64::from(relative_note) + f64::from(finetune) / 128f64,
+                });
+            }
+            instruments.push(ModuleInstrument {
+                name,
+                sample_map: keymap
+                    .iter()
+                    .map(|&sample| {
+                        let sample = usize::from(sample);
+                        if sample < nb_samples {
+                            Some(first_sample + sample)
+                        } else {
+                            None
+                        }
+                    })
+                    .collect(),
+            });
+        }
+        Ok(Module {
+            name,
+            nb_channels,
+            order: order[..song_length]
+                .iter()
+                .map(|&p| usize::from(p))
+                .collect(),
+            restart_position: if restart_position < song_length {
+                restart_position
+            } else {
+                0
+            },
+            patterns,
+            instruments,
+            samples,
+            channel_pan: vec![0f64; nb_channels],
+            initial_speed,
+            initial_tempo,
+        })
+    }
+
+    /// Plays the module into a Sequence.
+    /// The Instrument IDs are the indexes of the samples, and the Frequency IDs are MIDI note numbers, usable with MIDIFrequencyLookup.
+    /// Volume slides and changes, note cuts and delays, speed and tempo changes, pattern breaks and position jumps are followed.
+    /// When the module comes back to a row it already played, the loop is added to the Sequence and the playing stops.
+    /// Other effects, the envelopes and the vibrato of XM instruments are ignored.
+    /// # Arguments
+    /// * nb_channels - The number of channels of the final output, stereo uses the panning of the module
+    pub fn to_sequence(&self, nb_channels: usize) -> SynthesizerResult<Sequence> {
+        let mut sequence = Sequence::new();
+        let mut channels = self
+            .channel_pan
+            .iter()
+            .map(|&pan| ChannelState {
+                instrument: None,
+                key: None,
+                volume: 1f64,
+                pan,
+                last_slide: 0,
+                note: None,
+            })
+            .collect::<Vec<ChannelState>>();
+        let mut speed = self.initial_speed.max(1);
+        let mut tempo = self.initial_tempo.max(1);
+        let mut time = 0f64;
+        // When every (order position, row) was first played
+        let mut played = HashMap::new();
+        let (mut position, mut row) = (0, 0);
+        // Positions gone through without playing a row, to stop if none of them can be played
+        let mut skipped = 0;
+        loop {
+            if skipped > self.order.len() {
+                break;
+            }
+            if position >= self.order.len() {
+                position = self.restart_position;
+                row = 0;
+            }
+            let pattern = match self.patterns.get(self.order[position]) {
+                Some(pattern) if row < pattern.len() => pattern,
+                _ => {
+                    position += 1;
+                    row = 0;
+                    skipped += 1;
+                    continue;
+                }
+            };
+            if let Some(&loop_start) = played.get(&(position, row)) {
+                if let Ok(span) = TimeSpan::new(Time::new(loop_start)?, Time::new(time)?) {
+                    sequence.loop_info.push(span);
+                }
+                break;
+            }
+            played.insert((position, row), time);
+            let cells = &pattern[row];
+            // The speed and the jumps apply to the whole row
+            let (mut jump_position, mut jump_row) = (None, None);
+            for cell in cells {
+                match cell.effect {
+                    EFFECT_SET_SPEED if cell.param == 0 => {}
+                    EFFECT_SET_SPEED if cell.param < 32 => speed = u32::from(cell.param),
+                    EFFECT_SET_SPEED => tempo = u32::from(cell.param),
+                    EFFECT_POSITION_JUMP => jump_position = Some(usize::from(cell.param)),
+                    EFFECT_PATTERN_BREAK => {
+                        jump_row =
+                            Some(usize::from(cell.param >> 4) * 10 + usize::from(cell.param & 0x0F))
+                    }
+                    _ => {}
+                }
+            }
+            let timing = RowTiming {
+                time,
+                tick: 2.5f64 / f64::from(tempo),
+                speed,
+            };
+            for (cell, channel) in cells.iter().zip(channels.iter_mut()) {
+                self.play_cell(cell, channel, timing, nb_channels, &mut sequence)?;
+            }
+            skipped = 0;
+            time += f64::from(speed) * timing.tick;
+            if jump_position.is_some() | jump_row.is_some() {
+                position = jump_position.unwrap_or(position + 1);
+                row = jump_row.unwrap_or(0);
+            } else {
+                row += 1;
+            }
+        }
+        for channel in &mut channels {
+            self.stop_note(channel, time, nb_channels, &mut sequence)?;
+        }
+        Ok(sequence)
+    }
+
+    /// Creates an Instrument for every sample, its Instrument ID being the index of the sample
+    pub fn to_instruments(&self) -> SynthesizerResult<HashMap<usize, Instrument>> {
+        let mut instruments = HashMap::new();
+        for (i_id, sample) in self.samples.iter().enumerate() {
+            instruments.insert(
+                i_id,
+                Instrument::new(
+                    Box::new(SampleKeyGenerator {
+                        audio: sample.get_forward_audio()?,
+                        root: Frequency::new(
+                            440f64 * 2f64.powf((-9f64 - sample.transpose) / 12f64),
+                        )?,
+                    }),
+                    false,
+                ),
+            );
+        }
+        Ok(instruments)
+    }
+
+    /// Creates a Synthesizer ready to play the module
+    /// # Arguments
+    /// * params - The parameters of the final output
+    pub fn to_synthesizer(&self, params: PCMParameters) -> SynthesizerResult<Synthesizer> {
+        Ok(Synthesizer {
+            seq: self.to_sequence(usize::from(params.nb_channels))?,
+            inst: self.to_instruments()?,
+            f_lu: Box::new(MIDIFrequencyLookup {}),
+            params,
+        })
+    }
+
+    /// Plays the cell of a channel on a row
+    fn play_cell(
+        &self,
+        cell: &Cell,
+        channel: &mut ChannelState,
+        timing: RowTiming,
+        nb_channels: usize,
+        sequence: &mut Sequence,
+    ) -> SynthesizerResult<()> {
+        let RowTiming { time, tick, speed } = timing;
+        let extended = if cell.effect == EFFECT_EXTENDED {
+            Some((cell.param >> 4, u32::from(cell.param & 0x0F)))
+        } else {
+            None
+        };
+        let delay = match extended {
+            Some((EXTENDED_NOTE_DELAY, ticks)) => ticks,
+            _ => 0,
+        };
+        if delay >= speed {
+            return Ok(());
+        }
+        let start = time + f64::from(delay) * tick;
+        if let Some(instrument) = cell.instrument {
+            channel.instrument = Some(instrument);
+        }
+        // Sliding to a note keeps playing the previous one
+        let portamento = match cell.effect {
+            EFFECT_TONE_PORTAMENTO | EFFECT_TONE_PORTAMENTO_VOLUME_SLIDE => true,
+            _ => cell.volume >= 0xF0,
+        };
+        let key = match cell.note {
+            Some(key) if !portamento => {
+                channel.key = Some(key);
+                Some(key)
+            }
+            _ => None,
+        };
+        // An instrument resets the volume and panning to the ones of its sample
+        let sample = channel
+            .instrument
+            .and_then(|instrument| self.instruments.get(instrument))
+            .and_then(|instrument| {
+                channel
+                    .key
+                    .and_then(|key| key.checked_sub(12))
+                    .and_then(|index| instrument.sample_map.get(index))
+            })
+            .and_then(|&sample| sample);
+        if let (Some(_), Some(sample)) = (cell.instrument, sample) {
+            channel.volume = self.samples[sample].volume;
+            if let Some(pan) = self.samples[sample].pan {
+                channel.pan = pan;
+            }
+        }
+        if let Some(key) = key {
+            self.stop_note(channel, start, nb_channels, sequence)?;
+            if let Some(sample) = sample {
+                if !self.samples[sample].pcm.samples.is_empty() {
+                    channel.note = Some(PlayingNote {
+                        start,
+                        key,
+                        sample,
+                        pan: channel.pan,
+                        gain: vec![GainPoint {
+                            at: Time::new(0f64)?,
+                            factor: channel.volume,
+                        }],
+                    });
+                }
+            }
+        } else if cell.instrument.is_some() {
+            set_volume(channel, start, channel.volume)?;
+        }
+        if cell.key_off {
+            self.stop_note(channel, start, nb_channels, sequence)?;
+        }
+        // Volume column of XM modules
+        let slide = match cell.volume {
+            0x10..=0x50 => {
+                set_volume(
+                    channel,
+                    start,
+                    f64::from(cell.volume - 0x10) / f64::from(MAX_VOLUME),
+                )?;
+                0
+            }
+            0x60..=0x6F => -i32::from(cell.volume & 0x0F),
+            0x70..=0x7F => i32::from(cell.volume & 0x0F),
+            0x80..=0x8F => {
+                let volume = channel.volume - f64::from(cell.volume & 0x0F) / f64::from(MAX_VOLUME);
+                set_volume(channel, start, volume)?;
+                0
+            }
+            0x90..=0x9F => {
+                let volume = channel.volume + f64::from(cell.volume & 0x0F) / f64::from(MAX_VOLUME);
+                set_volume(channel, start, volume)?;
+                0
+            }
+            0xC0..=0xCF => {
+                channel.pan = (f64::from(cell.volume & 0x0F) * 17f64 - 128f64) / 128f64;
+                0
+            }
+            _ => 0,
+        };
+        slide_volume(channel, timing, slide)?;
+        match cell.effect {
+            EFFECT_SET_VOLUME => set_volume(
+                channel,
+                start,
+                f64::from(cell.param.min(MAX_VOLUME)) / f64::from(MAX_VOLUME),
+            )?,
+            EFFECT_VOLUME_SLIDE
+            | EFFECT_TONE_PORTAMENTO_VOLUME_SLIDE
+            | EFFECT_VIBRATO_VOLUME_SLIDE => {
+                if cell.param != 0 {
+                    channel.last_slide = cell.param;
+                }
+                let slide = if channel.last_slide >> 4 != 0 {
+                    i32::from(channel.last_slide >> 4)
+                } else {
+                    -i32::from(channel.last_slide & 0x0F)
+                };
+                slide_volume(channel, timing, slide)?;
+            }
+            EFFECT_SET_PANNING => channel.pan = (f64::from(cell.param) - 128f64) / 128f64,
+            EFFECT_KEY_OFF if u32::from(cell.param) < speed => {
+                let at = time + f64::from(cell.param) * tick;
+                self.stop_note(channel, at, nb_channels, sequence)?;
+            }
+            _ => {}
+        }
+        match extended {
+            Some((EXTENDED_FINE_VOLUME_UP, amount)) => {
+                let volume = channel.volume + f64::from(amount) / f64::from(MAX_VOLUME);
+                set_volume(channel, start, volume)?;
+            }
+            Some((EXTENDED_FINE_VOLUME_DOWN, amount)) => {
+                let volume = channel.volume - f64::from(amount) / f64::from(MAX_VOLUME);
+                set_volume(channel, start, volume)?;
+            }
+            Some((EXTENDED_NOTE_CUT, ticks)) if ticks < speed => {
+                let at = time + f64::from(ticks) * tick;
+                channel.volume = 0f64;
+                self.stop_note(channel, at, nb_channels, sequence)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Stops the note of a channel and adds it to the Sequence. A sample that does not loop ends the note early.
+    fn stop_note(
+        &self,
+        channel: &mut ChannelState,
+        time: f64,
+        nb_channels: usize,
+        sequence: &mut Sequence,
+    ) -> SynthesizerResult<()> {
+        let note = match channel.note.take() {
+            Some(note) => note,
+            None => return Ok(()),
+        };
+        let sample = &self.samples[note.sample];
+        let mut end = time;
+        if sample.pcm.loop_info.is_empty() {
+            let sample_rate = f64::from(C4_SAMPLE_RATE)
+                * 2f64.powf((note.key as f64 + sample.transpose - 60f64) / 12f64);
+            end = end.min(note.start + sample.pcm.samples.len() as f64 / sample_rate);
+        }
+        let vol = if nb_channels == 2 {
+            vec![
+                Volume::new((1f64 - note.pan).min(1f64))?,
+                Volume::new((1f64 + note.pan).min(1f64))?,
+            ]
+        } else {
+            vec![Volume::new(1f64)?; nb_channels]
+        };
+        // Ignore notes that have a duration of 0 seconds
+        if let Ok(t_span) = TimeSpan::new(Time::new(note.start)?, Time::new(end)?) {
+            sequence.add_note(Note {
+                t_span,
+                vol,
+                f_id: note.key,
+                i_id: note.sample,
+                pitch: Vec::new(),
+                gain: note.gain,
+                velocity: None,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl ModuleSample {
+    /// Returns the audio of the sample, a ping-pong loop being written out as a loop that only goes forward
+    pub fn get_forward_audio(&self) -> Result<PCM, TimeInvalidError> {
+        let rate = f64::from(C4_SAMPLE_RATE);
+        let (start, end) = match self.pcm.loop_info.first() {
+            Some(span) if self.ping_pong => (
+                (span.start_at().get() * rate).round() as usize,
+                ((span.end_at().get() * rate).round() as usize).min(self.pcm.samples.len()),
+            ),
+            _ => return Ok(self.pcm.clone()),
+        };
+        if end < start + 3 {
+            return Ok(self.pcm.clone());
+        }
+        // The ends of the loop are not played twice when turning around
+        let mut samples = self.pcm.samples[..end].to_vec();
+        samples.extend(self.pcm.samples[start + 1..end - 1].iter().rev());
+        let loop_end = samples.len();
+        Ok(PCM {
+            parameters: self.pcm.parameters,
+            loop_info: vec![TimeSpan::new(
+                Time::new(start as f64 / rate)?,
+                Time::new(loop_end as f64 / rate)?,
+            )?],
+            samples,
+        })
+    }
+}
+
+/// Places the samples of a module in a PCM at C4_SAMPLE_RATE
+/// # Arguments
+/// * samples - The samples in [-1; 1]
+/// * loop_points - Start and end of the loop in samples, if there is one
+fn make_pcm(samples: Vec<f64>, loop_points: Option<(usize, usize)>) -> Result<PCM, ReadError> {
+    let mut loop_info = Vec::new();
+    if let Some((start, end)) = loop_points {
+        let end = end.min(samples.len());
+        if end > start {
+            let rate = f64::from(C4_SAMPLE_RATE);
+            loop_info.push(TimeSpan::new(
+                Time::new(start as f64 / rate)?,
+                Time::new(end as f64 / rate)?,
+            )?);
+        }
+    }
+    Ok(PCM {
+        parameters: PCMParameters {
+            sample_rate: C4_SAMPLE_RATE,
+            nb_channels: 1,
+            speaker_layout: SpeakerLayout::Unspecified,
+        },
+        loop_info,
+        samples,
+    })
+}
+
+/// Finds the MIDI key of a MOD period, None for 0
+fn get_period_key(period: u16) -> Option<usize> {
+    if period == 0 {
+        return None;
+    }
+    let sample_rate = AMIGA_CLOCK / (2f64 * f64::from(period));
+    let key = 60f64 + 12f64 * (sample_rate / f64::from(C4_SAMPLE_RATE)).log2();
+    if key < 0f64 {
+        None
+    } else {
+        Some(key.round() as usize)
+    }
+}
+
+/// Unpacks the cells of an XM pattern
+fn read_xm_pattern(
+    data: &[u8],
+    nb_rows: usize,
+    nb_channels: usize,
+) -> Result<Vec<Vec<Cell>>, ReadError> {
+    // Empty patterns have no data at all
+    if data.is_empty() {
+        return Ok(vec![vec![Cell::default(); nb_channels]; nb_rows]);
+    }
+    // Rows are only built from the data that is actually there
+    let mut rows = Vec::new();
+    let mut reader = Cursor::new(data);
+    for _ in 0..nb_rows {
+        let mut row = Vec::with_capacity(nb_channels);
+        for _ in 0..nb_channels {
+            let first = reader.read_to_u8()?;
+            // The highest bit tells which of the fields follow, otherwise all of them do
+            let (flags, note) = if first & 0x80 != 0 {
+                let note = if first & 0x01 != 0 {
+                    reader.read_to_u8()?
+                } else {
+                    0
+                };
+                (first, note)
+            } else {
+                (0x1F, first)
+            };
+            let mut read_field = |flag: u8| -> Result<u8, ReadError> {
+                if flags & flag != 0 {
+                    Ok(reader.read_to_u8()?)
+                } else {
+                    Ok(0)
+                }
+            };
+            let instrument = read_field(0x02)?;
+            let volume = read_field(0x04)?;
+            let effect = read_field(0x08)?;
+            let param = read_field(0x10)?;
+            row.push(Cell {
+                note: match note {
+                    1..=96 => Some(usize::from(note) + 11),
+                    _ => None,
+                },
+                key_off: note == XM_KEY_OFF,
+                instrument: match instrument {
+                    0 => None,
+                    i => Some(usize::from(i) - 1),
+                },
+                volume,
+                effect,
+                param,
+            });
+        }
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+/// Decodes the samples of an XM module, stored as differences between samples
+fn decode_xm_sample(data: &[u8], sixteen_bits: bool) -> Vec<f64> {
+    if sixteen_bits {
+        let mut old = 0i16;
+        data.chunks(2)
+            .filter(|bytes| bytes.len() == 2)
+            .map(|bytes| {
+                old = old.wrapping_add(i16::from(bytes[0]) | (i16::from(bytes[1] as i8) << 8));
+                f64::from(old) / 32768f64
+            })
+            .collect()
+    } else {
+        let mut old = 0i8;
+        data.iter()
+            .map(|&byte| {
+                old = old.wrapping_add(byte as i8);
+                f64::from(old) / 128f64
+            })
+            .collect()
+    }
+}
+
+/// Reads a header of a given size, filling with zeros what is missing to reach a minimum size
+fn read_padded<R: Read>(reader: &mut R, size: usize, minimum: usize) -> Result<Vec<u8>, ReadError> {
+    let mut data = read_chunk_data(reader, size as u64)?;
+    if data.len() < minimum {
+        data.resize(minimum, 0);
+    }
+    Ok(data)
+}
+
+/// Reads text padded with zeros or spaces
+fn read_text<R: Read>(reader: &mut R, size: usize) -> Result<String, ReadError> {
+    let mut data = vec![0u8; size];
+    reader.read_exact(&mut data)?;
+    Ok(String::from_utf8_lossy(&data)
+        .trim_end_matches(|c| (c == '\0') | (c == ' '))
+        .to_string())
+}
+
+/// Changes the volume of a channel, and of its note if there is one
+fn set_volume(channel: &mut ChannelState, time: f64, volume: f64) -> SynthesizerResult<()> {
+    channel.volume = volume.min(1f64).max(0f64);
+    if let Some(ref mut note) = channel.note {
+        let at = Time::new((time - note.start).max(0f64))?;
+        match note.gain.last_mut() {
+            // Only keep the last change that happened at a same time
+            Some(ref mut last) if last.at.get() >= at.get() => last.factor = channel.volume,
+            _ => note.gain.push(GainPoint {
+                at,
+                factor: channel.volume,
+            }),
+        }
+    }
+    Ok(())
+}
+
+/// Changes the volume of a channel on every tick of a row but the first one
+/// # Arguments
+/// * channel - The channel to change
+/// * timing - When the row is played
+/// * slide - What is added to the volume every tick, out of MAX_VOLUME
+fn slide_volume(
+    channel: &mut ChannelState,
+    timing: RowTiming,
+    slide: i32,
+) -> SynthesizerResult<()> {
+    if slide == 0 {
+        return Ok(());
+    }
+    for tick_nb in 1..timing.speed {
+        let volume = channel.volume + f64::from(slide) / f64::from(MAX_VOLUME);
+        set_volume(
+            channel,
+            timing.time + f64::from(tick_nb) * timing.tick,
+            volume,
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Instrument ID, start, end and Frequency ID of every note, sorted
+    fn get_notes(sequence: &Sequence) -> Vec<(usize, f64, f64, usize)> {
+        let mut notes: Vec<(usize, f64, f64, usize)> = sequence
+            .notes
+            .iter()
+            .map(|note| {
+                (
+                    note.i_id,
+                    note.t_span.start_at().get(),
+                    note.t_span.end_at().get(),
+                    note.f_id,
+                )
+            })
+            .collect();
+        notes.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        notes
+    }
+
+    /// Checks the notes and the loop of a Sequence, rounding times to the millisecond
+    fn check_sequence(
+        sequence: &Sequence,
+        notes: &[(usize, f64, f64, usize)],
+        loop_span: (f64, f64),
+    ) {
+        let round = |time: f64| (time * 1000f64).round() / 1000f64;
+        let found = get_notes(sequence)
+            .into_iter()
+            .map(|(i_id, start, end, f_id)| (i_id, round(start), round(end), f_id))
+            .collect::<Vec<(usize, f64, f64, usize)>>();
+        assert_eq!(found, notes);
+        assert_eq!(sequence.loop_info.len(), 1);
+        assert_eq!(
+            (
+                round(sequence.loop_info[0].start_at().get()),
+                round(sequence.loop_info[0].end_at().get())
+            ),
+            loop_span
+        );
+    }
+
+    #[test]
+    fn mod_pattern_break_and_position_jump() {
+        let mut data = vec![0u8; 20];
+        for index in 0..MOD_NB_SAMPLES {
+            let mut header = vec![0u8; 30];
+            if index == 0 {
+                header[23] = 2; // Length in words
+                header[25] = 64; // Volume
+                header[29] = 2; // Loop length in words, so that notes last until they are stopped
+            }
+            data.extend(header);
+        }
+        data.extend(&[2, 0]); // Song length, restart position
+        let mut order = [0u8; 128];
+        order[1] = 1;
+        data.extend(&order[..]);
+        data.extend(b"M.K.");
+        // Period, instrument, effect and parameter of a cell
+        let cell = |period: u16, instrument: u8, effect: u8, param: u8| {
+            vec![
+                (instrument & 0xF0) | (period >> 8) as u8,
+                period as u8,
+                (instrument << 4) | effect,
+                param,
+            ]
+        };
+        for pattern in 0..2 {
+            for row in 0..MOD_NB_ROWS {
+                for channel in 0..4 {
+                    data.extend(match (pattern, row, channel) {
+                        (0, 0, 0) => cell(428, 1, 0, 0),
+                        (0, 1, 1) => cell(214, 1, 0, 0),
+                        (0, 2, 0) => cell(0, 0, EFFECT_PATTERN_BREAK, 0x01),
+                        (1, 1, 0) => cell(428, 1, 0, 0),
+                        (1, 3, 2) => cell(0, 0, EFFECT_POSITION_JUMP, 0),
+                        _ => cell(0, 0, 0, 0),
+                    });
+                }
+            }
+        }
+        data.extend(&[0, 64, 0, 192]);
+        let module = Module::read(&mut Cursor::new(data)).unwrap();
+        assert_eq!(module.nb_channels, 4);
+        assert_eq!(module.order, vec![0, 1]);
+        // A row lasts 6 ticks of 0.02 seconds
+        check_sequence(
+            &module.to_sequence(2).unwrap(),
+            &[
+                (0, 0f64, 0.36, 60),
+                (0, 0.12, 0.72, 72),
+                (0, 0.36, 0.72, 60),
+            ],
+            (0f64, 0.72),
+        );
+    }
+
+    #[test]
+    fn xm_pattern_break_and_position_jump() {
+        let mut data = Vec::new();
+        data.extend(XM_ID);
+        data.extend(&[0u8; 20]);
+        data.push(0x1A);
+        data.extend(&[b' '; 20]);
+        data.extend(&[0x04, 0x01]);
+        data.extend(&276u32.to_le_bytes());
+        // Song length, restart position, channels, patterns, instruments, flags, speed and tempo
+        for value in &[1u16, 0, 2, 1, 1, 1, 3, 125] {
+            data.extend(&value.to_le_bytes());
+        }
+        data.extend(&[0u8; 256]);
+        let pattern = [
+            0x83,
+            49,
+            1,
+            0x80, // C-4 with the first instrument
+            0x80,
+            0x83,
+            61,
+            1, // C-5 with the first instrument
+            0x81,
+            XM_KEY_OFF,
+            0x80, // Key off
+            0x98,
+            EFFECT_POSITION_JUMP,
+            0,
+            0x98,
+            EFFECT_PATTERN_BREAK,
+            0x02, // Goes to the third row
+        ];
+        data.extend(&9u32.to_le_bytes());
+        data.push(0);
+        data.extend(&4u16.to_le_bytes());
+        data.extend(&(pattern.len() as u16).to_le_bytes());
+        data.extend(&pattern[..]);
+        let mut instrument = vec![0u8; XM_INSTRUMENT_HEADER_SIZE];
+        instrument[..4].copy_from_slice(&(XM_INSTRUMENT_HEADER_SIZE as u32).to_le_bytes());
+        instrument[27] = 1; // Number of samples
+        instrument[29] = 40; // Size of a sample header
+        data.extend(instrument);
+        data.extend(&4u32.to_le_bytes());
+        // Looping on the whole sample, so that notes last until they are stopped
+        data.extend(&0u32.to_le_bytes());
+        data.extend(&4u32.to_le_bytes());
+        data.extend(&[64, 0, 1, 128, 0, 0]);
+        data.extend(&[0u8; 22]);
+        data.extend(&[0, 64, 0, 192]);
+        let module = Module::read(&mut Cursor::new(data)).unwrap();
+        assert_eq!(module.nb_channels, 2);
+        assert_eq!(module.patterns[0].len(), 4);
+        // A row lasts 3 ticks of 0.02 seconds
+        check_sequence(
+            &module.to_sequence(2).unwrap(),
+            &[(0, 0f64, 0.12, 60), (0, 0.06, 0.24, 72)],
+            (0.12, 0.24),
+        );
+    }
+}
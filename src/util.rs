@@ -1,4 +1,4 @@
-use error::{ForceInvalidError, TimeInvalidError};
+use error::{ForceInvalidError, ParseError, TimeInvalidError};
 use std::cmp::Ordering;
 use std::result::Result;
 
@@ -108,3 +108,75 @@ impl Volume {
         self.value
     }
 }
+
+/// Reads text character by character, keeping track of the position for errors
+pub(crate) struct TextCursor {
+    chars: Vec<char>,
+    /// Index of the next character
+    index: usize,
+    /// Line of the next character, starting from 1
+    pub line: usize,
+    /// Column of the next character, starting from 1
+    pub column: usize,
+}
+
+impl TextCursor {
+    /// Starts reading at the beginning of a text
+    pub fn new(text: &str) -> TextCursor {
+        TextCursor {
+            chars: text.chars().collect(),
+            index: 0,
+            line: 1,
+            column: 1,
+        }
+    }
+    /// Returns the next character without moving
+    pub fn peek_char(&self) -> Option<char> {
+        self.peek_nth(0)
+    }
+    /// Returns a character further than the next one without moving, 0 being the next one
+    pub fn peek_nth(&self, n: usize) -> Option<char> {
+        self.chars.get(self.index + n).cloned()
+    }
+    /// Returns the next character and moves after it
+    pub fn next_char(&mut self) -> Option<char> {
+        let c = self.peek_char()?;
+        self.index += 1;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+    /// Checks if the next characters are some text, without moving
+    pub fn starts_with(&self, text: &str) -> bool {
+        text.chars()
+            .enumerate()
+            .all(|(i, c)| self.peek_nth(i) == Some(c))
+    }
+    /// Moves after the spaces, tabs and new lines
+    pub fn skip_whitespace(&mut self) {
+        while self.peek_char().map_or(false, char::is_whitespace) {
+            self.next_char();
+        }
+    }
+    /// Reads a positive integer if there is one, too big values are capped
+    pub fn read_number(&mut self) -> Option<u32> {
+        let mut value: Option<u32> = None;
+        while let Some(digit) = self.peek_char().and_then(|c| c.to_digit(10)) {
+            self.next_char();
+            value = Some(value.unwrap_or(0).saturating_mul(10).saturating_add(digit));
+        }
+        value
+    }
+    /// Creates an error at the position of the next character
+    pub fn error(&self, reason: &'static str) -> ParseError {
+        ParseError {
+            line: self.line,
+            column: self.column,
+            reason,
+        }
+    }
+}
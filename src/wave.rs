@@ -1,94 +1,1244 @@
-use error::WriteError;
-use ez_io::WriteE;
-use pcm::PCM;
-use std::io::{Cursor, Write};
+use error::{
+    MalformedChunkError, MissingChunkError, NoSamplesError, ReadError, UnsupportedFormatError,
+    UnsupportedParametersError, WriteError, WrongMagicNumberError,
+};
+use ez_io::{ReadE, WriteE};
+use pcm::{PCMParameters, SpeakerLayout, PCM};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use sequence::{Marker, Sequence};
+use std::collections::HashMap;
+use std::io::{
+    Cursor, Error as IOError, ErrorKind, Read, Result as IOResult, Seek, SeekFrom, Write,
+};
 use std::result::Result;
+use util::{Time, TimeSpan};
 
 /// Represents a Wave File
 pub struct Wave {
     pub pcm: PCM,
     pub sample_type: SampleType,
+    /// How the PCM gets scaled before being written
+    pub gain: Gain,
+    /// How the quantization error is handled when writing integer samples
+    pub dither: Dither,
+    /// Tags and cue points stored along the audio
+    pub metadata: Metadata,
 }
 
+/// Information stored in a Wave file along the audio
+#[derive(Clone, Default)]
+pub struct Metadata {
+    /// LIST/INFO tags as their four letter identifier and their text, see the TAG_ constants for the common ones
+    pub tags: Vec<([u8; 4], String)>,
+    /// Cue points, with their labels
+    pub cue_points: Vec<Marker>,
+}
+
+/// Tag identifier for the title
+pub const TAG_TITLE: [u8; 4] = *b"INAM";
+/// Tag identifier for the artist
+pub const TAG_ARTIST: [u8; 4] = *b"IART";
+/// Tag identifier for the software that created the file
+pub const TAG_SOFTWARE: [u8; 4] = *b"ISFT";
+/// Tag identifier for a comment
+pub const TAG_COMMENT: [u8; 4] = *b"ICMT";
+/// Tag identifier for the creation date
+pub const TAG_DATE: [u8; 4] = *b"ICRD";
+/// Tag identifier for the copyright
+pub const TAG_COPYRIGHT: [u8; 4] = *b"ICOP";
+/// Tag identifier for the genre
+pub const TAG_GENRE: [u8; 4] = *b"IGNR";
+
+/// How samples are brought to the [-1; 1] range of the file
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Gain {
+    /// Divide every sample by the loudest one, nothing ever clips but the loudness depends on the peak
+    NormalizeToPeak,
+    /// Multiply every sample by a fixed factor and cut anything outside of [-1; 1]. Float samples are not cut, they keep their headroom.
+    HardClip(f64),
+    /// Multiply every sample by a fixed factor and smoothly compress anything louder than the knee into [-1; 1]
+    SoftClip(f64),
+}
+
+/// How the quantization error is handled when writing integer samples. Float samples are never dithered.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Dither {
+    /// Samples are simply rounded to the nearest value
+    None,
+    /// Triangular (TPDF) noise with a peak of one step is added before rounding
+    Triangular {
+        /// Seed for the noise, the same seed always produces the same file
+        seed: u64,
+        /// Feed back the quantization error to push the noise towards high frequencies, where it is less audible
+        noise_shaping: bool,
+    },
+}
+
+/// Writes a Wave file block by block, without ever holding the whole PCM in memory.
+/// The sizes in the header get patched when finalizing, and the file becomes an RF64 file if it grows past 4 GiB.
+pub struct WaveStreamWriter<W: Write + Seek> {
+    /// Where the file gets written
+    writer: W,
+    /// Position of the beginning of the file in the writer
+    start: u64,
+    /// The type of sample to use
+    sample_type: SampleType,
+    /// Parameters of the stream being written
+    parameters: PCMParameters,
+    /// How samples are scaled. Normalizing to the peak is impossible here, samples are then expected to already be in [-1; 1].
+    gain: Gain,
+    /// Quantizes the samples following the chosen Dither
+    quantizer: Quantizer,
+    /// How many samples were written so far
+    nb_samples: u64,
+    /// How many samples clipped so far
+    nb_clipped: usize,
+}
+
+/// Brings samples onto the values a SampleType can hold, following a Dither
+pub struct Quantizer {
+    /// The type to quantize for
+    sample_type: SampleType,
+    /// Source of the dither noise, if any
+    rng: Option<StdRng>,
+    /// Is the error fed back into the next sample
+    noise_shaping: bool,
+    /// Last quantization error of each channel
+    errors: Vec<f64>,
+    /// The channel the next sample belongs to
+    channel: usize,
+}
+
+/// Information about what happened when writing a file
+#[derive(Clone, Copy, Debug)]
+pub struct WriteReport {
+    /// How many samples went beyond [-1; 1] after applying the gain
+    pub nb_clipped: usize,
+}
+
+/// Size of the contents of a ds64 Chunk without any table entry
+const RF64_DS64_CHUNK_INTERIOR_SIZE: u32 = 28;
+
+/// Above this, soft clipping starts compressing the signal
+const SOFT_CLIP_KNEE: f64 = 0.8;
+
 /// The type of sample to use
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum SampleType {
     /// 8 Bits Unsigned Integer
     Unsigned8,
     /// 16 Bits Signed Integer
     Signed16,
+    /// 24 Bits Signed Integer
+    Signed24,
     /// 32 bits Signed Integer
     Signed32,
+    /// 32 Bits IEEE Float
+    Float32,
+    /// 64 Bits IEEE Float
+    Float64,
+}
+
+/// Format tag for integer PCM data
+pub const WAVE_FORMAT_PCM: u16 = 1;
+/// Format tag for floating point PCM data
+pub const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+/// Format tag for data described by a sub-format and a channel mask
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+/// The part of the sub-format GUID that comes after the format tag
+const WAVE_FORMAT_EXTENSIBLE_GUID_TAIL: [u8; 14] = [
+    0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xAA, 0x00, 0x38, 0x9B, 0x71,
+];
+
+/// Layout of the audio data, as described by the Format Chunk
+struct Format {
+    sample_type: SampleType,
+    nb_channels: u16,
+    sample_rate: u32,
+    block_align: u16,
+    speaker_layout: SpeakerLayout,
 }
 
 impl Wave {
+    /// Read a Wave file.
+    /// Integer samples are scaled back to [-1; 1], float samples are kept as they are.
+    pub fn read<R: Read + Seek>(reader: &mut R) -> Result<Wave, ReadError> {
+        let mut riff_id = [0u8; 4];
+        reader.read_exact(&mut riff_id)?;
+        let is_rf64 = match &riff_id {
+            b"RIFF" => false,
+            b"RF64" => true,
+            _ => {
+                return Err(WrongMagicNumberError {
+                    expected: *b"RIFF",
+                    found: riff_id,
+                }
+                .into())
+            }
+        };
+        let riff_size = reader.read_le_to_u32()?;
+        let riff_start = reader.seek(SeekFrom::Current(0))?;
+        let mut riff_end = riff_start + u64::from(riff_size);
+        check_id(reader, *b"WAVE")?;
+        // RF64 files store the real sizes in a ds64 Chunk that comes first
+        let mut ds64_data_size = None;
+        if is_rf64 {
+            check_id(reader, *b"ds64")?;
+            let chunk_size = reader.read_le_to_u32()?;
+            let chunk_start = reader.seek(SeekFrom::Current(0))?;
+            if chunk_size < 28 {
+                return Err(MalformedChunkError {
+                    chunk_id: *b"ds64",
+                    reason: "Chunk is too small",
+                }
+                .into());
+            }
+            riff_end = riff_start + reader.read_le_to_u64()?;
+            ds64_data_size = Some(reader.read_le_to_u64()?);
+            reader.seek(SeekFrom::Start(
+                chunk_start + u64::from(chunk_size) + u64::from(chunk_size % 2),
+            ))?;
+        }
+        let mut format: Option<Format> = None;
+        let mut data_pos: Option<(u64, u64)> = None;
+        let mut loop_points: Vec<(u32, u32)> = Vec::new();
+        let mut tags = Vec::new();
+        let mut cue_offsets: Vec<(u32, u32)> = Vec::new();
+        let mut labels: HashMap<u32, String> = HashMap::new();
+        // Go through all the chunks, only keeping track of the ones we know
+        while reader.seek(SeekFrom::Current(0))? + 8 <= riff_end {
+            let mut chunk_id = [0u8; 4];
+            match reader.read_exact(&mut chunk_id) {
+                Ok(()) => {}
+                // Files cut short or still being written often declare a bigger size than they have
+                Err(ref e) if (e.kind() == ErrorKind::UnexpectedEof) & data_pos.is_some() => break,
+                Err(e) => return Err(e.into()),
+            }
+            let chunk_size = reader.read_le_to_u32()?;
+            let chunk_start = reader.seek(SeekFrom::Current(0))?;
+            let chunk_length = match (&chunk_id, ds64_data_size) {
+                (b"data", Some(size)) if chunk_size == u32::max_value() => size,
+                _ => u64::from(chunk_size),
+            };
+            match &chunk_id {
+                b"fmt " => format = Some(Format::read(reader, chunk_size)?),
+                b"data" => data_pos = Some((chunk_start, chunk_length)),
+                b"smpl" => loop_points = read_sampler_chunk(reader, chunk_size)?,
+                b"cue " => cue_offsets = read_cue_chunk(reader, chunk_size)?,
+                b"LIST" => read_list_chunk(reader, chunk_size, &mut tags, &mut labels)?,
+                _ => {}
+            }
+            // Chunks are always aligned on 2 bytes
+            reader.seek(SeekFrom::Start(
+                chunk_start + chunk_length + (chunk_length % 2),
+            ))?;
+        }
+        let format = format.ok_or(MissingChunkError { chunk_id: *b"fmt " })?;
+        let (data_start, data_size) = data_pos.ok_or(MissingChunkError { chunk_id: *b"data" })?;
+        reader.seek(SeekFrom::Start(data_start))?;
+        let data = read_chunk_data(reader, data_size)?;
+        let sample_rate_float = f64::from(format.sample_rate);
+        let mut loop_info = Vec::with_capacity(loop_points.len());
+        for (start, end) in loop_points {
+            // The end of a loop is the last sample played, so the span ends one sample after it
+            loop_info.push(
+                TimeSpan::new(
+                    Time::new(f64::from(start) / sample_rate_float)?,
+                    Time::new((f64::from(end) + 1f64) / sample_rate_float)?,
+                )
+                .map_err(|_| MalformedChunkError {
+                    chunk_id: *b"smpl",
+                    reason: "Loop ends before it starts",
+                })?,
+            );
+        }
+        let mut cue_points = Vec::with_capacity(cue_offsets.len());
+        for (id, offset) in cue_offsets {
+            cue_points.push(Marker {
+                at: Time::new(f64::from(offset) / sample_rate_float)?,
+                label: labels.remove(&id).unwrap_or_default(),
+            });
+        }
+        Ok(Wave {
+            pcm: PCM {
+                parameters: PCMParameters {
+                    sample_rate: format.sample_rate,
+                    nb_channels: format.nb_channels,
+                    speaker_layout: format.speaker_layout,
+                },
+                loop_info,
+                samples: format.decode(&data)?,
+            },
+            sample_type: format.sample_type,
+            gain: Gain::default(),
+            dither: Dither::default(),
+            metadata: Metadata { tags, cue_points },
+        })
+    }
     /// Write to a Wave file
-    pub fn write<W: Write>(&self, writer: &mut W) -> Result<(), WriteError> {
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<WriteReport, WriteError> {
         // Error for bigger than 32 bits streams
-        let extreme = self.pcm.get_extreme()?;
-        let max_value = self.sample_type.get_max_value();
-        let data_chunk_interior_size =
-            self.pcm.samples.len() as u32 * u32::from(self.sample_type.get_sample_size());
+        let factor = self.gain.get_factor(&self.pcm)?;
+        let block_sizes = get_block_sizes(self.sample_type, self.pcm.parameters)?;
+        let sample_size = self.sample_type.get_sample_size();
+        let nb_channels = self.pcm.parameters.nb_channels;
+        let data_chunk_interior_size = self.pcm.samples.len() as u32 * u32::from(sample_size);
+        let data_chunk_padding = data_chunk_interior_size % 2;
+        // Float formats need a Fact Chunk
+        let is_float = self.sample_type.is_float();
+        let fact_chunk_size = if is_float { 12 } else { 0 };
+        // Loops and metadata go after the data
+        let mut trailing_chunks = Cursor::new(Vec::new());
+        self.write_sampler_chunk(&mut trailing_chunks)?;
+        // Loops and cue points share the same Cue Point IDs, the loops taking the first ones
+        self.metadata.write(
+            &mut trailing_chunks,
+            self.pcm.parameters.sample_rate,
+            self.get_loop_points().len() as u32,
+        )?;
+        let trailing_chunks = trailing_chunks.into_inner();
         // File Itself
         writer.write_all(&[b'R', b'I', b'F', b'F'])?; // RIFF Chunk
-        writer.write_le_to_u32(36 + data_chunk_interior_size)?;
-        writer.write_all(&[b'W', b'A', b'V', b'E'])?; // WAVE Format
-        writer.write_all(&[b'f', b'm', b't', b' '])?; // Format Chunk
-        writer.write_le_to_u32(16)?; // Chunk Size
-        writer.write_le_to_u16(1)?; // Audio Format
-        writer.write_le_to_u16(self.pcm.parameters.nb_channels)?;
-        writer.write_le_to_u32(self.pcm.parameters.sample_rate)?;
         writer.write_le_to_u32(
-            self.pcm.parameters.sample_rate
-                * u32::from(self.pcm.parameters.nb_channels)
-                * u32::from(self.sample_type.get_sample_size()),
-        )?; // Byte Rate
-        writer.write_le_to_u16(
-            self.pcm.parameters.nb_channels * u16::from(self.sample_type.get_sample_size()),
-        )?; // Block Align
-        writer.write_le_to_u16(u16::from(self.sample_type.get_sample_size()) * 8)?; // Bits per sample
+            4 + (8 + get_format_chunk_interior_size(self.sample_type, self.pcm.parameters))
+                + fact_chunk_size
+                + (8 + data_chunk_interior_size + data_chunk_padding)
+                + trailing_chunks.len() as u32,
+        )?;
+        writer.write_all(&[b'W', b'A', b'V', b'E'])?; // WAVE Format
+        write_format_chunk(writer, self.sample_type, self.pcm.parameters, block_sizes)?;
+        if is_float {
+            writer.write_all(&[b'f', b'a', b'c', b't'])?; // Fact Chunk
+            writer.write_le_to_u32(4)?; // Chunk Size
+            let nb_frames = self.pcm.samples.len() as u32 / u32::from(nb_channels);
+            writer.write_le_to_u32(nb_frames)?; // Number of sample frames
+        }
         writer.write_all(&[b'd', b'a', b't', b'a'])?; // Sub-chunk 2 ID
         writer.write_le_to_u32(data_chunk_interior_size)?;
-        match self.sample_type {
-            SampleType::Unsigned8 => {
-                let mut to_write = Vec::new();
-                for sample in &self.pcm.samples {
-                    to_write.push(((((sample / extreme) + 1f64) / 2f64) * max_value).round() as u8);
-                }
-                writer.write_all(&to_write)?;
+        let mut mem_writer = Cursor::new(Vec::with_capacity(data_chunk_interior_size as usize));
+        let mut quantizer = Quantizer::new(self.sample_type, self.dither, nb_channels);
+        let mut nb_clipped = 0;
+        for sample in &self.pcm.samples {
+            let (value, clipped) = self.gain.apply(sample * factor, self.sample_type);
+            if clipped {
+                nb_clipped += 1;
+            }
+            self.sample_type
+                .write_sample(&mut mem_writer, quantizer.quantize(value))?;
+        }
+        writer.write_all(&mem_writer.into_inner())?;
+        if data_chunk_padding == 1 {
+            writer.write_all(&[0])?; // Padding Byte
+        }
+        writer.write_all(&trailing_chunks)?;
+        Ok(WriteReport { nb_clipped })
+    }
+    /// Writes the loop information of the PCM as a Sampler Chunk, if there is any
+    fn write_sampler_chunk<W: Write>(&self, writer: &mut W) -> IOResult<()> {
+        let loop_points = self.get_loop_points();
+        if loop_points.is_empty() {
+            return Ok(());
+        }
+        writer.write_all(&[b's', b'm', b'p', b'l'])?; // Sampler Chunk
+        writer.write_le_to_u32(36 + (24 * loop_points.len() as u32))?; // Chunk Size
+        writer.write_le_to_u32(0)?; // Manufacturer
+        writer.write_le_to_u32(0)?; // Product
+        writer.write_le_to_u32(
+            (1_000_000_000f64 / f64::from(self.pcm.parameters.sample_rate)).round() as u32,
+        )?; // Sample Period in nanoseconds
+        writer.write_le_to_u32(60)?; // MIDI Unity Note
+        writer.write_le_to_u32(0)?; // MIDI Pitch Fraction
+        writer.write_le_to_u32(0)?; // SMPTE Format
+        writer.write_le_to_u32(0)?; // SMPTE Offset
+        writer.write_le_to_u32(loop_points.len() as u32)?; // Number of Loops
+        writer.write_le_to_u32(0)?; // Sampler Data
+        for (id, (start, end)) in loop_points.iter().enumerate() {
+            writer.write_le_to_u32(id as u32)?; // Cue Point ID
+            writer.write_le_to_u32(0)?; // Type, 0 is Forward
+            writer.write_le_to_u32(*start)?;
+            writer.write_le_to_u32(*end)?;
+            writer.write_le_to_u32(0)?; // Fraction
+            writer.write_le_to_u32(0)?; // Play Count, 0 is infinite
+        }
+        Ok(())
+    }
+    /// Converts the loop information of the PCM to the first and last sample frame of each loop
+    fn get_loop_points(&self) -> Vec<(u32, u32)> {
+        let sample_rate_float = f64::from(self.pcm.parameters.sample_rate);
+        let mut loop_points = Vec::with_capacity(self.pcm.loop_info.len());
+        for span in &self.pcm.loop_info {
+            let start = (span.start_at().get() * sample_rate_float).round() as u32; // Lossy
+            let end = (span.end_at().get() * sample_rate_float).round() as u32; // Lossy
+            if end > start {
+                loop_points.push((start, end - 1));
+            }
+        }
+        loop_points
+    }
+}
+
+impl Metadata {
+    /// Get the text of a tag, if there is one
+    pub fn get_tag(&self, id: [u8; 4]) -> Option<&str> {
+        self.tags
+            .iter()
+            .find(|(tag_id, _)| *tag_id == id)
+            .map(|(_, text)| text.as_str())
+    }
+    /// Sets the text of a tag, replacing the previous one
+    pub fn set_tag(&mut self, id: [u8; 4], text: String) {
+        match self.tags.iter_mut().find(|(tag_id, _)| *tag_id == id) {
+            Some(tag) => tag.1 = text,
+            None => self.tags.push((id, text)),
+        }
+    }
+    /// Adds a cue point for every marker of a Sequence
+    pub fn add_markers(&mut self, seq: &Sequence) {
+        self.cue_points.extend(seq.markers.iter().cloned());
+    }
+    /// Writes the Cue Chunk and the LIST Chunks, if there is anything to write
+    /// # Arguments
+    /// * writer - Where to write the chunks
+    /// * sample_rate - Sample rate of the audio, to place the cue points
+    /// * first_id - Cue Point ID of the first cue point, the ones before being used by other chunks
+    fn write<W: Write>(&self, writer: &mut W, sample_rate: u32, first_id: u32) -> IOResult<()> {
+        if !self.tags.is_empty() {
+            let mut list = Vec::new();
+            for (id, text) in &self.tags {
+                write_text_chunk(&mut list, *id, None, text)?;
             }
-            SampleType::Signed16 => {
-                let mut mem_writer = Cursor::new(Vec::new());
-                for sample in &self.pcm.samples {
-                    mem_writer.write_le_to_i16(((sample / extreme) * max_value).round() as i16)?;
+            write_list_chunk(writer, *b"INFO", &list)?;
+        }
+        if self.cue_points.is_empty() {
+            return Ok(());
+        }
+        let sample_rate_float = f64::from(sample_rate);
+        writer.write_all(&[b'c', b'u', b'e', b' '])?; // Cue Chunk
+        writer.write_le_to_u32(4 + (24 * self.cue_points.len() as u32))?; // Chunk Size
+        writer.write_le_to_u32(self.cue_points.len() as u32)?; // Number of Cue Points
+        for (id, marker) in self.cue_points.iter().enumerate() {
+            let offset = (marker.at.get() * sample_rate_float).round() as u32; // Lossy
+            writer.write_le_to_u32(first_id + id as u32)?; // Cue Point ID
+            writer.write_le_to_u32(offset)?; // Position
+            writer.write_all(&[b'd', b'a', b't', b'a'])?; // Data Chunk ID
+            writer.write_le_to_u32(0)?; // Chunk Start
+            writer.write_le_to_u32(0)?; // Block Start
+            writer.write_le_to_u32(offset)?; // Sample Offset
+        }
+        let mut list = Vec::new();
+        for (id, marker) in self.cue_points.iter().enumerate() {
+            if !marker.label.is_empty() {
+                write_text_chunk(
+                    &mut list,
+                    *b"labl",
+                    Some(first_id + id as u32),
+                    &marker.label,
+                )?;
+            }
+        }
+        if !list.is_empty() {
+            write_list_chunk(writer, *b"adtl", &list)?;
+        }
+        Ok(())
+    }
+}
+
+impl Gain {
+    /// Get the factor to multiply every sample of a PCM by
+    pub fn get_factor(&self, pcm: &PCM) -> Result<f64, NoSamplesError> {
+        match *self {
+            Gain::NormalizeToPeak => {
+                let extreme = pcm.get_extreme()?;
+                // A silent stream stays silent
+                if extreme > 0f64 {
+                    Ok(extreme.recip())
+                } else {
+                    Ok(1f64)
                 }
-                writer.write_all(&mem_writer.into_inner())?;
             }
-            SampleType::Signed32 => {
-                let mut mem_writer = Cursor::new(Vec::new());
-                for sample in &self.pcm.samples {
-                    mem_writer.write_le_to_i32(((sample / extreme) * max_value).round() as i32)?;
+            Gain::HardClip(factor) | Gain::SoftClip(factor) => Ok(factor),
+        }
+    }
+    /// Brings a sample that has already been multiplied by the factor into [-1; 1], unless it is hard clipped to a float type. Also tells if it went beyond.
+    pub fn apply(&self, sample: f64, sample_type: SampleType) -> (f64, bool) {
+        let clipped = sample.abs() > 1f64;
+        match *self {
+            Gain::HardClip(_) if sample_type.is_float() => (sample, clipped),
+            Gain::NormalizeToPeak | Gain::HardClip(_) => (sample.max(-1f64).min(1f64), clipped),
+            Gain::SoftClip(_) => {
+                let magnitude = sample.abs();
+                if magnitude <= SOFT_CLIP_KNEE {
+                    (sample, clipped)
+                } else {
+                    let range = 1f64 - SOFT_CLIP_KNEE;
+                    let compressed =
+                        SOFT_CLIP_KNEE + (range * ((magnitude - SOFT_CLIP_KNEE) / range).tanh());
+                    (compressed.copysign(sample), clipped)
                 }
-                writer.write_all(&mem_writer.into_inner())?;
             }
         }
+    }
+}
+
+impl Default for Gain {
+    fn default() -> Gain {
+        Gain::NormalizeToPeak
+    }
+}
+
+impl<W: Write + Seek> WaveStreamWriter<W> {
+    /// Creates a new writer and writes the header with temporary sizes
+    pub fn new(
+        mut writer: W,
+        parameters: PCMParameters,
+        sample_type: SampleType,
+        gain: Gain,
+        dither: Dither,
+    ) -> Result<WaveStreamWriter<W>, WriteError> {
+        let block_sizes = get_block_sizes(sample_type, parameters)?;
+        let start = writer.seek(SeekFrom::Current(0))?;
+        writer.write_all(&[b'R', b'I', b'F', b'F'])?; // RIFF Chunk
+        writer.write_le_to_u32(0)?; // Patched when finalizing
+        writer.write_all(&[b'W', b'A', b'V', b'E'])?; // WAVE Format
+        writer.write_all(&[b'J', b'U', b'N', b'K'])?; // Junk Chunk, reserves space for a ds64 Chunk in case the file ends up being an RF64 file
+        writer.write_le_to_u32(RF64_DS64_CHUNK_INTERIOR_SIZE)?; // Chunk Size
+        writer.write_all(&[0u8; RF64_DS64_CHUNK_INTERIOR_SIZE as usize])?;
+        write_format_chunk(&mut writer, sample_type, parameters, block_sizes)?;
+        if sample_type.is_float() {
+            writer.write_all(&[b'f', b'a', b'c', b't'])?; // Fact Chunk
+            writer.write_le_to_u32(4)?; // Chunk Size
+            writer.write_le_to_u32(0)?; // Patched when finalizing
+        }
+        writer.write_all(&[b'd', b'a', b't', b'a'])?; // Data Chunk
+        writer.write_le_to_u32(0)?; // Patched when finalizing
+        Ok(WaveStreamWriter {
+            writer,
+            start,
+            sample_type,
+            parameters,
+            gain,
+            quantizer: Quantizer::new(sample_type, dither, parameters.nb_channels),
+            nb_samples: 0,
+            nb_clipped: 0,
+        })
+    }
+    /// Writes a block of interleaved samples following the ones written before
+    pub fn write_samples(&mut self, samples: &[f64]) -> Result<(), WriteError> {
+        let factor = match self.gain {
+            Gain::NormalizeToPeak => 1f64,
+            Gain::HardClip(factor) | Gain::SoftClip(factor) => factor,
+        };
+        let mut mem_writer = Cursor::new(Vec::with_capacity(
+            samples.len() * usize::from(self.sample_type.get_sample_size()),
+        ));
+        for sample in samples {
+            let (value, clipped) = self.gain.apply(sample * factor, self.sample_type);
+            if clipped {
+                self.nb_clipped += 1;
+            }
+            self.sample_type
+                .write_sample(&mut mem_writer, self.quantizer.quantize(value))?;
+        }
+        self.writer.write_all(&mem_writer.into_inner())?;
+        self.nb_samples += samples.len() as u64;
         Ok(())
     }
+    /// Patches all the sizes in the header. Nothing can be written after this.
+    pub fn finalize(mut self) -> Result<WriteReport, WriteError> {
+        let data_size = self.nb_samples * u64::from(self.sample_type.get_sample_size());
+        if data_size % 2 == 1 {
+            self.writer.write_all(&[0])?; // Padding Byte
+        }
+        let end = self.writer.seek(SeekFrom::Current(0))?;
+        let riff_size = end - self.start - 8;
+        let nb_frames = self.nb_samples / u64::from(self.parameters.nb_channels.max(1));
+        let max_size = u64::from(u32::max_value());
+        let is_rf64 = riff_size > max_size;
+        // Header is: RIFF (12) + JUNK or ds64 (36) + Format Chunk + optional Fact Chunk + Data Chunk header
+        let format_chunk_end = self.start
+            + 12
+            + 8
+            + u64::from(RF64_DS64_CHUNK_INTERIOR_SIZE)
+            + 8
+            + u64::from(get_format_chunk_interior_size(
+                self.sample_type,
+                self.parameters,
+            ));
+        let data_chunk_start = if self.sample_type.is_float() {
+            self.writer.seek(SeekFrom::Start(format_chunk_end + 8))?;
+            self.writer
+                .write_le_to_u32(nb_frames.min(max_size) as u32)?;
+            format_chunk_end + 12
+        } else {
+            format_chunk_end
+        };
+        self.writer.seek(SeekFrom::Start(data_chunk_start + 4))?;
+        self.writer
+            .write_le_to_u32(data_size.min(max_size) as u32)?;
+        self.writer.seek(SeekFrom::Start(self.start))?;
+        if is_rf64 {
+            self.writer.write_all(&[b'R', b'F', b'6', b'4'])?;
+            self.writer.write_le_to_u32(u32::max_value())?;
+            self.writer.seek(SeekFrom::Current(4))?; // WAVE Format
+            self.writer.write_all(&[b'd', b's', b'6', b'4'])?;
+            self.writer.write_le_to_u32(RF64_DS64_CHUNK_INTERIOR_SIZE)?;
+            self.writer.write_le_to_u64(riff_size)?;
+            self.writer.write_le_to_u64(data_size)?;
+            self.writer.write_le_to_u64(nb_frames)?;
+            self.writer.write_le_to_u32(0)?; // Table Length
+        } else {
+            self.writer.seek(SeekFrom::Current(4))?; // RIFF Chunk
+            self.writer.write_le_to_u32(riff_size as u32)?;
+        }
+        self.writer.seek(SeekFrom::Start(end))?;
+        self.writer.flush()?;
+        Ok(WriteReport {
+            nb_clipped: self.nb_clipped,
+        })
+    }
+}
+
+impl Default for Dither {
+    fn default() -> Dither {
+        Dither::None
+    }
+}
+
+impl Quantizer {
+    /// Creates a new Quantizer for an interleaved stream
+    pub fn new(sample_type: SampleType, dither: Dither, nb_channels: u16) -> Quantizer {
+        let (rng, noise_shaping) = match dither {
+            Dither::None => (None, false),
+            Dither::Triangular {
+                seed,
+                noise_shaping,
+            } => (Some(StdRng::seed_from_u64(seed)), noise_shaping),
+        };
+        Quantizer {
+            sample_type,
+            rng,
+            noise_shaping,
+            errors: vec![0f64; usize::from(nb_channels.max(1))],
+            channel: 0,
+        }
+    }
+    /// Quantizes the next sample of the stream. The value should be in [-1; 1].
+    pub fn quantize(&mut self, value: f64) -> f64 {
+        let channel = self.channel;
+        self.channel = (self.channel + 1) % self.errors.len();
+        if self.sample_type.is_float() {
+            return value;
+        }
+        let step = self.sample_type.get_step();
+        // First order error feedback, the noise gets shaped by (1 - z^-1)
+        let target = if self.noise_shaping {
+            value - self.errors[channel]
+        } else {
+            value
+        };
+        let noise = match self.rng {
+            Some(ref mut rng) => (rng.gen::<f64>() - rng.gen::<f64>()) * step,
+            None => 0f64,
+        };
+        let quantized = self
+            .sample_type
+            .quantize((target + noise).max(-1f64).min(1f64));
+        self.errors[channel] = quantized - target;
+        quantized
+    }
 }
 
 impl SampleType {
+    /// Finds the sample type corresponding to a format tag and a number of bits per sample
+    pub fn from_format(format_tag: u16, bits_per_sample: u16) -> Option<SampleType> {
+        match (format_tag, bits_per_sample) {
+            (WAVE_FORMAT_PCM, 8) => Some(SampleType::Unsigned8),
+            (WAVE_FORMAT_PCM, 16) => Some(SampleType::Signed16),
+            (WAVE_FORMAT_PCM, 24) => Some(SampleType::Signed24),
+            (WAVE_FORMAT_PCM, 32) => Some(SampleType::Signed32),
+            (WAVE_FORMAT_IEEE_FLOAT, 32) => Some(SampleType::Float32),
+            (WAVE_FORMAT_IEEE_FLOAT, 64) => Some(SampleType::Float64),
+            _ => None,
+        }
+    }
     /// Get the size of a single sample in bytes
     pub fn get_sample_size(&self) -> u8 {
         match *self {
             SampleType::Unsigned8 => 1,
             SampleType::Signed16 => 2,
+            SampleType::Signed24 => 3,
             SampleType::Signed32 => 4,
+            SampleType::Float32 => 4,
+            SampleType::Float64 => 8,
         }
     }
     pub fn get_max_value(&self) -> f64 {
         match *self {
             SampleType::Unsigned8 => f64::from(<u8>::max_value()),
             SampleType::Signed16 => f64::from(<i16>::max_value()),
+            SampleType::Signed24 => f64::from((1i32 << 23) - 1),
             SampleType::Signed32 => f64::from(<i32>::max_value()),
+            SampleType::Float32 | SampleType::Float64 => 1f64,
+        }
+    }
+    /// Get the distance between two consecutive values of this type, once brought to [-1; 1]. It is 0 for floats.
+    pub fn get_step(&self) -> f64 {
+        match *self {
+            SampleType::Unsigned8 => 2f64 / self.get_max_value(),
+            SampleType::Float32 | SampleType::Float64 => 0f64,
+            _ => self.get_max_value().recip(),
+        }
+    }
+    /// Rounds a value in [-1; 1] to the nearest one this type can hold
+    pub fn quantize(&self, value: f64) -> f64 {
+        let max_value = self.get_max_value();
+        match *self {
+            SampleType::Unsigned8 => {
+                (((((value + 1f64) / 2f64) * max_value).round() / max_value) * 2f64) - 1f64
+            }
+            SampleType::Float32 | SampleType::Float64 => value,
+            _ => (value * max_value).round() / max_value,
+        }
+    }
+    /// Writes a single sample in [-1; 1] with this type
+    pub fn write_sample<W: Write>(&self, writer: &mut W, value: f64) -> IOResult<()> {
+        let max_value = self.get_max_value();
+        match *self {
+            SampleType::Unsigned8 => {
+                writer.write_to_u8((((value + 1f64) / 2f64) * max_value).round() as u8)
+            }
+            SampleType::Signed16 => writer.write_le_to_i16((value * max_value).round() as i16),
+            SampleType::Signed24 => {
+                let sample = (value * max_value).round() as i32;
+                writer.write_all(&sample.to_le_bytes()[..3])
+            }
+            SampleType::Signed32 => writer.write_le_to_i32((value * max_value).round() as i32),
+            SampleType::Float32 => writer.write_le_to_f32(value as f32),
+            SampleType::Float64 => writer.write_le_to_f64(value),
+        }
+    }
+    /// Get the format tag to write in the Format Chunk
+    pub fn get_format_tag(&self) -> u16 {
+        if self.is_float() {
+            WAVE_FORMAT_IEEE_FLOAT
+        } else {
+            WAVE_FORMAT_PCM
+        }
+    }
+    /// Is this an IEEE float format
+    pub fn is_float(&self) -> bool {
+        match *self {
+            SampleType::Float32 | SampleType::Float64 => true,
+            _ => false,
+        }
+    }
+}
+
+impl Format {
+    /// Reads the contents of a Format Chunk
+    fn read<R: Read>(reader: &mut R, chunk_size: u32) -> Result<Format, ReadError> {
+        if chunk_size < 16 {
+            return Err(MalformedChunkError {
+                chunk_id: *b"fmt ",
+                reason: "Chunk is too small",
+            }
+            .into());
+        }
+        let mut format_tag = reader.read_le_to_u16()?;
+        let nb_channels = reader.read_le_to_u16()?;
+        let sample_rate = reader.read_le_to_u32()?;
+        let _byte_rate = reader.read_le_to_u32()?;
+        let block_align = reader.read_le_to_u16()?;
+        let bits_per_sample = reader.read_le_to_u16()?;
+        let mut speaker_layout = SpeakerLayout::Unspecified;
+        if format_tag == WAVE_FORMAT_EXTENSIBLE {
+            if chunk_size < 40 {
+                return Err(MalformedChunkError {
+                    chunk_id: *b"fmt ",
+                    reason: "Chunk is too small for WAVE_FORMAT_EXTENSIBLE",
+                }
+                .into());
+            }
+            let _extension_size = reader.read_le_to_u16()?;
+            let _valid_bits_per_sample = reader.read_le_to_u16()?;
+            speaker_layout = SpeakerLayout::from_channel_mask(reader.read_le_to_u32()?);
+            // The actual format is given by the sub-format GUID
+            format_tag = reader.read_le_to_u16()?;
+            let mut guid_tail = [0u8; 14];
+            reader.read_exact(&mut guid_tail)?;
+            if guid_tail != WAVE_FORMAT_EXTENSIBLE_GUID_TAIL {
+                return Err(UnsupportedFormatError {
+                    format_tag: WAVE_FORMAT_EXTENSIBLE,
+                    bits_per_sample,
+                }
+                .into());
+            }
+        }
+        if nb_channels == 0 {
+            return Err(MalformedChunkError {
+                chunk_id: *b"fmt ",
+                reason: "No channels",
+            }
+            .into());
+        }
+        if sample_rate == 0 {
+            return Err(MalformedChunkError {
+                chunk_id: *b"fmt ",
+                reason: "Sample rate is 0",
+            }
+            .into());
+        }
+        let sample_type =
+            SampleType::from_format(format_tag, bits_per_sample).ok_or(UnsupportedFormatError {
+                format_tag,
+                bits_per_sample,
+            })?;
+        if u32::from(block_align)
+            != u32::from(nb_channels) * u32::from(sample_type.get_sample_size())
+        {
+            return Err(MalformedChunkError {
+                chunk_id: *b"fmt ",
+                reason: "Block align does not match the number of channels and bits per sample",
+            }
+            .into());
+        }
+        Ok(Format {
+            sample_type,
+            nb_channels,
+            sample_rate,
+            block_align,
+            speaker_layout,
+        })
+    }
+    /// Converts the raw contents of a Data Chunk to f64 samples
+    fn decode(&self, data: &[u8]) -> Result<Vec<f64>, ReadError> {
+        if data.len() % usize::from(self.block_align) != 0 {
+            return Err(MalformedChunkError {
+                chunk_id: *b"data",
+                reason: "Size is not a multiple of the block align",
+            }
+            .into());
+        }
+        let nb_samples = data.len() / usize::from(self.sample_type.get_sample_size());
+        let max_value = self.sample_type.get_max_value();
+        let mut samples = Vec::with_capacity(nb_samples);
+        let mut data_reader = Cursor::new(data);
+        for _ in 0..nb_samples {
+            samples.push(match self.sample_type {
+                // 128 is the silence
+                SampleType::Unsigned8 => (f64::from(data_reader.read_to_u8()?) - 128f64) / 128f64,
+                SampleType::Signed16 => f64::from(data_reader.read_le_to_i16()?) / max_value,
+                SampleType::Signed24 => {
+                    let mut bytes = [0u8; 4];
+                    data_reader.read_exact(&mut bytes[1..])?;
+                    // Shifting back down keeps the sign
+                    f64::from(i32::from_le_bytes(bytes) >> 8) / max_value
+                }
+                SampleType::Signed32 => f64::from(data_reader.read_le_to_i32()?) / max_value,
+                SampleType::Float32 => f64::from(data_reader.read_le_to_f32()?),
+                SampleType::Float64 => data_reader.read_le_to_f64()?,
+            });
+        }
+        Ok(samples)
+    }
+}
+
+/// Should the Format Chunk be a WAVE_FORMAT_EXTENSIBLE one
+fn is_extensible(parameters: PCMParameters) -> bool {
+    (parameters.nb_channels > 2) | (parameters.speaker_layout != SpeakerLayout::Unspecified)
+}
+
+/// Get the size of the contents of the Format Chunk
+fn get_format_chunk_interior_size(sample_type: SampleType, parameters: PCMParameters) -> u32 {
+    if is_extensible(parameters) {
+        40
+    } else if sample_type.is_float() {
+        // Float formats need the extension size field
+        18
+    } else {
+        16
+    }
+}
+
+/// Computes the Block Align and the Byte Rate of the Format Chunk, if they fit in their fields
+fn get_block_sizes(
+    sample_type: SampleType,
+    parameters: PCMParameters,
+) -> Result<(u16, u32), UnsupportedParametersError> {
+    let block_align = parameters
+        .nb_channels
+        .checked_mul(u16::from(sample_type.get_sample_size()))
+        .ok_or(UnsupportedParametersError {
+            reason: "Too many channels for the size of a sample frame to fit in 16 bits",
+        })?;
+    let byte_rate = parameters
+        .sample_rate
+        .checked_mul(u32::from(block_align))
+        .ok_or(UnsupportedParametersError {
+            reason: "Sample rate too high for the byte rate to fit in 32 bits",
+        })?;
+    Ok((block_align, byte_rate))
+}
+
+/// Writes a whole Format Chunk
+/// # Arguments
+/// * block_sizes - Block Align and Byte Rate, from get_block_sizes
+fn write_format_chunk<W: Write>(
+    writer: &mut W,
+    sample_type: SampleType,
+    parameters: PCMParameters,
+    block_sizes: (u16, u32),
+) -> IOResult<()> {
+    let sample_size = sample_type.get_sample_size();
+    let nb_channels = parameters.nb_channels;
+    let (block_align, byte_rate) = block_sizes;
+    let extensible = is_extensible(parameters);
+    writer.write_all(&[b'f', b'm', b't', b' '])?; // Format Chunk
+    writer.write_le_to_u32(get_format_chunk_interior_size(sample_type, parameters))?; // Chunk Size
+    if extensible {
+        writer.write_le_to_u16(WAVE_FORMAT_EXTENSIBLE)?; // Audio Format
+    } else {
+        writer.write_le_to_u16(sample_type.get_format_tag())?; // Audio Format
+    }
+    writer.write_le_to_u16(nb_channels)?;
+    writer.write_le_to_u32(parameters.sample_rate)?;
+    writer.write_le_to_u32(byte_rate)?; // Byte Rate
+    writer.write_le_to_u16(block_align)?; // Block Align
+    writer.write_le_to_u16(u16::from(sample_size) * 8)?; // Bits per sample
+    if extensible {
+        writer.write_le_to_u16(22)?; // Extension Size
+        writer.write_le_to_u16(u16::from(sample_size) * 8)?; // Valid bits per sample
+        writer.write_le_to_u32(parameters.speaker_layout.get_channel_mask())?;
+        writer.write_le_to_u16(sample_type.get_format_tag())?; // Sub-format GUID
+        writer.write_all(&WAVE_FORMAT_EXTENSIBLE_GUID_TAIL)?;
+    } else if sample_type.is_float() {
+        writer.write_le_to_u16(0)?; // Extension Size
+    }
+    Ok(())
+}
+
+/// Writes a LIST Chunk around already written sub-chunks
+fn write_list_chunk<W: Write>(writer: &mut W, list_type: [u8; 4], contents: &[u8]) -> IOResult<()> {
+    writer.write_all(&[b'L', b'I', b'S', b'T'])?; // LIST Chunk
+    writer.write_le_to_u32(4 + contents.len() as u32)?; // Chunk Size
+    writer.write_all(&list_type)?;
+    writer.write_all(contents)
+}
+
+/// Writes a chunk holding a null-terminated text, optionally preceded by a Cue Point ID
+fn write_text_chunk<W: Write>(
+    writer: &mut W,
+    id: [u8; 4],
+    cue_point_id: Option<u32>,
+    text: &str,
+) -> IOResult<()> {
+    let id_size = if cue_point_id.is_some() { 4 } else { 0 };
+    let chunk_size = id_size + text.len() as u32 + 1;
+    writer.write_all(&id)?;
+    writer.write_le_to_u32(chunk_size)?;
+    if let Some(cue_point_id) = cue_point_id {
+        writer.write_le_to_u32(cue_point_id)?;
+    }
+    writer.write_all(text.as_bytes())?;
+    writer.write_all(&[0])?; // Null terminator
+    if chunk_size % 2 == 1 {
+        writer.write_all(&[0])?; // Padding Byte
+    }
+    Ok(())
+}
+
+/// Reads the Cue Point IDs and sample offsets of a Cue Chunk
+fn read_cue_chunk<R: Read>(reader: &mut R, chunk_size: u32) -> Result<Vec<(u32, u32)>, ReadError> {
+    if chunk_size < 4 {
+        return Err(MalformedChunkError {
+            chunk_id: *b"cue ",
+            reason: "Chunk is too small",
+        }
+        .into());
+    }
+    let nb_cue_points = reader.read_le_to_u32()?;
+    if u64::from(chunk_size) < 4 + (24 * u64::from(nb_cue_points)) {
+        return Err(MalformedChunkError {
+            chunk_id: *b"cue ",
+            reason: "Chunk is too small for the number of cue points",
         }
+        .into());
+    }
+    let mut cue_offsets = Vec::new();
+    for _ in 0..nb_cue_points {
+        let id = reader.read_le_to_u32()?;
+        // Skip Position, Data Chunk ID, Chunk Start and Block Start
+        let mut skipped = [0u8; 16];
+        reader.read_exact(&mut skipped)?;
+        cue_offsets.push((id, reader.read_le_to_u32()?));
+    }
+    Ok(cue_offsets)
+}
+
+/// Reads the tags of a LIST/INFO Chunk or the labels of a LIST/adtl Chunk, ignoring any other list
+fn read_list_chunk<R: Read>(
+    reader: &mut R,
+    chunk_size: u32,
+    tags: &mut Vec<([u8; 4], String)>,
+    labels: &mut HashMap<u32, String>,
+) -> Result<(), ReadError> {
+    if chunk_size < 4 {
+        return Err(MalformedChunkError {
+            chunk_id: *b"LIST",
+            reason: "Chunk is too small",
+        }
+        .into());
+    }
+    let mut list_type = [0u8; 4];
+    reader.read_exact(&mut list_type)?;
+    if (&list_type != b"INFO") & (&list_type != b"adtl") {
+        return Ok(());
+    }
+    let mut remaining = u64::from(chunk_size) - 4;
+    while remaining >= 8 {
+        let mut id = [0u8; 4];
+        reader.read_exact(&mut id)?;
+        let size = reader.read_le_to_u32()?;
+        let padded_size = u64::from(size) + u64::from(size % 2);
+        if 8 + padded_size > remaining {
+            return Err(MalformedChunkError {
+                chunk_id: *b"LIST",
+                reason: "Sub-chunk goes past the end of the list",
+            }
+            .into());
+        }
+        let mut contents = read_chunk_data(reader, padded_size)?;
+        contents.truncate(size as usize);
+        if &list_type == b"INFO" {
+            tags.push((id, read_text(&contents)));
+        } else if (&id == b"labl") & (contents.len() >= 4) {
+            let cue_point_id = Cursor::new(&contents[..4]).read_le_to_u32()?;
+            labels.insert(cue_point_id, read_text(&contents[4..]));
+        }
+        remaining -= 8 + padded_size;
+    }
+    Ok(())
+}
+
+/// Converts a null-terminated text to a String
+fn read_text(bytes: &[u8]) -> String {
+    let end = bytes
+        .iter()
+        .position(|&b| b == 0)
+        .unwrap_or_else(|| bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+/// Reads the loops of a Sampler Chunk as the first and last sample frame of each loop
+fn read_sampler_chunk<R: Read>(
+    reader: &mut R,
+    chunk_size: u32,
+) -> Result<Vec<(u32, u32)>, ReadError> {
+    if chunk_size < 36 {
+        return Err(MalformedChunkError {
+            chunk_id: *b"smpl",
+            reason: "Chunk is too small",
+        }
+        .into());
+    }
+    // Skip Manufacturer, Product, Sample Period, MIDI Unity Note, MIDI Pitch Fraction, SMPTE Format and SMPTE Offset
+    let mut header = [0u8; 28];
+    reader.read_exact(&mut header)?;
+    let nb_loops = reader.read_le_to_u32()?;
+    let _sampler_data = reader.read_le_to_u32()?;
+    if u64::from(chunk_size) < 36 + (24 * u64::from(nb_loops)) {
+        return Err(MalformedChunkError {
+            chunk_id: *b"smpl",
+            reason: "Chunk is too small for the number of loops",
+        }
+        .into());
+    }
+    let mut loop_points = Vec::new();
+    for _ in 0..nb_loops {
+        let _cue_point_id = reader.read_le_to_u32()?;
+        let _loop_type = reader.read_le_to_u32()?;
+        let start = reader.read_le_to_u32()?;
+        let end = reader.read_le_to_u32()?;
+        let _fraction = reader.read_le_to_u32()?;
+        let _play_count = reader.read_le_to_u32()?;
+        loop_points.push((start, end));
+    }
+    Ok(loop_points)
+}
+
+/// Reads 4 bytes and checks that they match the expected identifier
+pub(crate) fn check_id<R: Read>(
+    reader: &mut R,
+    expected: [u8; 4],
+) -> Result<(), WrongMagicNumberError> {
+    let mut found = [0u8; 4];
+    if reader.read_exact(&mut found).is_err() || found != expected {
+        return Err(WrongMagicNumberError { expected, found });
+    }
+    Ok(())
+}
+
+/// Reads the content of a chunk. The buffer only grows with what is actually read, so a corrupt size cannot exhaust the memory.
+pub(crate) fn read_chunk_data<R: Read>(reader: &mut R, size: u64) -> IOResult<Vec<u8>> {
+    let mut data = Vec::new();
+    reader.by_ref().take(size).read_to_end(&mut data)?;
+    if (data.len() as u64) < size {
+        return Err(IOError::new(
+            ErrorKind::UnexpectedEof,
+            "Chunk goes past the end of the file",
+        ));
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Finds the content of a chunk by its ID
+    fn find_chunk(bytes: &[u8], id: &[u8; 4]) -> Vec<u8> {
+        let start = bytes.windows(4).position(|window| window == id).unwrap() + 8;
+        let size = u32::from_le_bytes([
+            bytes[start - 4],
+            bytes[start - 3],
+            bytes[start - 2],
+            bytes[start - 1],
+        ]) as usize;
+        bytes[start..start + size].to_vec()
+    }
+
+    /// Reads the Cue Point IDs of a list of 24 bytes entries
+    fn read_ids(entries: &[u8]) -> Vec<u32> {
+        entries
+            .chunks(24)
+            .map(|entry| u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]))
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let max_value = SampleType::Signed16.get_max_value();
+        let samples: Vec<f64> = (0..2000)
+            .map(|i| f64::from((i * 37) % 65_535 - 32_767) / max_value)
+            .collect();
+        let mut metadata = Metadata::default();
+        metadata.set_tag(TAG_TITLE, "Round trip".to_string());
+        metadata.cue_points.push(Marker {
+            at: Time::new(0.001).unwrap(),
+            label: "Verse".to_string(),
+        });
+        metadata.cue_points.push(Marker {
+            at: Time::new(0.01).unwrap(),
+            label: String::new(),
+        });
+        let wave = Wave {
+            pcm: PCM {
+                parameters: PCMParameters {
+                    sample_rate: 10_000,
+                    nb_channels: 2,
+                    speaker_layout: SpeakerLayout::Unspecified,
+                },
+                loop_info: vec![
+                    TimeSpan::new(Time::new(0.01).unwrap(), Time::new(0.03).unwrap()).unwrap(),
+                ],
+                samples: samples.clone(),
+            },
+            sample_type: SampleType::Signed16,
+            gain: Gain::HardClip(1f64),
+            dither: Dither::None,
+            metadata,
+        };
+        let mut bytes = Vec::new();
+        wave.write(&mut bytes).unwrap();
+        // Loops and cue points must not share Cue Point IDs
+        let loop_ids = read_ids(&find_chunk(&bytes, b"smpl")[36..]);
+        let cue_ids = read_ids(&find_chunk(&bytes, b"cue ")[4..]);
+        assert_eq!(loop_ids, vec![0]);
+        assert_eq!(cue_ids, vec![1, 2]);
+        let read = Wave::read(&mut Cursor::new(bytes)).unwrap();
+        assert!(read.sample_type == SampleType::Signed16);
+        assert_eq!(read.pcm.parameters.sample_rate, 10_000);
+        assert_eq!(read.pcm.parameters.nb_channels, 2);
+        assert!(read.pcm.samples == samples);
+        assert_eq!(read.pcm.loop_info.len(), 1);
+        assert!((read.pcm.loop_info[0].start_at().get() - 0.01).abs() < 1e-9);
+        assert!((read.pcm.loop_info[0].end_at().get() - 0.03).abs() < 1e-9);
+        assert_eq!(read.metadata.get_tag(TAG_TITLE), Some("Round trip"));
+        let cue_points = &read.metadata.cue_points;
+        assert_eq!(cue_points.len(), 2);
+        assert!((cue_points[0].at.get() - 0.001).abs() < 1e-9);
+        assert_eq!(cue_points[0].label, "Verse");
+        assert!((cue_points[1].at.get() - 0.01).abs() < 1e-9);
+        assert_eq!(cue_points[1].label, "");
+    }
+
+    #[test]
+    fn riff_size_past_the_end() {
+        let mut bytes = b"RIFF\0\0\0\0WAVEfmt \x10\0\0\0\x01\0\x01\0".to_vec();
+        bytes.extend_from_slice(&8_000u32.to_le_bytes()); // Sample Rate
+        bytes.extend_from_slice(&8_000u32.to_le_bytes()); // Byte Rate
+        bytes.extend_from_slice(&[1, 0, 8, 0]); // Block Align, Bits per sample
+        bytes.extend_from_slice(b"data\x03\0\0\0");
+        bytes.extend_from_slice(&[0, 128, 255, 0]);
+        // Declares more than there is
+        let riff_size = bytes.len() as u32 + 1000;
+        bytes[4..8].copy_from_slice(&riff_size.to_le_bytes());
+        let read = Wave::read(&mut Cursor::new(bytes)).unwrap();
+        assert!(read.pcm.samples == vec![-1f64, 0f64, 127f64 / 128f64]);
+    }
+
+    #[test]
+    fn float_headroom() {
+        let mut wave = Wave {
+            pcm: PCM {
+                parameters: PCMParameters {
+                    sample_rate: 44_100,
+                    nb_channels: 1,
+                    speaker_layout: SpeakerLayout::Unspecified,
+                },
+                loop_info: Vec::new(),
+                samples: vec![0.5, 1.5, -2f64],
+            },
+            sample_type: SampleType::Float32,
+            gain: Gain::HardClip(1f64),
+            dither: Dither::None,
+            metadata: Metadata::default(),
+        };
+        let mut bytes = Vec::new();
+        assert_eq!(wave.write(&mut bytes).unwrap().nb_clipped, 2);
+        let read = Wave::read(&mut Cursor::new(bytes)).unwrap();
+        assert!(read.pcm.samples == vec![0.5, 1.5, -2f64]);
+        // Integer samples cannot hold them
+        wave.sample_type = SampleType::Signed16;
+        let mut bytes = Vec::new();
+        assert_eq!(wave.write(&mut bytes).unwrap().nb_clipped, 2);
+        let read = Wave::read(&mut Cursor::new(bytes)).unwrap();
+        assert!(read.pcm.samples == vec![16_384f64 / 32_767f64, 1f64, -1f64]);
+    }
+
+    #[test]
+    fn too_many_channels() {
+        let mut bytes = b"RIFF\x24\0\0\0WAVEfmt \x10\0\0\0\x01\0".to_vec();
+        bytes.extend_from_slice(&40_000u16.to_le_bytes()); // Number of Channels
+        bytes.extend_from_slice(&44_100u32.to_le_bytes()); // Sample Rate
+        bytes.extend_from_slice(&[0, 0, 0, 0, 0x80, 0x38, 16, 0]); // Byte Rate, Block Align, Bits per sample
+        assert!(Wave::read(&mut Cursor::new(bytes)).is_err());
+        let wave = Wave {
+            pcm: PCM {
+                parameters: PCMParameters {
+                    sample_rate: 44_100,
+                    nb_channels: 40_000,
+                    speaker_layout: SpeakerLayout::Unspecified,
+                },
+                loop_info: Vec::new(),
+                samples: vec![0f64; 40_000],
+            },
+            sample_type: SampleType::Signed16,
+            gain: Gain::HardClip(1f64),
+            dither: Dither::None,
+            metadata: Metadata::default(),
+        };
+        assert!(wave.write(&mut Vec::new()).is_err());
     }
 }